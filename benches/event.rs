@@ -0,0 +1,75 @@
+use cornostr::crypto::{generate_keypair, verify_event, verify_events};
+use cornostr::event::{calculate_event_id, Event, EventBuilder, EventId};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sha2::{Digest, Sha256};
+use std::hint::black_box;
+
+/// The id computed the straightforward way: format the canonical array into a string, then hash it.
+fn formatted_event_id(event: &Event) -> EventId {
+    let serialized = format!(
+        "[0,\"{}\",{},{},{},{}]",
+        event.pubkey,
+        event.created_at,
+        event.kind,
+        serde_json::to_string(&event.tags).unwrap(),
+        serde_json::to_string(&event.content).unwrap()
+    );
+    EventId(Sha256::digest(serialized).into())
+}
+
+/// Events from a short note to a long article with many tags, with some characters to escape.
+fn events() -> Vec<(&'static str, Event)> {
+    let keypair = generate_keypair();
+    let note = EventBuilder::text_note("gm \"nostr\"!\n").sign(&keypair);
+    let reply = EventBuilder::text_note("replying to a few people at once")
+        .tags((0..20).map(|i| vec!["p".to_string(), format!("{:064x}", i)]))
+        .tag(["e", &format!("{:064x}", 42), "wss://nos.lol", "reply"])
+        .sign(&keypair);
+    let article = EventBuilder::new(30023, "A long article.\n\n\tWith \"quotes\".\n".repeat(500))
+        .tags((0..50).map(|i| vec!["t".to_string(), format!("topic{}", i)]))
+        .sign(&keypair);
+    vec![("note", note), ("reply", reply), ("article", article)]
+}
+
+fn bench_event_id(c: &mut Criterion) {
+    let mut group = c.benchmark_group("event_id");
+    for (name, event) in events() {
+        assert_eq!(formatted_event_id(&event), calculate_event_id(&event));
+        group.bench_with_input(BenchmarkId::new("format", name), &event, |b, event| {
+            b.iter(|| formatted_event_id(black_box(event)))
+        });
+        group.bench_with_input(BenchmarkId::new("streamed", name), &event, |b, event| {
+            b.iter(|| calculate_event_id(black_box(event)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_verify(c: &mut Criterion) {
+    let keypair = generate_keypair();
+    let mut group = c.benchmark_group("verify");
+    for count in [1, 100, 1_000] {
+        let events: Vec<Event> = (0..count)
+            .map(|i| EventBuilder::text_note(format!("note {}", i)).sign(&keypair))
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("one_by_one", count),
+            &events,
+            |b, events| {
+                b.iter(|| {
+                    black_box(events)
+                        .iter()
+                        .map(verify_event)
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("batch", count), &events, |b, events| {
+            b.iter(|| verify_events(black_box(events)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_event_id, bench_verify);
+criterion_main!(benches);
@@ -0,0 +1,81 @@
+use cornostr::crypto::generate_keypair;
+use cornostr::event::{Event, EventId, Filter, PublicKey, Signature};
+use cornostr::store::{EventStore, IndexedStore, MemoryStore};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::hint::black_box;
+
+const AUTHORS: usize = 1_000;
+
+/// Unsigned events spread over `AUTHORS` authors, a few kinds and hashtags. Stores don't verify signatures, so
+/// there's no need to pay for signing here.
+fn events(count: u64, authors: &[PublicKey]) -> Vec<Event> {
+    let sig: Signature = "0".repeat(128).parse().unwrap();
+    (0..count)
+        .map(|i| Event {
+            id: format!("{:064x}", i).parse::<EventId>().unwrap(),
+            pubkey: authors[i as usize % AUTHORS],
+            created_at: 1_700_000_000 + i,
+            kind: [1, 1, 1, 7, 6, 0][(i % 6) as usize],
+            tags: vec![
+                vec!["e".to_string(), format!("{:064x}", i / 2)],
+                vec!["t".to_string(), format!("topic{}", i % 100)],
+            ],
+            content: format!("note {}", i),
+            sig,
+        })
+        .collect()
+}
+
+fn filters(authors: &[PublicKey]) -> Vec<(&'static str, Filter)> {
+    let filter = |json: String| serde_json::from_str::<Filter>(&json).unwrap();
+    vec![
+        (
+            "ids",
+            filter(format!(r#"{{"ids":["{:064x}","{:064x}"]}}"#, 42, 4242)),
+        ),
+        (
+            "author",
+            filter(format!(r#"{{"authors":["{}"],"kinds":[1]}}"#, authors[7])),
+        ),
+        (
+            "kind_limit",
+            filter(r#"{"kinds":[0],"limit":20}"#.to_string()),
+        ),
+        ("tag", filter(format!(r##"{{"#e":["{:064x}"]}}"##, 1234))),
+        (
+            "hashtag_since",
+            filter(r##"{"#t":["topic5"],"since":1700090000}"##.to_string()),
+        ),
+        ("recent", filter(r#"{"limit":50}"#.to_string())),
+    ]
+}
+
+fn bench_query(c: &mut Criterion) {
+    let authors: Vec<PublicKey> = (0..AUTHORS)
+        .map(|_| PublicKey::from_keypair(&generate_keypair()))
+        .collect();
+
+    for count in [10_000, 100_000] {
+        let mut memory = MemoryStore::new();
+        let mut indexed = IndexedStore::new();
+        for event in events(count, &authors) {
+            memory.insert(event.clone()).unwrap();
+            indexed.insert(event).unwrap();
+        }
+
+        let mut group = c.benchmark_group(format!("query/{}", count));
+        for (name, filter) in filters(&authors) {
+            let filters = [filter];
+            group.bench_with_input(BenchmarkId::new("scan", name), &filters, |b, f| {
+                b.iter(|| memory.query(black_box(f)).unwrap())
+            });
+            group.bench_with_input(BenchmarkId::new("indexed", name), &filters, |b, f| {
+                b.iter(|| indexed.query(black_box(f)).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_query);
+criterion_main!(benches);
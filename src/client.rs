@@ -1,24 +1,76 @@
-use crate::crypto::{generate_keypair, sign_event, verify_event};
-use crate::event::Event;
+use crate::crypto::{generate_keypair, nip04, VerifyCache, VerifyError};
+use crate::event::{Event, EventBuilder, EventId, Filter, Kind, PublicKey};
+use crate::nip11::{self, RelayInformation};
+use crate::nip42;
+use crate::nip45::Count;
+use crate::nip59::{self, UnwrappedGift};
+use crate::post::create_private_message;
+use crate::Error;
 use futures_util::{SinkExt, StreamExt};
 use secp256k1::Keypair;
+use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
 use tokio_tungstenite::connect_async;
-use url::Url;
+use tokio_tungstenite::tungstenite::{self, http::Uri, Message};
+
+/// How long to wait for a relay to answer a published event with `OK`.
+const OK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of waiting messages from a relay that are handled as one batch.
+const MAX_BATCH_SIZE: usize = 256;
+
+/// How long to wait for a relay to answer an HTTP request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of headers of an HTTP response.
+const MAX_RESPONSE_HEADERS: usize = 64;
+
+/// Maximum size of an HTTP response, head and body.
+const MAX_RESPONSE_SIZE: usize = 1 << 20;
+
+type WebSocket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// A connection to a relay.
+struct Connection {
+    ws_stream: WebSocket,
+    /// Where NIP-42 authentication to the relay stands.
+    auth: AuthState,
+    /// Subscriptions the relay closed with `auth-required:` while authentication was pending, to request again once
+    /// it is accepted.
+    refused: Vec<String>,
+}
+
+/// The state of NIP-42 authentication to a relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AuthState {
+    /// The relay hasn't sent a challenge, or the client has no keypair to answer it.
+    None,
+    /// The client answered a challenge with the authentication event of this id, and waits for the relay's `OK`.
+    Pending(EventId),
+    /// The relay accepted the authentication, or refused it with this reason.
+    Done(Result<(), String>),
+}
 
 /// Represents a Nostr client that can connect to relays, publish events, and manage subscriptions.
 pub struct Client {
     /// The client's keypair for signing events. It's optional because a client might not always have a keypair set.
     keypair: Option<Keypair>,
-    /// A map of relay URLs to their corresponding WebSocket connections.
-    relays: HashMap<
-        String,
-        tokio_tungstenite::WebSocketStream<
-            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-        >,
-    >,
+    /// A map of relay URLs to their corresponding connections.
+    relays: HashMap<String, Connection>,
     /// A map of subscription IDs to the events received for that subscription.
     subscriptions: HashMap<String, Vec<Event>>,
+    /// The `REQ` message of each subscription, to send it again to relays that require authentication first.
+    requests: HashMap<String, String>,
+    /// Events already verified, so that copies received from other relays are not verified again.
+    verify_cache: Arc<VerifyCache>,
+    /// Number of `COUNT` queries sent, to give each one its own id.
+    count_queries: usize,
 }
 
 impl Default for Client {
@@ -34,15 +86,27 @@ impl Client {
             keypair: None,
             relays: HashMap::new(),
             subscriptions: HashMap::new(),
+            requests: HashMap::new(),
+            verify_cache: Arc::new(VerifyCache::default()),
+            count_queries: 0,
         }
     }
 
     /// Sets the client's keypair for signing events.
-    #[allow(dead_code)]
     pub fn set_keypair(&mut self, keypair: Keypair) {
         self.keypair = Some(keypair);
     }
 
+    /// Replaces the default cache of verified events, to share it with other clients or a relay.
+    pub fn set_verify_cache(&mut self, cache: Arc<VerifyCache>) {
+        self.verify_cache = cache;
+    }
+
+    /// Returns the cache of verified events, to read its statistics.
+    pub fn verify_cache(&self) -> &Arc<VerifyCache> {
+        &self.verify_cache
+    }
+
     /// Generates a new keypair for the client using the crypto module's generate_keypair function.
     pub fn generate_keypair(&mut self) {
         self.keypair = Some(generate_keypair());
@@ -51,109 +115,933 @@ impl Client {
     /// Connects to a Nostr relay at the given URL.
     ///
     /// This method establishes a WebSocket connection to the relay and stores it in the relays map.
-    pub async fn connect(&mut self, relay_url: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let url = Url::parse(relay_url)?;
-        let (ws_stream, _) = connect_async(url.to_string()).await?;
-        self.relays.insert(relay_url.to_string(), ws_stream);
+    pub async fn connect(&mut self, relay_url: &str) -> Result<(), Error> {
+        let (ws_stream, _) = connect_async(relay_url).await?;
+        let connection = Connection {
+            ws_stream,
+            auth: AuthState::None,
+            refused: Vec::new(),
+        };
+        self.relays.insert(relay_url.to_string(), connection);
         Ok(())
     }
 
-    /// Publishes an event to all connected relays.
+    /// Fetches the NIP-11 information document of the relay at `relay_url`, which doesn't need to be connected.
+    pub async fn fetch_relay_info(&self, relay_url: &str) -> Result<RelayInformation, Error> {
+        let body = timeout(HTTP_TIMEOUT, http_get(relay_url, nip11::MEDIA_TYPE))
+            .await
+            .map_err(|_| {
+                Error::Protocol("timed out waiting for the relay information document".to_string())
+            })??;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Signs an event with the client's keypair and publishes it to all connected relays.
     ///
-    /// This method signs the event with the client's keypair (if set), then sends it to all connected relays.
-    pub async fn publish_event(
-        &mut self,
-        event: &mut Event,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(keypair) = &self.keypair {
-            // Sign the event
-            event.sig = sign_event(event, keypair);
+    /// Returns the published event.
+    pub async fn publish(&mut self, builder: EventBuilder) -> Result<Event, Error> {
+        let keypair = self.keypair.as_ref().ok_or(Error::MissingKeypair)?;
+        let event = builder.sign(keypair);
+        self.publish_event(&event).await?;
+        Ok(event)
+    }
 
-            // Create a JSON array with "EVENT" and the event
-            let message = serde_json::json!(["EVENT", event]);
+    /// Publishes a signed event to all connected relays.
+    ///
+    /// This method sends the event to all connected relays and waits for each of them to accept it. Events received
+    /// for subscriptions in the meantime are kept.
+    ///
+    /// Relays that refuse the event with `auth-required:` while the client authenticates to them are sent the event
+    /// again once they accept the authentication.
+    ///
+    /// Returns [`Error::RelayRejected`] with the relay's reason if any relay refused the event.
+    pub async fn publish_event(&mut self, event: &Event) -> Result<(), Error> {
+        // Create a JSON array with "EVENT" and the event
+        let message = serde_json::json!(["EVENT", event]);
 
-            // Convert the entire structure to a string
-            let message_string = serde_json::to_string(&message)?;
+        // Convert the entire structure to a string
+        let message_string = serde_json::to_string(&message)?;
 
-            // Send the message to all connected relays
-            for ws_stream in self.relays.values_mut() {
-                ws_stream
-                    .send(tokio_tungstenite::tungstenite::Message::Text(
-                        message_string.clone(),
-                    ))
+        // Send the message to all connected relays
+        for connection in self.relays.values_mut() {
+            connection
+                .ws_stream
+                .send(Message::Text(message_string.clone()))
+                .await?;
+        }
+
+        // Collect every relay's answer before reporting the first rejection
+        let mut result = Ok(());
+        for (relay_url, connection) in self.relays.iter_mut() {
+            let mut relay = RelayContext {
+                url: relay_url,
+                keypair: self.keypair.as_ref(),
+                subscriptions: &mut self.subscriptions,
+                requests: &self.requests,
+                cache: &self.verify_cache,
+            };
+            let mut answer = wait_for_ok(connection, &event.id, &mut relay).await?;
+            if answer
+                .as_ref()
+                .is_err_and(|reason| reason.starts_with("auth-required:"))
+                && wait_for_auth(connection, &mut relay).await? == Ok(())
+            {
+                connection
+                    .ws_stream
+                    .send(Message::Text(message_string.clone()))
                     .await?;
+                answer = wait_for_ok(connection, &event.id, &mut relay).await?;
+            }
+            if let (Err(reason), Ok(())) = (answer, &result) {
+                result = Err(Error::RelayRejected {
+                    relay: relay_url.clone(),
+                    reason,
+                });
             }
-            Ok(())
-        } else {
-            Err("No keypair set".into())
         }
+        result
     }
 
     /// Creates a new subscription with the given ID and filter.
     ///
     /// This method sends a subscription request to all connected relays and initializes
     /// an empty vector in the subscriptions map to store future events for this subscription.
-    pub async fn subscribe(
-        &mut self,
-        subscription_id: &str,
-        filter: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn subscribe(&mut self, subscription_id: &str, filter: &str) -> Result<(), Error> {
         // Prepare the subscription message in the format expected by relays: ["REQ", <subscription_id>, <filter>]
         let message = format!("[\"{}\", \"{}\", {}]", "REQ", subscription_id, filter);
         // Send the subscription request to all connected relays
-        for ws_stream in self.relays.values_mut() {
-            ws_stream
-                .send(tokio_tungstenite::tungstenite::Message::Text(
-                    message.clone(),
-                ))
+        for connection in self.relays.values_mut() {
+            connection
+                .ws_stream
+                .send(Message::Text(message.clone()))
                 .await?;
         }
         // Initialize an empty vector for this subscription to store future events
         self.subscriptions
             .insert(subscription_id.to_string(), Vec::new());
+        self.requests.insert(subscription_id.to_string(), message);
         Ok(())
     }
 
+    /// Asks all connected relays how many events match any of the filters, and combines their answers with
+    /// [`Count::merge`].
+    ///
+    /// Relays that refuse the query with `auth-required:` while the client authenticates to them are asked again
+    /// once they accept the authentication. Relays that still refuse it, time out, disconnect or don't follow the
+    /// protocol are left out. Returns [`Error::Protocol`] if no relay answered.
+    pub async fn count(&mut self, filters: &[Filter]) -> Result<Count, Error> {
+        self.count_queries += 1;
+        let query_id = format!("count-{}", self.count_queries);
+        let mut message = vec![serde_json::json!("COUNT"), serde_json::json!(query_id)];
+        for filter in filters {
+            message.push(serde_json::to_value(filter)?);
+        }
+        let message_string = serde_json::to_string(&message)?;
+        let mut sent = HashMap::new();
+        for (relay_url, connection) in self.relays.iter_mut() {
+            let result = connection
+                .ws_stream
+                .send(Message::Text(message_string.clone()))
+                .await;
+            sent.insert(relay_url.clone(), result.map_err(Error::from));
+        }
+
+        let mut total: Option<Count> = None;
+        let mut refusal = None;
+        for (relay_url, connection) in self.relays.iter_mut() {
+            let mut relay = RelayContext {
+                url: relay_url,
+                keypair: self.keypair.as_ref(),
+                subscriptions: &mut self.subscriptions,
+                requests: &self.requests,
+                cache: &self.verify_cache,
+            };
+            let answer = match sent.remove(relay_url).unwrap() {
+                Ok(()) => count_answer(connection, &message_string, &query_id, &mut relay).await,
+                Err(e) => Err(e),
+            };
+            match answer {
+                Ok(Ok(count)) => total = Some(total.map_or(count, |total| total.merge(count))),
+                Ok(Err(reason)) => {
+                    refusal = Some(format!("{} refused to count: {}", relay_url, reason))
+                }
+                Err(e) => refusal = Some(format!("{} could not count: {}", relay_url, e)),
+            }
+        }
+        total.ok_or_else(|| {
+            Error::Protocol(refusal.unwrap_or_else(|| "not connected to any relay".to_string()))
+        })
+    }
+
     /// Receives and processes events from all connected relays.
     ///
     /// This method listens for incoming messages from all relays, verifies received events,
-    /// and stores them in the appropriate subscription's event list.
-    pub async fn receive_events(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        for ws_stream in self.relays.values_mut() {
-            while let Some(message) = ws_stream.next().await {
-                let message = message?;
-                match message {
-                    tokio_tungstenite::tungstenite::Message::Text(text) => {
-                        // Parse the incoming message as JSON
-                        let json: serde_json::Value = serde_json::from_str(&text)?;
-                        // Print the text as pretty JSON
-                        println!("{}", serde_json::to_string_pretty(&json)?);
-                        // Check if the message is an event message
-                        if json[0] == "EVENT" && json[1].is_string() && json[2].is_object() {
-                            let subscription_id = json[1].as_str().unwrap();
-                            let event: Event = serde_json::from_value(json[2].clone())?;
-                            // Verify the event's signature
-                            if verify_event(&event) {
-                                // If the event is valid, add it to the appropriate subscription's event list
-                                if let Some(events) = self.subscriptions.get_mut(subscription_id) {
-                                    events.push(event);
-                                }
-                            }
+    /// and stores them in the appropriate subscription's event list. Authentication challenges are answered with the
+    /// client's keypair.
+    pub async fn receive_events(&mut self) -> Result<(), Error> {
+        for (relay_url, connection) in self.relays.iter_mut() {
+            let mut relay = RelayContext {
+                url: relay_url,
+                keypair: self.keypair.as_ref(),
+                subscriptions: &mut self.subscriptions,
+                requests: &self.requests,
+                cache: &self.verify_cache,
+            };
+            // Messages that are already waiting are handled together, so that their events are verified as one batch
+            let mut batches = (&mut connection.ws_stream).ready_chunks(MAX_BATCH_SIZE);
+            while let Some(batch) = batches.next().await {
+                let mut messages = Vec::with_capacity(batch.len());
+                for message in batch {
+                    match message? {
+                        Message::Text(text) => {
+                            // Parse the incoming message as JSON
+                            let json: Value = serde_json::from_str(&text)?;
+                            // Print the text as pretty JSON
+                            println!("{}", serde_json::to_string_pretty(&json)?);
+                            messages.push(json);
                         }
+                        Message::Binary(data) => {
+                            println!("Received binary data: {:?}", data);
+                        }
+                        _ => {}
                     }
-                    tokio_tungstenite::tungstenite::Message::Binary(data) => {
-                        println!("Received binary data: {:?}", data);
-                    }
-                    _ => {}
                 }
+                handle_relay_messages(
+                    batches.get_mut(),
+                    &mut connection.auth,
+                    &mut connection.refused,
+                    &mut relay,
+                    messages,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds events obtained some other way, such as from a backup, to a subscription's event list.
+    ///
+    /// The events are verified in parallel and only the valid ones are kept. Returns the verification result of each
+    /// event, in order.
+    pub fn import_events(
+        &mut self,
+        subscription_id: &str,
+        events: Vec<Event>,
+    ) -> Vec<Result<(), VerifyError>> {
+        let results = self.verify_cache.verify_events(&events);
+        let cached = self
+            .subscriptions
+            .entry(subscription_id.to_string())
+            .or_default();
+        for (event, result) in events.into_iter().zip(&results) {
+            if result.is_ok() {
+                cache_event(cached, event);
             }
         }
+        results
+    }
+
+    /// Decrypts a legacy NIP-04 direct message (kind 4) sent to or by the client's keypair.
+    ///
+    /// Returns [`Error::Protocol`] if the event is not a direct message between the keypair and someone else.
+    pub fn decrypt_dm(&self, event: &Event) -> Result<String, Error> {
+        let keypair = self.keypair.as_ref().ok_or(Error::MissingKeypair)?;
+        if event.kind != u32::from(Kind::ENCRYPTED_DIRECT_MESSAGE) {
+            return Err(Error::Protocol(format!(
+                "kind {} is not a direct message",
+                event.kind
+            )));
+        }
+        let own_pubkey = PublicKey::from_keypair(keypair);
+        let recipients = event.mentioned_pubkeys();
+        let peer = if event.pubkey == own_pubkey {
+            recipients.first().copied()
+        } else {
+            recipients.contains(&own_pubkey).then_some(event.pubkey)
+        };
+        let peer = peer.ok_or_else(|| {
+            Error::Protocol("direct message is not addressed to this keypair".to_string())
+        })?;
+        Ok(nip04::decrypt(keypair, &peer, &event.content)?)
+    }
+
+    /// Returns the legacy NIP-04 direct messages of a subscription that could be decrypted, with their plaintext.
+    pub fn direct_messages(&self, subscription_id: &str) -> Vec<(&Event, String)> {
+        self.subscriptions
+            .get(subscription_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|event| Some((event, self.decrypt_dm(event).ok()?)))
+            .collect()
+    }
+
+    /// Sends a NIP-17 private direct message to `receivers`, publishing a gift wrap for each of them and one for the
+    /// client's own copy.
+    pub async fn send_private_message(
+        &mut self,
+        receivers: &[PublicKey],
+        message: &str,
+    ) -> Result<(), Error> {
+        let keypair = self.keypair.as_ref().ok_or(Error::MissingKeypair)?;
+        for gift_wrap in create_private_message(keypair, receivers, message)? {
+            self.publish_event(&gift_wrap).await?;
+        }
         Ok(())
     }
 
+    /// Subscribes to the gift wraps addressed to the client's keypair, to read them with [`Client::gift_wraps`].
+    pub async fn subscribe_gift_wraps(&mut self, subscription_id: &str) -> Result<(), Error> {
+        let keypair = self.keypair.as_ref().ok_or(Error::MissingKeypair)?;
+        let filter = Filter {
+            kinds: Some(vec![Kind::GIFT_WRAP.into()]),
+            tags: [('p', vec![PublicKey::from_keypair(keypair).to_string()])].into(),
+            ..Filter::default()
+        };
+        self.subscribe(subscription_id, &serde_json::to_string(&filter)?)
+            .await
+    }
+
+    /// Returns the rumors of a subscription's gift wraps that the client's keypair can open, with their verified
+    /// sender, oldest first.
+    ///
+    /// Gift wraps that are not addressed to the keypair or that fail verification are skipped.
+    pub fn gift_wraps(&self, subscription_id: &str) -> Vec<UnwrappedGift> {
+        let Some(keypair) = self.keypair.as_ref() else {
+            return Vec::new();
+        };
+        let mut unwrapped: Vec<UnwrappedGift> = self
+            .subscriptions
+            .get(subscription_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|event| nip59::unwrap(event, keypair).ok())
+            .collect();
+        unwrapped.sort_by_key(|gift| (gift.rumor.created_at, gift.rumor.id));
+        // The same rumor can be wrapped several times, for instance to several of our devices
+        unwrapped.dedup_by_key(|gift| gift.rumor.id);
+        unwrapped
+    }
+
     /// Retrieves the list of events for a given subscription ID.
     #[allow(dead_code)]
     pub fn get_events(&self, subscription_id: &str) -> Option<&Vec<Event>> {
         self.subscriptions.get(subscription_id)
     }
 }
+
+/// What messages from a relay are handled with.
+struct RelayContext<'a> {
+    url: &'a str,
+    keypair: Option<&'a Keypair>,
+    subscriptions: &'a mut HashMap<String, Vec<Event>>,
+    requests: &'a HashMap<String, String>,
+    cache: &'a VerifyCache,
+}
+
+/// Handles messages from a relay: answers its authentication challenges, records whether it accepted the
+/// authentication, and stores the events of `EVENT` messages.
+///
+/// Subscriptions the relay closes with `auth-required:` while the client authenticates are requested again once the
+/// relay accepts the authentication.
+async fn handle_relay_messages(
+    ws_stream: &mut WebSocket,
+    auth: &mut AuthState,
+    refused: &mut Vec<String>,
+    relay: &mut RelayContext<'_>,
+    messages: Vec<Value>,
+) -> Result<(), Error> {
+    for json in &messages {
+        match (json[0].as_str(), &*auth) {
+            (Some("AUTH"), _) => {
+                let (Some(challenge), Some(keypair)) = (json[1].as_str(), relay.keypair) else {
+                    continue;
+                };
+                let event = nip42::auth_event(keypair, relay.url, challenge);
+                let message = serde_json::json!(["AUTH", event]);
+                ws_stream
+                    .send(Message::Text(serde_json::to_string(&message)?))
+                    .await?;
+                *auth = AuthState::Pending(event.id);
+            }
+            (Some("OK"), AuthState::Pending(id)) if json[1] == id.to_string().as_str() => {
+                *auth = AuthState::Done(ok_result(json)?);
+                let requests = std::mem::take(refused);
+                if *auth != AuthState::Done(Ok(())) {
+                    continue;
+                }
+                for subscription_id in requests {
+                    if let Some(request) = relay.requests.get(&subscription_id) {
+                        ws_stream.send(Message::Text(request.clone())).await?;
+                    }
+                }
+            }
+            (Some("CLOSED"), AuthState::Pending(_)) => {
+                let (Some(subscription_id), Some(reason)) = (json[1].as_str(), json[2].as_str())
+                else {
+                    continue;
+                };
+                if reason.starts_with("auth-required:")
+                    && relay.requests.contains_key(subscription_id)
+                {
+                    refused.push(subscription_id.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    handle_subscription_events(relay.subscriptions, relay.cache, messages);
+    Ok(())
+}
+
+/// Stores the events of `EVENT` messages in their subscription's event list if their signature is valid, verifying
+/// them in parallel.
+///
+/// Events that don't parse are skipped like those that fail verification, and other messages are ignored.
+fn handle_subscription_events(
+    subscriptions: &mut HashMap<String, Vec<Event>>,
+    cache: &VerifyCache,
+    messages: Vec<Value>,
+) {
+    let mut subscription_ids = Vec::new();
+    let mut events = Vec::new();
+    for mut json in messages {
+        if json[0] != "EVENT" || !json[1].is_string() {
+            continue;
+        }
+        let Ok(event) = serde_json::from_value::<Event>(json[2].take()) else {
+            continue;
+        };
+        events.push(event);
+        subscription_ids.push(json[1].take());
+    }
+
+    // Verify the events' signatures, and add them to the appropriate subscription's event list if they're valid
+    let results = cache.verify_events(&events);
+    for ((subscription_id, event), result) in subscription_ids.iter().zip(events).zip(results) {
+        let subscription_id = subscription_id.as_str().unwrap_or_default();
+        if let (Ok(()), Some(cached)) = (result, subscriptions.get_mut(subscription_id)) {
+            cache_event(cached, event);
+        }
+    }
+}
+
+/// Reads messages from a relay until it answers the event with `OK`, returning the rejection reason if the event was
+/// refused.
+async fn wait_for_ok(
+    connection: &mut Connection,
+    id: &EventId,
+    relay: &mut RelayContext<'_>,
+) -> Result<Result<(), String>, Error> {
+    let id = id.to_string();
+    let json = wait_for_answer(connection, relay, "the event to be acknowledged", |json| {
+        json[0] == "OK" && json[1] == id.as_str()
+    })
+    .await?;
+    ok_result(&json)
+}
+
+/// Waits for a relay's answer to the `COUNT` query in `message`, asking again once authenticated if the relay
+/// requires it.
+async fn count_answer(
+    connection: &mut Connection,
+    message: &str,
+    query_id: &str,
+    relay: &mut RelayContext<'_>,
+) -> Result<Result<Count, String>, Error> {
+    let answer = wait_for_count(connection, query_id, relay).await?;
+    if answer
+        .as_ref()
+        .is_err_and(|reason| reason.starts_with("auth-required:"))
+        && wait_for_auth(connection, relay).await? == Ok(())
+    {
+        connection
+            .ws_stream
+            .send(Message::Text(message.to_string()))
+            .await?;
+        return wait_for_count(connection, query_id, relay).await;
+    }
+    Ok(answer)
+}
+
+/// Reads messages from a relay until it answers a `COUNT` query, returning the reason if the query was refused.
+async fn wait_for_count(
+    connection: &mut Connection,
+    query_id: &str,
+    relay: &mut RelayContext<'_>,
+) -> Result<Result<Count, String>, Error> {
+    let json = wait_for_answer(connection, relay, "the count", |json| {
+        (json[0] == "COUNT" || json[0] == "CLOSED") && json[1] == query_id
+    })
+    .await?;
+    if json[0] == "CLOSED" {
+        return Ok(Err(json[2].as_str().unwrap_or_default().to_string()));
+    }
+    match serde_json::from_value(json[2].clone()) {
+        Ok(count) => Ok(Ok(count)),
+        Err(_) => Err(Error::Protocol(format!(
+            "malformed COUNT message: {}",
+            json
+        ))),
+    }
+}
+
+/// Reads messages from a relay until `is_answer` accepts one, and returns it. The others are handled as usual.
+async fn wait_for_answer(
+    connection: &mut Connection,
+    relay: &mut RelayContext<'_>,
+    waiting_for: &str,
+    is_answer: impl Fn(&Value) -> bool,
+) -> Result<Value, Error> {
+    loop {
+        let json = next_message(&mut connection.ws_stream, waiting_for).await?;
+        if is_answer(&json) {
+            return Ok(json);
+        }
+        handle_relay_messages(
+            &mut connection.ws_stream,
+            &mut connection.auth,
+            &mut connection.refused,
+            relay,
+            vec![json],
+        )
+        .await?;
+    }
+}
+
+/// Reads messages from a relay until it answers the pending authentication, and returns whether it accepted it.
+///
+/// Returns right away if the client didn't answer any challenge.
+async fn wait_for_auth(
+    connection: &mut Connection,
+    relay: &mut RelayContext<'_>,
+) -> Result<Result<(), String>, Error> {
+    loop {
+        match &connection.auth {
+            AuthState::None => return Ok(Err("not authenticated".to_string())),
+            AuthState::Done(result) => return Ok(result.clone()),
+            AuthState::Pending(_) => {}
+        }
+        let json = next_message(&mut connection.ws_stream, "the authentication result").await?;
+        handle_relay_messages(
+            &mut connection.ws_stream,
+            &mut connection.auth,
+            &mut connection.refused,
+            relay,
+            vec![json],
+        )
+        .await?;
+    }
+}
+
+/// Reads the next text message from a relay, waiting at most [`OK_TIMEOUT`] for it.
+async fn next_message(ws_stream: &mut WebSocket, waiting_for: &str) -> Result<Value, Error> {
+    loop {
+        let message = match timeout(OK_TIMEOUT, ws_stream.next()).await {
+            Ok(Some(message)) => message?,
+            Ok(None) => {
+                return Err(Error::Protocol(format!(
+                    "connection closed while waiting for {}",
+                    waiting_for
+                )))
+            }
+            Err(_) => {
+                return Err(Error::Protocol(format!(
+                    "timed out waiting for {}",
+                    waiting_for
+                )))
+            }
+        };
+        if let Message::Text(text) = message {
+            return Ok(serde_json::from_str(&text)?);
+        }
+    }
+}
+
+/// Returns whether an `OK` message accepted its event, with the reason if it didn't.
+fn ok_result(json: &Value) -> Result<Result<(), String>, Error> {
+    match json[2].as_bool() {
+        Some(true) => Ok(Ok(())),
+        Some(false) => Ok(Err(json[3].as_str().unwrap_or_default().to_string())),
+        None => Err(Error::Protocol(format!("malformed OK message: {}", json))),
+    }
+}
+
+/// Requests a relay's URL over HTTP, or HTTPS for `wss://` URLs, and returns the body of the response.
+async fn http_get(relay_url: &str, accept: &str) -> Result<Vec<u8>, Error> {
+    let invalid_url = || Error::Protocol(format!("invalid relay URL: {}", relay_url));
+    let uri: Uri = relay_url.parse().map_err(|_| invalid_url())?;
+    let tls = match uri.scheme_str() {
+        Some("wss" | "https") => true,
+        Some("ws" | "http") => false,
+        _ => return Err(invalid_url()),
+    };
+    let authority = uri.authority().ok_or_else(invalid_url)?;
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = authority.port_u16().unwrap_or(if tls { 443 } else { 80 });
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+
+    // HTTP/1.0 so that the relay closes the connection after the body, which is never chunked
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: {}\r\n\r\n",
+        path, authority, accept
+    );
+    let stream = TcpStream::connect((host, port)).await?;
+    let mut response = if tls {
+        let tls_error = |e: native_tls::Error| Error::from(tungstenite::Error::Tls(e.into()));
+        let connector = native_tls::TlsConnector::new().map_err(tls_error)?;
+        let stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect(host, stream)
+            .await
+            .map_err(tls_error)?;
+        exchange(stream, request.as_bytes()).await?
+    } else {
+        exchange(stream, request.as_bytes()).await?
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let head_len = match parsed.parse(&response) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        Ok(httparse::Status::Partial) => {
+            return Err(Error::Protocol("incomplete HTTP response".to_string()))
+        }
+        Err(e) => return Err(Error::Protocol(format!("invalid HTTP response: {}", e))),
+    };
+    match parsed.code {
+        Some(200) => Ok(response.split_off(head_len)),
+        code => Err(Error::Protocol(format!(
+            "relay answered with HTTP status {}",
+            code.unwrap_or_default()
+        ))),
+    }
+}
+
+/// Sends a request and reads the response until the other side closes the connection, failing if it is larger
+/// than [`MAX_RESPONSE_SIZE`].
+async fn exchange(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request: &[u8],
+) -> Result<Vec<u8>, Error> {
+    stream.write_all(request).await?;
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_RESPONSE_SIZE as u64 + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() > MAX_RESPONSE_SIZE {
+        return Err(Error::Protocol("HTTP response is too large".to_string()));
+    }
+    Ok(response)
+}
+
+/// Adds an event to a subscription's event list, keeping only the latest version of replaceable and addressable
+/// events.
+fn cache_event(events: &mut Vec<Event>, event: Event) {
+    if events
+        .iter()
+        .any(|e| e.id == event.id || e.replaces(&event))
+    {
+        return;
+    }
+    events.retain(|e| !event.replaces(e));
+    events.push(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::create_dm;
+    use crate::relay::{Config, Relay};
+    use tokio::net::TcpListener;
+
+    /// Binds a listener on a free port for a relay, and returns it with the relay's URL.
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    #[test]
+    fn test_decrypt_dm() {
+        let mut alice = Client::new();
+        alice.generate_keypair();
+        let mut bob = Client::new();
+        bob.generate_keypair();
+        let mut eve = Client::new();
+        eve.generate_keypair();
+
+        let bob_pubkey = PublicKey::from_keypair(bob.keypair.as_ref().unwrap());
+        let dm = create_dm(alice.keypair.as_ref().unwrap(), &bob_pubkey, "Hello, Bob!");
+        assert_eq!(bob.decrypt_dm(&dm).unwrap(), "Hello, Bob!");
+        assert_eq!(alice.decrypt_dm(&dm).unwrap(), "Hello, Bob!");
+        assert!(matches!(eve.decrypt_dm(&dm), Err(Error::Protocol(_))));
+        assert!(matches!(
+            Client::new().decrypt_dm(&dm),
+            Err(Error::MissingKeypair)
+        ));
+
+        let note = EventBuilder::text_note("Hello, Nostr!").sign(alice.keypair.as_ref().unwrap());
+        assert!(matches!(bob.decrypt_dm(&note), Err(Error::Protocol(_))));
+
+        // Other clients often send an empty relay hint
+        let content = nip04::encrypt(alice.keypair.as_ref().unwrap(), &bob_pubkey, "Hi again!");
+        let with_empty_relay = EventBuilder::new(Kind::ENCRYPTED_DIRECT_MESSAGE, content)
+            .tag(["p", &bob_pubkey.to_string(), ""])
+            .sign(alice.keypair.as_ref().unwrap());
+        assert_eq!(bob.decrypt_dm(&with_empty_relay).unwrap(), "Hi again!");
+        assert_eq!(alice.decrypt_dm(&with_empty_relay).unwrap(), "Hi again!");
+
+        let imported = bob.import_events("dms", vec![dm.clone(), note]);
+        assert!(imported.iter().all(Result::is_ok));
+        assert_eq!(
+            bob.direct_messages("dms"),
+            vec![(&dm, "Hello, Bob!".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_gift_wraps() {
+        let mut alice = Client::new();
+        alice.generate_keypair();
+        let mut bob = Client::new();
+        bob.generate_keypair();
+        let alice_pubkey = PublicKey::from_keypair(alice.keypair.as_ref().unwrap());
+        let bob_pubkey = PublicKey::from_keypair(bob.keypair.as_ref().unwrap());
+
+        let first = EventBuilder::text_note("first").created_at(100);
+        let rumor = nip59::Rumor::new(first, &alice_pubkey);
+        let mut wraps = vec![
+            nip59::wrap(&rumor, alice.keypair.as_ref().unwrap(), &bob_pubkey).unwrap(),
+            nip59::wrap(&rumor, alice.keypair.as_ref().unwrap(), &bob_pubkey).unwrap(),
+        ];
+        wraps.extend(
+            create_private_message(alice.keypair.as_ref().unwrap(), &[bob_pubkey], "second")
+                .unwrap(),
+        );
+        wraps.push(create_dm(
+            alice.keypair.as_ref().unwrap(),
+            &bob_pubkey,
+            "legacy",
+        ));
+
+        bob.import_events("gifts", wraps.clone());
+        let gifts = bob.gift_wraps("gifts");
+        assert_eq!(gifts.len(), 2);
+        assert!(gifts.iter().all(|gift| gift.sender == alice_pubkey));
+        assert_eq!(gifts[0].rumor, rumor);
+        assert_eq!(gifts[1].rumor.content, "second");
+        assert_eq!(gifts[1].rumor.kind, 14);
+
+        // Alice can read her own copy of the private message
+        alice.import_events("gifts", wraps);
+        let gifts = alice.gift_wraps("gifts");
+        assert_eq!(gifts.len(), 1);
+        assert_eq!(gifts[0].rumor.content, "second");
+    }
+
+    #[test]
+    fn test_malformed_events_are_skipped() {
+        let keypair = generate_keypair();
+        let good = EventBuilder::text_note("good").sign(&keypair);
+        let mut bad = serde_json::to_value(EventBuilder::text_note("bad").sign(&keypair)).unwrap();
+        bad["sig"] = "not a signature".into();
+        let mut subscriptions = HashMap::from([("sub".to_string(), Vec::new())]);
+
+        handle_subscription_events(
+            &mut subscriptions,
+            &VerifyCache::default(),
+            vec![
+                serde_json::json!(["EVENT", "sub", bad]),
+                serde_json::json!(["EVENT", "sub", good]),
+            ],
+        );
+
+        assert_eq!(subscriptions["sub"], vec![good]);
+    }
+
+    #[tokio::test]
+    async fn test_publish_with_auth() {
+        let (listener, url) = listen().await;
+        let url = url.as_str();
+        let relay = Relay::new().with_config(Config {
+            relay_url: Some(url.to_string()),
+            require_auth: true,
+            ..Config::default()
+        });
+        tokio::spawn(async move { relay.run_on(listener).await });
+
+        let mut client = Client::new();
+        client.generate_keypair();
+        client.connect(url).await.unwrap();
+        // The relay refuses the event until the client answers its challenge, then accepts it again
+        let event = client
+            .publish(EventBuilder::text_note("Hello"))
+            .await
+            .unwrap();
+        let connection = &client.relays[url];
+        assert!(matches!(connection.auth, AuthState::Done(Ok(()))));
+
+        // Without a keypair, the challenge can't be answered
+        let mut anonymous = Client::new();
+        anonymous.connect(url).await.unwrap();
+        match anonymous.publish_event(&event).await {
+            Err(Error::RelayRejected { reason, .. }) => {
+                assert!(reason.starts_with("auth-required:"))
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_gift_wraps() {
+        let (listener, url) = listen().await;
+        tokio::spawn(async move { Relay::new().run_on(listener).await });
+
+        let mut alice = Client::new();
+        alice.generate_keypair();
+        alice.connect(&url).await.unwrap();
+        let mut bob = Client::new();
+        bob.generate_keypair();
+        bob.connect(&url).await.unwrap();
+        let bob_pubkey = PublicKey::from_keypair(bob.keypair.as_ref().unwrap());
+
+        // The relay only serves gift wraps once Bob authenticates, which he does when he reads its challenge
+        bob.subscribe_gift_wraps("gifts").await.unwrap();
+        alice
+            .send_private_message(&[bob_pubkey], "Hello, Bob!")
+            .await
+            .unwrap();
+
+        let received = timeout(Duration::from_secs(10), async {
+            while bob.gift_wraps("gifts").is_empty() {
+                let _ = timeout(Duration::from_millis(50), bob.receive_events()).await;
+            }
+        })
+        .await;
+        assert!(received.is_ok(), "no gift wrap received");
+        let gifts = bob.gift_wraps("gifts");
+        assert_eq!(gifts.len(), 1);
+        assert_eq!(gifts[0].rumor.content, "Hello, Bob!");
+    }
+
+    #[tokio::test]
+    async fn test_exchange_caps_response_size() {
+        for (size, fits) in [(MAX_RESPONSE_SIZE, true), (MAX_RESPONSE_SIZE + 1, false)] {
+            let (client, mut server) = tokio::io::duplex(64 * 1024);
+            let answer = tokio::spawn(async move {
+                let mut request = [0; 4];
+                server.read_exact(&mut request).await.unwrap();
+                // The client may stop reading once over the limit
+                let _ = server.write_all(&vec![b'a'; size]).await;
+            });
+            let response = exchange(client, b"GET\n").await;
+            assert_eq!(response.is_ok(), fits);
+            answer.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_relay_info() {
+        let relay = Relay::new().with_config(Config {
+            info: RelayInformation {
+                name: Some("Test relay".to_string()),
+                ..RelayInformation::default()
+            },
+            ..Config::default()
+        });
+        let (listener, url) = listen().await;
+        tokio::spawn(async move { relay.run_on(listener).await });
+
+        let client = Client::new();
+        let info = client.fetch_relay_info(&url).await.unwrap();
+        assert_eq!(info.name.as_deref(), Some("Test relay"));
+        assert!(info.supported_nips.contains(&11));
+        assert_eq!(info.limitation.unwrap().auth_required, Some(false));
+
+        assert!(matches!(
+            client
+                .fetch_relay_info(&url.replace("ws://", "ftp://"))
+                .await,
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_count() {
+        let mut urls = Vec::new();
+        for _ in 0..2 {
+            let (listener, url) = listen().await;
+            tokio::spawn(async move { Relay::new().run_on(listener).await });
+            urls.push(url);
+        }
+
+        let mut alice = Client::new();
+        alice.generate_keypair();
+        assert!(matches!(
+            alice.count(&[Filter::default()]).await,
+            Err(Error::Protocol(_))
+        ));
+        for url in &urls {
+            alice.connect(url).await.unwrap();
+        }
+        let mut bob = Client::new();
+        bob.generate_keypair();
+        bob.connect(&urls[1]).await.unwrap();
+
+        for content in ["first", "second"] {
+            alice
+                .publish(EventBuilder::text_note(content))
+                .await
+                .unwrap();
+        }
+        bob.publish(EventBuilder::text_note("third")).await.unwrap();
+
+        let alice_pubkey = PublicKey::from_keypair(alice.keypair.as_ref().unwrap());
+        let by_alice = Filter {
+            authors: Some(vec![alice_pubkey]),
+            ..Filter::default()
+        };
+        let count = alice.count(&[by_alice]).await.unwrap();
+        assert_eq!(
+            count,
+            Count {
+                count: 2,
+                approximate: false
+            }
+        );
+
+        // The relays disagree, so all that is known is that there are at least 3 notes
+        let notes = [Filter {
+            kinds: Some(vec![1]),
+            ..Filter::default()
+        }];
+        let count = alice.count(&notes).await.unwrap();
+        assert_eq!(
+            count,
+            Count {
+                count: 3,
+                approximate: true
+            }
+        );
+
+        // Relays that can't count are left out like those that refuse
+        let (listener, url) = listen().await;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+                let json: Value = serde_json::from_str(&text).unwrap();
+                let malformed = serde_json::json!(["COUNT", json[1], "many"]);
+                ws_stream
+                    .send(Message::Text(malformed.to_string()))
+                    .await
+                    .unwrap();
+            }
+        });
+        alice.connect(&url).await.unwrap();
+        assert_eq!(alice.count(&notes).await.unwrap(), count);
+    }
+}
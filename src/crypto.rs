@@ -1,61 +1,166 @@
+use std::fmt;
+use std::thread;
+
 use rand::rngs::OsRng;
-use secp256k1::{schnorr, Keypair, Message, Secp256k1, XOnlyPublicKey};
+use secp256k1::{Keypair, Message, SECP256K1};
+
+use crate::event::{calculate_event_id, Event, EventId, Signature};
 
-use crate::event::Event;
+mod cache;
+/// Legacy NIP-04 encryption of kind 4 direct messages, superseded by [`nip44`].
+pub mod nip04;
+/// NIP-44 versioned encryption, to use for anything new.
+pub mod nip44;
+
+pub use cache::{CacheStats, VerifyCache, DEFAULT_CAPACITY};
 
 /// Generates a new secp256k1 keypair for use in Nostr.
 pub fn generate_keypair() -> Keypair {
-    // Create a new Secp256k1 context
-    let secp = Secp256k1::new();
-
-    // Generate a new keypair
-    Keypair::new(&secp, &mut OsRng)
+    Keypair::new(SECP256K1, &mut OsRng)
 }
 
 /// Signs a Nostr event using the provided secret key.
-pub fn sign_event(event: &Event, keypair: &Keypair) -> String {
+pub fn sign_event(event: &Event, keypair: &Keypair) -> Signature {
+    sign_event_id(&event.id, keypair)
+}
+
+/// Signs an event id, for events that are still being assembled.
+pub fn sign_event_id(id: &EventId, keypair: &Keypair) -> Signature {
     // Create a message from the event ID
-    let message = Message::from_digest_slice(&hex::decode(&event.id).unwrap()).unwrap();
+    let message = Message::from_digest(id.0);
 
     // Sign the message using Schnorr signature
-    let signature = keypair.sign_schnorr(message);
-
-    // Convert the signature to a hex-encoded string
-    hex::encode(signature.as_ref())
+    Signature(keypair.sign_schnorr(message))
 }
 
-/// Verifies the signature of a Nostr event.
-pub fn verify_event(event: &Event) -> bool {
-    let secp = Secp256k1::new();
+/// Why an event failed verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The id is not the hash of the serialized event.
+    Id,
+    /// The pubkey is well-formed hex but not a valid x-only public key.
+    ///
+    /// A parsed [`Event`] always holds a valid pubkey, so this is only used for raw events that fail to parse because
+    /// of it, such as those a relay rejects.
+    PublicKey,
+    /// The signature is not valid for the id and pubkey.
+    Signature,
+}
 
-    // Parse the public key
-    let pubkey = match XOnlyPublicKey::from_slice(&hex::decode(&event.pubkey).unwrap()) {
-        Ok(key) => key,
-        Err(e) => {
-            println!("Failed to parse public key: {:?}", e);
-            return false;
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Id => write!(f, "event id does not match the serialized event"),
+            VerifyError::PublicKey => write!(f, "pubkey is not a valid public key"),
+            VerifyError::Signature => write!(f, "signature verification failed"),
         }
-    };
-
-    // Parse the signature
-    let signature = match schnorr::Signature::from_slice(&hex::decode(&event.sig).unwrap()) {
-        Ok(sig) => sig,
-        Err(e) => {
-            println!("Failed to parse schnorr signature: {:?}", e);
-            return false;
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Why a message could not be encrypted or decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionError {
+    /// The plaintext is empty or too long for the encryption scheme.
+    MessageLength,
+    /// The payload uses a version of the encryption scheme that isn't supported.
+    UnsupportedVersion,
+    /// The payload is not valid base64 or has an invalid length.
+    InvalidPayload,
+    /// The MAC doesn't match: the payload was modified, or encrypted for someone else.
+    InvalidMac,
+    /// The decrypted message is not padded as it should be.
+    InvalidPadding,
+    /// The decrypted message is not valid UTF-8.
+    InvalidUtf8,
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionError::MessageLength => write!(f, "invalid message length"),
+            EncryptionError::UnsupportedVersion => write!(f, "unsupported encryption version"),
+            EncryptionError::InvalidPayload => write!(f, "invalid payload"),
+            EncryptionError::InvalidMac => write!(f, "invalid MAC"),
+            EncryptionError::InvalidPadding => write!(f, "invalid padding"),
+            EncryptionError::InvalidUtf8 => write!(f, "decrypted message is not valid UTF-8"),
         }
-    };
+    }
+}
 
-    // Verify the signature
-    let message = Message::from_digest_slice(&hex::decode(&event.id).unwrap()).unwrap();
+impl std::error::Error for EncryptionError {}
 
-    secp.verify_schnorr(&signature, &message, &pubkey).is_ok()
+/// Verifies that a Nostr event's id matches its content and that it is signed by its pubkey.
+pub fn verify_event(event: &Event) -> Result<(), VerifyError> {
+    if calculate_event_id(event) != event.id {
+        return Err(VerifyError::Id);
+    }
+
+    let message = Message::from_digest(event.id.0);
+    SECP256K1
+        .verify_schnorr(&event.sig.0, &message, &event.pubkey.0)
+        .map_err(|_| VerifyError::Signature)
+}
+
+/// Smallest number of events worth handing to another thread, as spawning one costs about as much as verifying a
+/// few signatures.
+const MIN_EVENTS_PER_THREAD: usize = 16;
+
+/// Verifies many events at once, spreading the work over all available cores.
+///
+/// Returns the result of [`verify_event`] for each event, in the same order.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::{generate_keypair, verify_events};
+/// use cornostr::event::EventBuilder;
+///
+/// let keypair = generate_keypair();
+/// let mut events: Vec<_> = (0..3)
+///     .map(|i| EventBuilder::text_note(format!("note {}", i)).sign(&keypair))
+///     .collect();
+/// events[1].content = "forged".to_string();
+///
+/// let results = verify_events(&events);
+/// assert!(results[0].is_ok() && results[1].is_err() && results[2].is_ok());
+/// ```
+pub fn verify_events(events: &[Event]) -> Vec<Result<(), VerifyError>> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    verify_events_with_threads(events, threads)
+}
+
+/// Like [`verify_events`], with the maximum number of threads to use.
+pub fn verify_events_with_threads(
+    events: &[Event],
+    threads: usize,
+) -> Vec<Result<(), VerifyError>> {
+    let chunk_size = events
+        .len()
+        .div_ceil(threads.max(1))
+        .max(MIN_EVENTS_PER_THREAD);
+    if events.len() <= chunk_size {
+        return events.iter().map(verify_event).collect();
+    }
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = events
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(verify_event).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("verification never panics"))
+            .collect()
+    })
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::event::calculate_event_id;
+    use crate::event::{EventBuilder, PublicKey};
+    use secp256k1::schnorr;
 
     use super::*;
 
@@ -63,10 +168,10 @@ mod tests {
         Event {
             content: "Thank you!".to_string(),
             created_at: 1725316278,
-            id: "4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6".to_string(),
+            id: "4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6".parse().unwrap(),
             kind: 1,
-            pubkey: "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453".to_string(),
-            sig: "44b4b5e4087504f7ca44bb72cb89c119e680f459739a476023a036075e93a5219dc21380fbda14af4c5008185c1fc86a08acb433fb7097eff175cc81174a345c".to_string(),
+            pubkey: "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453".parse().unwrap(),
+            sig: "44b4b5e4087504f7ca44bb72cb89c119e680f459739a476023a036075e93a5219dc21380fbda14af4c5008185c1fc86a08acb433fb7097eff175cc81174a345c".parse().unwrap(),
             tags: vec![
                 vec!["e".to_string(),"f14669da001fc23052bbfa3e4124699a85dc14b3ecb65023a86ed16a317c1cc3".to_string(),"".to_string(),"root".to_string()],
                 vec!["e".to_string(),"32928056b07792e9a92193720c67d3458351ea66fbc568cdc87be41a5faa92ce".to_string(),"wss://nos.lol".to_string(),"reply".to_string()],
@@ -80,44 +185,68 @@ mod tests {
         // Generate a new keypair
         let keypair = generate_keypair();
 
-        // Extract the XOnlyPublicKey from the Keypair
-        let (xonly_pubkey, _parity) = XOnlyPublicKey::from_keypair(&keypair);
+        // Extract the public key from the Keypair
+        let pubkey = PublicKey::from_keypair(&keypair);
 
-        let mut event = Event {
-            id: "".to_string(),
-            pubkey: hex::encode(xonly_pubkey.serialize()),
+        let id = EventId::compute(&pubkey, 1617932400, 1, &[], "Hello, Nostr!");
+        let event = Event {
+            id,
+            pubkey,
             created_at: 1617932400,
             kind: 1,
             tags: vec![],
             content: "Hello, Nostr!".to_string(),
-            sig: String::new(),
+            sig: sign_event_id(&id, &keypair),
         };
-        event.id = calculate_event_id(&event);
+        assert_eq!(event.id, calculate_event_id(&event));
 
-        event.sig = sign_event(&event, &keypair);
-        assert_eq!(event.sig.len(), 128);
-        assert!(hex::decode(&event.sig).is_ok());
+        let sig = sign_event(&event, &keypair).to_string();
+        assert_eq!(sig.len(), 128);
+        assert!(hex::decode(&sig).is_ok());
 
         // now verify the signature
-        assert!(verify_event(&event));
+        assert_eq!(verify_event(&event), Ok(()));
     }
 
     #[test]
     fn test_verify_event() {
         let event = test_event();
 
-        println!("Event: {:#?}", event);
-        assert!(verify_event(&event), "Event verification failed");
+        assert_eq!(verify_event(&event), Ok(()));
 
         // Test with invalid signature
         let mut invalid_event = event.clone();
-        invalid_event.sig = hex::encode([0u8; 64]);
-        assert!(!verify_event(&invalid_event));
+        invalid_event.sig = Signature(schnorr::Signature::from_slice(&[0u8; 64]).unwrap());
+        assert_eq!(verify_event(&invalid_event), Err(VerifyError::Signature));
 
         // Test with modified content
         let mut modified_event = event.clone();
         modified_event.content = "Modified content".to_string();
+        assert_eq!(verify_event(&modified_event), Err(VerifyError::Id));
+
+        // Test with modified content and a matching id
         modified_event.id = calculate_event_id(&modified_event);
-        assert!(!verify_event(&modified_event));
+        assert_eq!(verify_event(&modified_event), Err(VerifyError::Signature));
+    }
+
+    #[test]
+    fn test_verify_events() {
+        let keypair = generate_keypair();
+        let mut events: Vec<Event> = (0..100)
+            .map(|i| EventBuilder::text_note(format!("note {}", i)).sign(&keypair))
+            .collect();
+        events[3].content = "Modified content".to_string();
+        events[97].sig = Signature(schnorr::Signature::from_slice(&[0u8; 64]).unwrap());
+
+        let expected: Vec<_> = events.iter().map(verify_event).collect();
+        assert_eq!(expected[3], Err(VerifyError::Id));
+        assert_eq!(expected[97], Err(VerifyError::Signature));
+        assert_eq!(expected.iter().filter(|r| r.is_ok()).count(), 98);
+
+        for threads in [0, 1, 3, 8] {
+            assert_eq!(verify_events_with_threads(&events, threads), expected);
+        }
+        assert_eq!(verify_events(&events), expected);
+        assert!(verify_events(&[]).is_empty());
     }
 }
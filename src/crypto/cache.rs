@@ -0,0 +1,250 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use super::{verify_event, verify_events, VerifyError};
+use crate::event::{calculate_event_id, Event, EventId, Signature};
+
+/// Number of events a [`VerifyCache`] remembers by default.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Remembers the most recently verified events, so that copies of them arriving from other relays or connections
+/// skip the signature check.
+///
+/// Entries are keyed by event id. A lookup still recomputes the id from the full event and compares the signature, so
+/// an event that reuses a verified id with different fields or signature is never taken for the verified one. Only
+/// the Schnorr verification, which is the expensive part, is skipped.
+///
+/// Wrap it in an `Arc` to share it between a [`Client`](crate::client::Client) and a
+/// [`Relay`](crate::relay::Relay).
+#[derive(Debug)]
+pub struct VerifyCache {
+    capacity: usize,
+    inner: Mutex<Lru>,
+}
+
+/// How well a [`VerifyCache`] is doing, to size it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Events found in the cache.
+    pub hits: u64,
+    /// Events that had to be verified.
+    pub misses: u64,
+    /// Events currently in the cache.
+    pub len: usize,
+    /// Maximum number of events in the cache.
+    pub capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    /// Signature and last use of each cached event.
+    entries: HashMap<EventId, (Signature, u64)>,
+    /// Cached event ids by last use, least recently used first.
+    by_use: BTreeMap<u64, EventId>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Lru {
+    /// Returns true if the event is cached, marking it as just used.
+    fn get(&mut self, id: &EventId, sig: &Signature) -> bool {
+        self.tick += 1;
+        let Some((cached, last_use)) = self.entries.get_mut(id) else {
+            return false;
+        };
+        if cached != sig {
+            return false;
+        }
+        self.by_use.remove(last_use);
+        self.by_use.insert(self.tick, *id);
+        *last_use = self.tick;
+        true
+    }
+
+    fn insert(&mut self, id: EventId, sig: Signature, capacity: usize) {
+        self.tick += 1;
+        if let Some((_, last_use)) = self.entries.insert(id, (sig, self.tick)) {
+            self.by_use.remove(&last_use);
+        }
+        self.by_use.insert(self.tick, id);
+        while self.entries.len() > capacity {
+            let (_, oldest) = self.by_use.pop_first().expect("every entry has a use");
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+impl Default for VerifyCache {
+    fn default() -> Self {
+        VerifyCache::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl VerifyCache {
+    /// Creates a cache that remembers at most `capacity` events.
+    pub fn new(capacity: usize) -> Self {
+        VerifyCache {
+            capacity,
+            inner: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Like [`verify_event`], skipping the signature check if the event was already verified.
+    pub fn verify(&self, event: &Event) -> Result<(), VerifyError> {
+        if self.lookup(event)? {
+            return Ok(());
+        }
+        verify_event(event)?;
+        self.remember(event);
+        Ok(())
+    }
+
+    /// Like [`verify_events`], only verifying the events that were not already verified.
+    pub fn verify_events(&self, events: &[Event]) -> Vec<Result<(), VerifyError>> {
+        let mut results: Vec<Option<Result<(), VerifyError>>> = events
+            .iter()
+            .map(|event| match self.lookup(event) {
+                Ok(true) => Some(Ok(())),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect();
+
+        let missed: Vec<Event> = events
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.is_none())
+            .map(|(event, _)| event.clone())
+            .collect();
+        let mut verified = verify_events(&missed).into_iter();
+        for (event, result) in events.iter().zip(&mut results) {
+            if result.is_none() {
+                let outcome = verified.next().expect("one result per missed event");
+                if outcome.is_ok() {
+                    self.remember(event);
+                }
+                *result = Some(outcome);
+            }
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    /// Returns the hit and miss counts and the size of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.inner.lock().unwrap();
+        CacheStats {
+            hits: lru.hits,
+            misses: lru.misses,
+            len: lru.entries.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    /// Returns whether the event is cached, counting the hit or miss, or the id error if the id doesn't match the
+    /// event.
+    fn lookup(&self, event: &Event) -> Result<bool, VerifyError> {
+        if calculate_event_id(event) != event.id {
+            return Err(VerifyError::Id);
+        }
+        let mut lru = self.inner.lock().unwrap();
+        let hit = lru.get(&event.id, &event.sig);
+        if hit {
+            lru.hits += 1;
+        } else {
+            lru.misses += 1;
+        }
+        Ok(hit)
+    }
+
+    fn remember(&self, event: &Event) {
+        if self.capacity > 0 {
+            let mut lru = self.inner.lock().unwrap();
+            lru.insert(event.id, event.sig, self.capacity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_keypair;
+    use crate::event::EventBuilder;
+    use secp256k1::schnorr;
+
+    fn notes(count: usize) -> Vec<Event> {
+        let keypair = generate_keypair();
+        (0..count)
+            .map(|i| EventBuilder::text_note(format!("note {}", i)).sign(&keypair))
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_counts_hits_and_misses() {
+        let cache = VerifyCache::new(10);
+        let event = &notes(1)[0];
+
+        assert_eq!(cache.verify(event), Ok(()));
+        assert_eq!(cache.verify(event), Ok(()));
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                len: 1,
+                capacity: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_verified_id_with_other_fields_is_not_a_hit() {
+        let cache = VerifyCache::new(10);
+        let event = notes(1).remove(0);
+        assert_eq!(cache.verify(&event), Ok(()));
+
+        let mut modified = event.clone();
+        modified.content = "Modified content".to_string();
+        assert_eq!(cache.verify(&modified), Err(VerifyError::Id));
+
+        let mut forged = event.clone();
+        forged.sig = Signature(schnorr::Signature::from_slice(&[0u8; 64]).unwrap());
+        assert_eq!(cache.verify(&forged), Err(VerifyError::Signature));
+
+        // Failures are never cached
+        assert_eq!(cache.verify(&forged), Err(VerifyError::Signature));
+        assert_eq!(cache.stats().hits, 0);
+        assert_eq!(cache.stats().len, 1);
+    }
+
+    #[test]
+    fn test_least_recently_used_event_is_evicted() {
+        let cache = VerifyCache::new(2);
+        let events = notes(3);
+
+        cache.verify(&events[0]).unwrap();
+        cache.verify(&events[1]).unwrap();
+        cache.verify(&events[0]).unwrap();
+        cache.verify(&events[2]).unwrap();
+        assert_eq!(cache.stats().len, 2);
+
+        // events[1] was evicted, events[0] was not as it had just been used
+        cache.verify(&events[0]).unwrap();
+        assert_eq!(cache.stats().hits, 2);
+        cache.verify(&events[1]).unwrap();
+        assert_eq!(cache.stats().misses, 4);
+    }
+
+    #[test]
+    fn test_verify_events_skips_cached_events() {
+        let cache = VerifyCache::new(100);
+        let mut events = notes(50);
+        events[7].content = "Modified content".to_string();
+        let expected = verify_events(&events);
+
+        assert_eq!(cache.verify_events(&events[..20]), expected[..20]);
+        assert_eq!(cache.verify_events(&events), expected);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (19, 49, 49));
+    }
+}
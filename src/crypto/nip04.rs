@@ -0,0 +1,168 @@
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use secp256k1::{ecdh, Keypair, Parity};
+
+use super::EncryptionError;
+use crate::event::PublicKey;
+
+/*
+## NIP-04: Encrypted Direct Message (legacy)
+
+Deprecated in favor of NIP-17 private messages, which use NIP-44 encryption: kind 4 events leak who talks to whom
+and when, and their encryption is not authenticated. Only use this to talk to clients that don't support NIP-17.
+
+The content is AES-256-CBC encrypted with the unhashed x coordinate of the ECDH point of the sender's secret key and
+the recipient's pubkey, and a random IV:
+
+```text
+<base64 ciphertext>?iv=<base64 iv>
+```
+*/
+
+type Encryptor = cbc::Encryptor<aes::Aes256>;
+type Decryptor = cbc::Decryptor<aes::Aes256>;
+
+const IV_SEPARATOR: &str = "?iv=";
+
+/// Encrypts a direct message from a keypair to a peer with a random IV, in the `content?iv=...` format.
+///
+/// This is the legacy scheme, prefer [`nip44`](super::nip44) when the peer supports it.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::{generate_keypair, nip04};
+/// use cornostr::event::PublicKey;
+///
+/// let alice = generate_keypair();
+/// let bob = generate_keypair();
+///
+/// let content = nip04::encrypt(&alice, &PublicKey::from_keypair(&bob), "Hello, Bob!");
+/// let plaintext = nip04::decrypt(&bob, &PublicKey::from_keypair(&alice), &content).unwrap();
+/// assert_eq!(plaintext, "Hello, Bob!");
+/// ```
+pub fn encrypt(keypair: &Keypair, peer: &PublicKey, plaintext: &str) -> String {
+    let mut iv = [0u8; 16];
+    OsRng.fill_bytes(&mut iv);
+    encrypt_with_iv(keypair, peer, plaintext, &iv)
+}
+
+/// Decrypts the content of a direct message a peer sent to the keypair, or that the keypair sent to the peer.
+///
+/// This is the legacy scheme: nothing authenticates the ciphertext, so a modified message may decrypt to garbage
+/// instead of failing.
+pub fn decrypt(
+    keypair: &Keypair,
+    peer: &PublicKey,
+    content: &str,
+) -> Result<String, EncryptionError> {
+    let (ciphertext, iv) = content
+        .split_once(IV_SEPARATOR)
+        .ok_or(EncryptionError::InvalidPayload)?;
+    let ciphertext = BASE64
+        .decode(ciphertext)
+        .map_err(|_| EncryptionError::InvalidPayload)?;
+    let iv: [u8; 16] = BASE64
+        .decode(iv)
+        .ok()
+        .and_then(|iv| iv.try_into().ok())
+        .ok_or(EncryptionError::InvalidPayload)?;
+
+    let plaintext = Decryptor::new(&shared_key(keypair, peer).into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+        .map_err(|_| EncryptionError::InvalidPadding)?;
+    String::from_utf8(plaintext).map_err(|_| EncryptionError::InvalidUtf8)
+}
+
+fn encrypt_with_iv(keypair: &Keypair, peer: &PublicKey, plaintext: &str, iv: &[u8; 16]) -> String {
+    let ciphertext = Encryptor::new(&shared_key(keypair, peer).into(), iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+    format!(
+        "{}{}{}",
+        BASE64.encode(ciphertext),
+        IV_SEPARATOR,
+        BASE64.encode(iv)
+    )
+}
+
+/// The unhashed x coordinate of the ECDH point, unlike the hashed secret of `secp256k1::ecdh::SharedSecret`.
+fn shared_key(keypair: &Keypair, peer: &PublicKey) -> [u8; 32] {
+    let point = ecdh::shared_secret_point(&peer.0.public_key(Parity::Even), &keypair.secret_key());
+    point[..32].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_keypair;
+    use secp256k1::{SecretKey, SECP256K1};
+
+    fn keypair(secret_key: &str) -> Keypair {
+        Keypair::from_secret_key(SECP256K1, &secret_key.parse::<SecretKey>().unwrap())
+    }
+
+    #[test]
+    fn test_encrypt_with_iv() {
+        // Computed with an independent implementation of ECDH and AES-256-CBC
+        let sender = keypair("0000000000000000000000000000000000000000000000000000000000000001");
+        let recipient = keypair("0000000000000000000000000000000000000000000000000000000000000002");
+        let iv: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let content = "RppIjHjjIGRhNed9Ysoev83ii2JYpemWm0EKJObaKBI=?iv=AAECAwQFBgcICQoLDA0ODw==";
+
+        assert_eq!(
+            hex::encode(shared_key(&sender, &PublicKey::from_keypair(&recipient))),
+            "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5"
+        );
+        let recipient_pubkey = PublicKey::from_keypair(&recipient);
+        assert_eq!(
+            encrypt_with_iv(&sender, &recipient_pubkey, "Hello, Nostr! 🤙", &iv),
+            content
+        );
+        let sender_pubkey = PublicKey::from_keypair(&sender);
+        assert_eq!(
+            decrypt(&recipient, &sender_pubkey, content).unwrap(),
+            "Hello, Nostr! 🤙"
+        );
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let alice = generate_keypair();
+        let bob = generate_keypair();
+        let (alice_pubkey, bob_pubkey) = (
+            PublicKey::from_keypair(&alice),
+            PublicKey::from_keypair(&bob),
+        );
+
+        for plaintext in ["", "x", "0123456789abcdef", &"é".repeat(1000)] {
+            let content = encrypt(&alice, &bob_pubkey, plaintext);
+            assert_eq!(decrypt(&bob, &alice_pubkey, &content).unwrap(), plaintext);
+            assert_eq!(decrypt(&alice, &bob_pubkey, &content).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_invalid_content() {
+        let alice = generate_keypair();
+        let bob_pubkey = PublicKey::from_keypair(&generate_keypair());
+        let content = encrypt(&alice, &bob_pubkey, "Hello, Nostr!");
+        let (ciphertext, iv) = content.split_once(IV_SEPARATOR).unwrap();
+
+        for (content, error) in [
+            (ciphertext.to_string(), EncryptionError::InvalidPayload),
+            (
+                format!("{}?iv=AAEC", ciphertext),
+                EncryptionError::InvalidPayload,
+            ),
+            (format!("!{}", content), EncryptionError::InvalidPayload),
+            // Not a whole number of blocks
+            (format!("AAAA?iv={}", iv), EncryptionError::InvalidPadding),
+        ] {
+            assert_eq!(decrypt(&alice, &bob_pubkey, &content), Err(error));
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use hkdf::Hkdf;
+use hmac::{Hmac, KeyInit, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use secp256k1::{ecdh, Keypair, Parity};
+use sha2::Sha256;
+
+use super::EncryptionError;
+use crate::event::PublicKey;
+
+/*
+## NIP-44: Encrypted Payloads (Versioned)
+
+Version 2 derives a conversation key shared by two pubkeys, then encrypts each message with its own keys:
+
+- The conversation key is `HKDF-extract(salt = "nip44-v2", ikm = x)`, where `x` is the unhashed x coordinate of the
+  ECDH point of the sender's secret key and the recipient's pubkey.
+- A random 32-byte nonce is expanded with `HKDF-expand(conversation_key, info = nonce, 76)` into a ChaCha20 key
+  (32 bytes), ChaCha20 nonce (12 bytes) and HMAC-SHA256 key (32 bytes).
+- The plaintext is prefixed with its length as a big-endian u16 and zero-padded to hide its exact length.
+- The payload is `base64(version || nonce || ciphertext || mac)`, with `version` 2 and
+  `mac = HMAC-SHA256(hmac_key, nonce || ciphertext)`.
+*/
+
+/// The only version of the payload format that is supported.
+const VERSION: u8 = 2;
+
+/// Longest plaintext that can be encrypted, in bytes.
+pub const MAX_PLAINTEXT_LEN: usize = 65535;
+
+const SALT: &[u8] = b"nip44-v2";
+
+/// Shortest and longest valid payloads, in base64 characters and decoded bytes.
+const MIN_PAYLOAD_LEN: usize = 132;
+const MAX_PAYLOAD_LEN: usize = 87472;
+const MIN_DECODED_LEN: usize = 99;
+const MAX_DECODED_LEN: usize = 65603;
+
+/// The key two pubkeys share to encrypt messages to each other, whichever of them is the sender.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ConversationKey(pub [u8; 32]);
+
+impl ConversationKey {
+    /// Derives the conversation key of a keypair and a peer's pubkey.
+    pub fn derive(keypair: &Keypair, peer: &PublicKey) -> ConversationKey {
+        let point =
+            ecdh::shared_secret_point(&peer.0.public_key(Parity::Even), &keypair.secret_key());
+        let (prk, _) = Hkdf::<Sha256>::extract(Some(SALT), &point[..32]);
+        ConversationKey(prk.into())
+    }
+
+    /// Encrypts a message with a random nonce.
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, EncryptionError> {
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+        self.encrypt_with_nonce(plaintext, &nonce)
+    }
+
+    /// Encrypts a message with the given nonce, which must never be reused with the same conversation key.
+    pub fn encrypt_with_nonce(
+        &self,
+        plaintext: &str,
+        nonce: &[u8; 32],
+    ) -> Result<String, EncryptionError> {
+        let keys = MessageKeys::derive(self, nonce);
+        let mut ciphertext = pad(plaintext.as_bytes())?;
+        keys.apply_keystream(&mut ciphertext);
+        let mac = keys.mac(nonce, &ciphertext).finalize().into_bytes();
+
+        let mut payload = Vec::with_capacity(1 + nonce.len() + ciphertext.len() + mac.len());
+        payload.push(VERSION);
+        payload.extend_from_slice(nonce);
+        payload.extend_from_slice(&ciphertext);
+        payload.extend_from_slice(&mac);
+        Ok(BASE64.encode(payload))
+    }
+
+    /// Decrypts a payload, checking its MAC before anything else.
+    pub fn decrypt(&self, payload: &str) -> Result<String, EncryptionError> {
+        // A leading `#` marks a payload that isn't base64 encoded, used by future versions
+        if payload.starts_with('#') {
+            return Err(EncryptionError::UnsupportedVersion);
+        }
+        if !(MIN_PAYLOAD_LEN..=MAX_PAYLOAD_LEN).contains(&payload.len()) {
+            return Err(EncryptionError::InvalidPayload);
+        }
+        let decoded = BASE64
+            .decode(payload)
+            .map_err(|_| EncryptionError::InvalidPayload)?;
+        if !(MIN_DECODED_LEN..=MAX_DECODED_LEN).contains(&decoded.len()) {
+            return Err(EncryptionError::InvalidPayload);
+        }
+        if decoded[0] != VERSION {
+            return Err(EncryptionError::UnsupportedVersion);
+        }
+
+        let nonce: &[u8; 32] = decoded[1..33].try_into().expect("length checked above");
+        let (ciphertext, mac) = decoded[33..].split_at(decoded.len() - 33 - 32);
+        let keys = MessageKeys::derive(self, nonce);
+        keys.mac(nonce, ciphertext)
+            .verify_slice(mac)
+            .map_err(|_| EncryptionError::InvalidMac)?;
+
+        let mut padded = ciphertext.to_vec();
+        keys.apply_keystream(&mut padded);
+        let plaintext = unpad(&padded)?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| EncryptionError::InvalidUtf8)
+    }
+}
+
+impl std::fmt::Debug for ConversationKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the key itself
+        f.write_str("ConversationKey(..)")
+    }
+}
+
+/// Encrypts a message from a keypair to a peer with a random nonce.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::{generate_keypair, nip44};
+/// use cornostr::event::PublicKey;
+///
+/// let alice = generate_keypair();
+/// let bob = generate_keypair();
+///
+/// let payload = nip44::encrypt(&alice, &PublicKey::from_keypair(&bob), "Hello, Bob!").unwrap();
+/// let plaintext = nip44::decrypt(&bob, &PublicKey::from_keypair(&alice), &payload).unwrap();
+/// assert_eq!(plaintext, "Hello, Bob!");
+/// ```
+pub fn encrypt(
+    keypair: &Keypair,
+    peer: &PublicKey,
+    plaintext: &str,
+) -> Result<String, EncryptionError> {
+    ConversationKey::derive(keypair, peer).encrypt(plaintext)
+}
+
+/// Decrypts a payload a peer sent to the keypair, or that the keypair sent to the peer.
+pub fn decrypt(
+    keypair: &Keypair,
+    peer: &PublicKey,
+    payload: &str,
+) -> Result<String, EncryptionError> {
+    ConversationKey::derive(keypair, peer).decrypt(payload)
+}
+
+/// The keys of a single message, derived from the conversation key and the message's nonce.
+struct MessageKeys {
+    chacha_key: [u8; 32],
+    chacha_nonce: [u8; 12],
+    hmac_key: [u8; 32],
+}
+
+impl MessageKeys {
+    fn derive(conversation_key: &ConversationKey, nonce: &[u8; 32]) -> MessageKeys {
+        let hkdf =
+            Hkdf::<Sha256>::from_prk(&conversation_key.0).expect("32 bytes is a valid PRK length");
+        let mut keys = [0u8; 76];
+        hkdf.expand(nonce, &mut keys)
+            .expect("76 bytes is a valid length for HKDF-SHA256");
+        MessageKeys {
+            chacha_key: keys[..32].try_into().unwrap(),
+            chacha_nonce: keys[32..44].try_into().unwrap(),
+            hmac_key: keys[44..].try_into().unwrap(),
+        }
+    }
+
+    fn apply_keystream(&self, buffer: &mut [u8]) {
+        ChaCha20::new(&self.chacha_key.into(), &self.chacha_nonce.into()).apply_keystream(buffer);
+    }
+
+    /// The MAC of the ciphertext, with the nonce as associated data.
+    fn mac(&self, nonce: &[u8; 32], ciphertext: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.hmac_key).expect("HMAC takes keys of any length");
+        mac.update(nonce);
+        mac.update(ciphertext);
+        mac
+    }
+}
+
+/// Returns the length a plaintext of `len` bytes is padded to: 32 bytes at least, then the next multiple of an
+/// eighth of the next power of two, in chunks of 32 bytes up to 256.
+pub fn padded_len(len: usize) -> usize {
+    if len <= 32 {
+        return 32;
+    }
+    let next_power = len.next_power_of_two();
+    let chunk = if next_power <= 256 {
+        32
+    } else {
+        next_power / 8
+    };
+    chunk * ((len - 1) / chunk + 1)
+}
+
+/// Prefixes the plaintext with its length as a big-endian u16 and pads it with zeros.
+fn pad(plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if plaintext.is_empty() || plaintext.len() > MAX_PLAINTEXT_LEN {
+        return Err(EncryptionError::MessageLength);
+    }
+    let mut padded = Vec::with_capacity(2 + padded_len(plaintext.len()));
+    padded.extend_from_slice(&(plaintext.len() as u16).to_be_bytes());
+    padded.extend_from_slice(plaintext);
+    padded.resize(2 + padded_len(plaintext.len()), 0);
+    Ok(padded)
+}
+
+/// Returns the plaintext of a padded message, checking that it was padded as [`pad`] does.
+fn unpad(padded: &[u8]) -> Result<&[u8], EncryptionError> {
+    let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if len == 0 || padded.len() != 2 + padded_len(len) {
+        return Err(EncryptionError::InvalidPadding);
+    }
+    Ok(&padded[2..2 + len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_keypair;
+    use secp256k1::{SecretKey, SECP256K1};
+
+    fn keypair(secret_key: &str) -> Keypair {
+        Keypair::from_secret_key(SECP256K1, &secret_key.parse::<SecretKey>().unwrap())
+    }
+
+    fn conversation_key(sec1: &str, sec2: &str) -> ConversationKey {
+        ConversationKey::derive(&keypair(sec1), &PublicKey::from_keypair(&keypair(sec2)))
+    }
+
+    fn nonce(hex: &str) -> [u8; 32] {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_conversation_key_vectors() {
+        // From `valid.get_conversation_key` of the NIP-44 test vectors
+        let key = ConversationKey::derive(
+            &keypair("315e59ff51cb9209768cf7da80791ddcaae56ac9775eb25b6dee1234bc5d2268"),
+            &"c2f9d9948dc8c7c38321e4b85c8558872eafa0641cd269db76848a6073e69133"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            hex::encode(key.0),
+            "3dfef0ce2a4d80a25e7a328accf73448ef67096f65f79588e358d9a0eb9013f1"
+        );
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_vectors() {
+        // From `valid.encrypt_decrypt` of the NIP-44 test vectors
+        let sec1 = "0000000000000000000000000000000000000000000000000000000000000001";
+        let sec2 = "0000000000000000000000000000000000000000000000000000000000000002";
+        for (sec1, sec2, conversation, nonce_hex, plaintext, payload) in [
+            (
+                sec1,
+                sec2,
+                "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "a",
+                "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb",
+            ),
+            (
+                sec2,
+                sec1,
+                "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d",
+                "f00000000000000000000000000000f00000000000000000000000000000000f",
+                "🍕🫃",
+                "AvAAAAAAAAAAAAAAAAAAAPAAAAAAAAAAAAAAAAAAAAAPSKSK6is9ngkX2+cSq85Th16oRTISAOfhStnixqZziKMDvB0QQzgFZdjLTPicCJaV8nDITO+QfaQ61+KbWQIOO2Yj",
+            ),
+            (
+                "5c0c523f52a5b6fad39ed2403092df8cebc36318b39383bca6c00808626fab3a",
+                "4b22aa260e4acb7021e32f38a6cdf4b673c6a277755bfce287e370c924dc936d",
+                "3e2b52a63be47d34fe0a80e34e73d436d6963bc8f39827f327057a9986c20a45",
+                "b635236c42db20f021bb8d1cdff5ca75dd1a0cc72ea742ad750f33010b24f73b",
+                "表ポあA鷗ŒéＢ逍Üßªąñ丂㐀𠀀",
+                "ArY1I2xC2yDwIbuNHN/1ynXdGgzHLqdCrXUPMwELJPc7s7JqlCMJBAIIjfkpHReBPXeoMCyuClwgbT419jUWU1PwaNl4FEQYKCDKVJz+97Mp3K+Q2YGa77B6gpxB/lr1QgoqpDf7wDVrDmOqGoiPjWDqy8KzLueKDcm9BVP8xeTJIxs=",
+            ),
+        ] {
+            let key = conversation_key(sec1, sec2);
+            assert_eq!(hex::encode(key.0), conversation);
+            assert_eq!(conversation_key(sec2, sec1), key);
+            assert_eq!(
+                key.encrypt_with_nonce(plaintext, &nonce(nonce_hex)).unwrap(),
+                payload
+            );
+            assert_eq!(key.decrypt(payload).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn test_padded_len_vectors() {
+        // From `valid.calc_padded_len` of the NIP-44 test vectors
+        for (len, padded) in [
+            (16, 32),
+            (32, 32),
+            (33, 64),
+            (37, 64),
+            (45, 64),
+            (49, 64),
+            (64, 64),
+            (65, 96),
+            (100, 128),
+            (111, 128),
+            (200, 224),
+            (250, 256),
+            (320, 320),
+            (383, 384),
+            (384, 384),
+            (400, 448),
+            (500, 512),
+            (512, 512),
+            (515, 640),
+            (700, 768),
+            (800, 896),
+            (900, 1024),
+            (1020, 1024),
+            (65536, 65536),
+        ] {
+            assert_eq!(padded_len(len), padded, "padded length of {}", len);
+        }
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let alice = generate_keypair();
+        let bob = generate_keypair();
+        let (alice_pubkey, bob_pubkey) = (
+            PublicKey::from_keypair(&alice),
+            PublicKey::from_keypair(&bob),
+        );
+
+        for plaintext in [
+            "x".to_string(),
+            "é".repeat(1000),
+            "a".repeat(MAX_PLAINTEXT_LEN),
+        ] {
+            let payload = encrypt(&alice, &bob_pubkey, &plaintext).unwrap();
+            assert_eq!(decrypt(&bob, &alice_pubkey, &payload).unwrap(), plaintext);
+            assert_eq!(decrypt(&alice, &bob_pubkey, &payload).unwrap(), plaintext);
+            // A random nonce is used each time
+            assert_ne!(encrypt(&alice, &bob_pubkey, &plaintext).unwrap(), payload);
+        }
+
+        assert_eq!(
+            encrypt(&alice, &bob_pubkey, ""),
+            Err(EncryptionError::MessageLength)
+        );
+        assert_eq!(
+            encrypt(&alice, &bob_pubkey, &"a".repeat(MAX_PLAINTEXT_LEN + 1)),
+            Err(EncryptionError::MessageLength)
+        );
+    }
+
+    #[test]
+    fn test_decrypt_invalid_payloads() {
+        let key = conversation_key(
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+        );
+        let payload = key.encrypt("Hello, Nostr!").unwrap();
+        let mut decoded = BASE64.decode(&payload).unwrap();
+
+        assert_eq!(
+            key.decrypt(&format!("#{}", &payload[1..])),
+            Err(EncryptionError::UnsupportedVersion)
+        );
+        assert_eq!(
+            key.decrypt(&payload[..MIN_PAYLOAD_LEN - 1]),
+            Err(EncryptionError::InvalidPayload)
+        );
+        assert_eq!(
+            key.decrypt(&format!("{}!", &payload[..payload.len() - 1])),
+            Err(EncryptionError::InvalidPayload)
+        );
+
+        let other_key = conversation_key(
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000003",
+        );
+        assert_eq!(
+            other_key.decrypt(&payload),
+            Err(EncryptionError::InvalidMac)
+        );
+
+        decoded[40] ^= 1;
+        assert_eq!(
+            key.decrypt(&BASE64.encode(&decoded)),
+            Err(EncryptionError::InvalidMac)
+        );
+
+        decoded[40] ^= 1;
+        decoded[0] = 1;
+        assert_eq!(
+            key.decrypt(&BASE64.encode(&decoded)),
+            Err(EncryptionError::UnsupportedVersion)
+        );
+    }
+
+    #[test]
+    fn test_decrypt_invalid_padding() {
+        let key = conversation_key(
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+        );
+        let nonce = [7u8; 32];
+        let keys = MessageKeys::derive(&key, &nonce);
+
+        // A 1-byte plaintext claiming to be 40 bytes long, which would need 64 bytes of padding instead of 32
+        let mut padded = pad(b"a").unwrap();
+        padded[..2].copy_from_slice(&40u16.to_be_bytes());
+        keys.apply_keystream(&mut padded);
+        let mac = keys.mac(&nonce, &padded).finalize().into_bytes();
+        let payload = BASE64.encode([&[VERSION], &nonce[..], &padded, &mac[..]].concat());
+
+        assert_eq!(key.decrypt(&payload), Err(EncryptionError::InvalidPadding));
+    }
+
+    #[test]
+    fn test_decrypt_invalid_vectors() {
+        // From `invalid.decrypt` of the NIP-44 test vectors
+        for (conversation, payload, error) in [
+            (
+                "ca2527a037347b91bea0c8a30fc8d9600ffd81ec00038671e3a0f0cb0fc9f642",
+                "#Atqupco0WyaOW2IGDKcshwxI9xO8HgD/P8Ddt46CbxDbrhdG8VmJZE0UICD06CUvEvdnr1cp1fiMtlM/GrE92xAc1EwsVCQEgWEu2gsHUVf4JAa3TpgkmFc3TWsax0v6n/Wq",
+                EncryptionError::UnsupportedVersion,
+            ),
+            (
+                "36f04e558af246352dcf73b692fbd3646a2207bd8abd4b1cd26b234db84d9481",
+                "AK1AjUvoYW3IS7C/BGRUoqEC7ayTfDUgnEPNeWTF/reBZFaha6EAIRueE9D1B1RuoiuFScC0Q94yjIuxZD3JStQtE8JMNacWFs9rlYP+ZydtHhRucp+lxfdvFlaGV/sQlqZz",
+                EncryptionError::UnsupportedVersion,
+            ),
+            (
+                "ca2527a037347b91bea0c8a30fc8d9600ffd81ec00038671e3a0f0cb0fc9f642",
+                "Atфupco0WyaOW2IGDKcshwxI9xO8HgD/P8Ddt46CbxDbrhdG8VmJZE0UICD06CUvEvdnr1cp1fiMtlM/GrE92xAc1EwsVCQEgWEu2gsHUVf4JAa3TpgkmFc3TWsax0v6n/Wq",
+                EncryptionError::InvalidPayload,
+            ),
+            (
+                "cff7bd6a3e29a450fd27f6c125d5edeb0987c475fd1e8d97591e0d4d8a89763c",
+                "Agn/l3ULCEAS4V7LhGFM6IGA17jsDUaFCKhrbXDANholyySBfeh+EN8wNB9gaLlg4j6wdBYh+3oK+mnxWu3NKRbSvQAGGCp1J7T9y/ZkdwwuZY6tk9jdydS0Lwcs2OqFCqyL",
+                EncryptionError::InvalidMac,
+            ),
+            (
+                "cfcc9cf682dfb00b11357f65bdc45e29156b69db424d20b3596919074f5bf957",
+                "AmWxSwuUmqp9UsQX63U7OQ6K1thLI69L7G2b+j4DoIr0oRWQ8avl4OLqWZiTJ10vIgKrNqjoaX+fNhE9RqmR5g0f6BtUg1ijFMz71MO1D4lQLQfW7+UHva8PGYgQ1QpHlKgR",
+                EncryptionError::InvalidMac,
+            ),
+            (
+                "5254827d29177622d40a7b67cad014fe7137700c3c523903ebbe3e1b74d40214",
+                "Anq2XbuLvCuONcr7V0UxTh8FAyWoZNEdBHXvdbNmDZHB573MI7R7rrTYftpqmvUpahmBC2sngmI14/L0HjOZ7lWGJlzdh6luiOnGPc46cGxf08MRC4CIuxx3i2Lm0KqgJ7vA",
+                EncryptionError::InvalidPadding,
+            ),
+            (
+                "fea39aca9aa8340c3a78ae1f0902aa7e726946e4efcd7783379df8096029c496",
+                "An1Cg+O1TIhdav7ogfSOYvCj9dep4ctxzKtZSniCw5MwRrrPJFyAQYZh5VpjC2QYzny5LIQ9v9lhqmZR4WBYRNJ0ognHVNMwiFV1SHpvUFT8HHZN/m/QarflbvDHAtO6pY16",
+                EncryptionError::InvalidPadding,
+            ),
+        ] {
+            let key = ConversationKey(hex::decode(conversation).unwrap().try_into().unwrap());
+            assert_eq!(key.decrypt(payload), Err(error), "payload {}", payload);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+
+use tokio_tungstenite::tungstenite;
+
+use crate::crypto::{EncryptionError, VerifyError};
+
+/// Errors returned by the client and relay.
+#[derive(Debug)]
+pub enum Error {
+    /// The WebSocket connection failed or was closed. Boxed since it's much larger than the other variants.
+    Transport(Box<tungstenite::Error>),
+    /// A socket could not be bound or accepted.
+    Io(std::io::Error),
+    /// The other side sent a message that doesn't follow the protocol.
+    Protocol(String),
+    /// A message or event could not be serialized or parsed.
+    Serialization(serde_json::Error),
+    /// An event failed verification.
+    Signature(VerifyError),
+    /// A key is invalid.
+    Key(secp256k1::Error),
+    /// A message could not be encrypted or decrypted.
+    Encryption(EncryptionError),
+    /// An operation needed the client's keypair but none is set.
+    MissingKeypair,
+    /// A relay refused an event, with the reason from its `OK` message.
+    RelayRejected { relay: String, reason: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Protocol(message) => write!(f, "protocol error: {}", message),
+            Error::Serialization(e) => write!(f, "serialization error: {}", e),
+            Error::Signature(e) => write!(f, "invalid event: {}", e),
+            Error::Key(e) => write!(f, "invalid key: {}", e),
+            Error::Encryption(e) => write!(f, "encryption error: {}", e),
+            Error::MissingKeypair => write!(f, "no keypair set"),
+            Error::RelayRejected { relay, reason } => {
+                write!(f, "{} rejected the event: {}", relay, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e.as_ref()),
+            Error::Io(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            Error::Signature(e) => Some(e),
+            Error::Key(e) => Some(e),
+            Error::Encryption(e) => Some(e),
+            Error::Protocol(_) | Error::MissingKeypair | Error::RelayRejected { .. } => None,
+        }
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::Transport(Box::new(e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
+
+impl From<VerifyError> for Error {
+    fn from(e: VerifyError) -> Self {
+        Error::Signature(e)
+    }
+}
+
+impl From<secp256k1::Error> for Error {
+    fn from(e: secp256k1::Error) -> Self {
+        Error::Key(e)
+    }
+}
+
+impl From<EncryptionError> for Error {
+    fn from(e: EncryptionError) -> Self {
+        Error::Encryption(e)
+    }
+}
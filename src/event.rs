@@ -1,5 +1,17 @@
-use serde::{Deserialize, Serialize};
+use secp256k1::{schnorr, Keypair, XOnlyPublicKey};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::crypto::sign_event_id;
+
+mod tag;
+
+pub use tag::{Coordinate, Marker, Tag};
 
 /*
 ## Events and signatures
@@ -28,12 +40,12 @@ Each user has a keypair. Signatures, public key, and encodings are done accordin
 ///   "sig": <64-bytes lowercase hex of the signature of the sha256 hash of the serialized event data, which is the same as the "id" field>
 /// }
 /// ```
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Event {
     /// 32-bytes lowercase hex-encoded sha256 of the serialized event data
-    pub id: String,
+    pub id: EventId,
     /// 32-bytes lowercase hex-encoded public key of the event creator
-    pub pubkey: String,
+    pub pubkey: PublicKey,
     /// unix timestamp in seconds
     pub created_at: u64,
     /// integer between 0 and 65535
@@ -43,7 +55,500 @@ pub struct Event {
     /// Arbitrary string.
     pub content: String,
     /// 64-bytes lowercase hex of the signature of the sha256 hash of the serialized event data, which is the same as the "id" field
-    pub sig: String,
+    pub sig: Signature,
+}
+
+impl Event {
+    /// Returns the value of the first `d` tag, which identifies an addressable event among the events of the same
+    /// pubkey and kind.
+    pub fn identifier(&self) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().is_some_and(|name| name == "d"))
+            .map(|tag| tag.get(1).map_or("", String::as_str))
+    }
+
+    /// Returns true if this event is a newer version of `other` that relays and clients should keep instead.
+    ///
+    /// Only replaceable events with the same pubkey and kind, or addressable events with the same pubkey, kind and
+    /// `d` tag, replace each other. In case of the same timestamp the event with the lowest id wins.
+    pub fn replaces(&self, other: &Event) -> bool {
+        let kind = Kind(self.kind);
+        let same_address = self.pubkey == other.pubkey
+            && self.kind == other.kind
+            && (kind.is_replaceable()
+                || (kind.is_addressable()
+                    && self.identifier().unwrap_or("") == other.identifier().unwrap_or("")));
+        same_address
+            && (self.created_at > other.created_at
+                || (self.created_at == other.created_at && self.id < other.id))
+    }
+
+    /// Returns the typed tags of the event.
+    pub fn parsed_tags(&self) -> impl Iterator<Item = Tag> + '_ {
+        self.tags.iter().map(|tag| Tag::parse(tag))
+    }
+
+    /// Returns the id of the root of the thread this event replies to, following NIP-10.
+    ///
+    /// Uses the `e` tag marked `root`, or for events without markers the first `e` tag.
+    pub fn root_id(&self) -> Option<EventId> {
+        let references = self.event_references();
+        if references.iter().any(|(_, marker)| marker.is_some()) {
+            return references
+                .into_iter()
+                .find(|(_, marker)| *marker == Some(Marker::Root))
+                .map(|(id, _)| id);
+        }
+        references.first().map(|(id, _)| *id)
+    }
+
+    /// Returns the id of the event this event directly replies to, following NIP-10.
+    ///
+    /// Uses the `e` tag marked `reply`, then the one marked `root` for direct replies to the root, or for events
+    /// without markers the last `e` tag.
+    pub fn reply_to(&self) -> Option<EventId> {
+        let references = self.event_references();
+        if references.iter().any(|(_, marker)| marker.is_some()) {
+            let marked = |wanted| {
+                references
+                    .iter()
+                    .find(|(_, marker)| *marker == Some(wanted))
+                    .map(|(id, _)| *id)
+            };
+            return marked(Marker::Reply).or_else(|| marked(Marker::Root));
+        }
+        references.last().map(|(id, _)| *id)
+    }
+
+    /// Returns the pubkeys of the `p` tags, in order.
+    ///
+    /// Unlike [`Tag::parse`], this reads tags leniently: any `p` tag whose second value is a valid pubkey counts,
+    /// whatever follows it, such as an empty relay or a petname.
+    pub fn mentioned_pubkeys(&self) -> Vec<PublicKey> {
+        self.tags_named("p")
+            .filter_map(|tag| tag.get(1)?.parse().ok())
+            .collect()
+    }
+
+    /// Ids and markers of the `e` tags, in order.
+    ///
+    /// Read leniently like [`Event::mentioned_pubkeys`]: empty and unknown markers count as absent.
+    fn event_references(&self) -> Vec<(EventId, Option<Marker>)> {
+        self.tags_named("e")
+            .filter_map(|tag| {
+                let id = tag.get(1)?.parse().ok()?;
+                let marker = tag.get(3).and_then(|marker| marker.parse().ok());
+                Some((id, marker))
+            })
+            .collect()
+    }
+
+    fn tags_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Vec<String>> + 'a {
+        self.tags
+            .iter()
+            .filter(move |tag| tag.first().is_some_and(|first| first == name))
+    }
+}
+
+/// Assembles an event and signs it, computing its id from the final fields.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::generate_keypair;
+/// use cornostr::event::{EventBuilder, Kind};
+///
+/// let keypair = generate_keypair();
+/// let event = EventBuilder::new(Kind::TEXT_NOTE, "Hello, Nostr!")
+///     .tag(["t", "nostr"])
+///     .created_at(1725316278)
+///     .sign(&keypair);
+/// assert_eq!(event.tags, vec![vec!["t", "nostr"]]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventBuilder {
+    kind: Kind,
+    content: String,
+    tags: Vec<Vec<String>>,
+    created_at: Option<u64>,
+}
+
+impl EventBuilder {
+    /// Starts an event of the given kind, with no tags, created when it is signed.
+    pub fn new(kind: impl Into<Kind>, content: impl Into<String>) -> Self {
+        EventBuilder {
+            kind: kind.into(),
+            content: content.into(),
+            tags: Vec::new(),
+            created_at: None,
+        }
+    }
+
+    /// A text note (kind 1).
+    pub fn text_note(content: impl Into<String>) -> Self {
+        Self::new(Kind::TEXT_NOTE, content)
+    }
+
+    /// User metadata (kind 0), such as `name`, `about` and `picture`, stringified as the content.
+    pub fn metadata(metadata: &Value) -> Self {
+        Self::new(Kind::METADATA, metadata.to_string())
+    }
+
+    /// A follow list (kind 3) with a `p` tag per followed pubkey, replacing the previous one.
+    pub fn contacts(pubkeys: &[PublicKey]) -> Self {
+        pubkeys
+            .iter()
+            .fold(Self::new(Kind::CONTACTS, ""), |builder, pubkey| {
+                builder.tag(["p".to_string(), pubkey.to_string()])
+            })
+    }
+
+    /// A deletion request (kind 5, NIP-09) for events of the same author, with an optional reason as the content.
+    pub fn deletion(ids: &[EventId], reason: &str) -> Self {
+        ids.iter()
+            .fold(Self::new(Kind::DELETION, reason), |builder, id| {
+                builder.tag(["e".to_string(), id.to_string()])
+            })
+    }
+
+    /// A repost (NIP-18) of an event seen on `relay_url`: kind 6 for text notes, kind 16 with a `k` tag otherwise.
+    pub fn repost(event: &Event, relay_url: &str) -> Self {
+        let content = serde_json::to_string(event).unwrap_or_default();
+        let builder = if event.kind == u32::from(Kind::TEXT_NOTE) {
+            Self::new(Kind::REPOST, content)
+        } else {
+            Self::new(Kind::GENERIC_REPOST, content).tag(["k".to_string(), event.kind.to_string()])
+        };
+        builder
+            .tag(["e".to_string(), event.id.to_string(), relay_url.to_string()])
+            .tag(["p".to_string(), event.pubkey.to_string()])
+    }
+
+    /// A reaction (kind 7, NIP-25) to an event, `+` for a like, `-` for a dislike or an emoji.
+    pub fn reaction(event: &Event, content: &str) -> Self {
+        Self::new(Kind::REACTION, content)
+            .tag(["e".to_string(), event.id.to_string()])
+            .tag(["p".to_string(), event.pubkey.to_string()])
+    }
+
+    /// Appends a tag.
+    pub fn tag<S: Into<String>>(mut self, tag: impl IntoIterator<Item = S>) -> Self {
+        self.tags.push(tag.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Appends tags.
+    pub fn tags(mut self, tags: impl IntoIterator<Item = Vec<String>>) -> Self {
+        self.tags.extend(tags);
+        self
+    }
+
+    /// Sets the creation time instead of using the time of signing.
+    pub fn created_at(mut self, created_at: u64) -> Self {
+        self.created_at = Some(created_at);
+        self
+    }
+
+    /// Returns the kind, content, tags and creation time, which is the current time if none was set.
+    pub(crate) fn into_parts(self) -> (u32, String, Vec<Vec<String>>, u64) {
+        let created_at = self.created_at.unwrap_or_else(unix_time);
+        (self.kind.into(), self.content, self.tags, created_at)
+    }
+
+    /// Computes the id of the event and signs it.
+    pub fn sign(self, keypair: &Keypair) -> Event {
+        let pubkey = PublicKey::from_keypair(keypair);
+        let created_at = self.created_at.unwrap_or_else(unix_time);
+        let kind = self.kind.into();
+        let id = EventId::compute(&pubkey, created_at, kind, &self.tags, &self.content);
+        Event {
+            id,
+            pubkey,
+            created_at,
+            kind,
+            tags: self.tags,
+            content: self.content,
+            sig: sign_event_id(&id, keypair),
+        }
+    }
+}
+
+/// Current unix timestamp in seconds.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Kind of an event, which decides how relays store it.
+///
+/// From NIP-01:
+///
+/// - for kind `n` such that `1000 <= n < 10000 || 4 <= n < 45 || n == 1 || n == 2`, events are **regular**, which
+///   means they're all expected to be stored by relays.
+/// - for kind `n` such that `10000 <= n < 20000 || n == 0 || n == 3`, events are **replaceable**, which means
+///   that, for each combination of `pubkey` and `kind`, only the latest event MUST be stored by relays, older
+///   versions MAY be discarded.
+/// - for kind `n` such that `20000 <= n < 30000`, events are **ephemeral**, which means they are not expected to
+///   be stored by relays.
+/// - for kind `n` such that `30000 <= n < 40000`, events are **addressable**, which means that, for each
+///   combination of `pubkey`, `kind` and the `d` tag's first value, only the latest event MUST be stored by
+///   relays, older versions MAY be discarded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct Kind(pub u32);
+
+impl Kind {
+    /// User metadata
+    pub const METADATA: Kind = Kind(0);
+    /// Text note
+    pub const TEXT_NOTE: Kind = Kind(1);
+    /// Follow list
+    pub const CONTACTS: Kind = Kind(3);
+    /// Legacy encrypted direct message (NIP-04)
+    pub const ENCRYPTED_DIRECT_MESSAGE: Kind = Kind(4);
+    /// Deletion request
+    pub const DELETION: Kind = Kind(5);
+    /// Repost of a text note
+    pub const REPOST: Kind = Kind(6);
+    /// Reaction
+    pub const REACTION: Kind = Kind(7);
+    /// Seal of a gift-wrapped rumor (NIP-59)
+    pub const SEAL: Kind = Kind(13);
+    /// Private direct message (NIP-17)
+    pub const PRIVATE_DIRECT_MESSAGE: Kind = Kind(14);
+    /// Repost of any other kind of event
+    pub const GENERIC_REPOST: Kind = Kind(16);
+    /// Gift wrap (NIP-59)
+    pub const GIFT_WRAP: Kind = Kind(1059);
+    /// Client authentication to a relay (NIP-42)
+    pub const CLIENT_AUTHENTICATION: Kind = Kind(22242);
+
+    /// Events that are all expected to be stored by relays.
+    pub fn is_regular(self) -> bool {
+        matches!(self.0, 1 | 2 | 4..=44 | 1000..=9999)
+    }
+
+    /// Events of which only the latest per pubkey and kind is stored.
+    pub fn is_replaceable(self) -> bool {
+        matches!(self.0, 0 | 3 | 10000..=19999)
+    }
+
+    /// Events that are broadcast but never stored.
+    pub fn is_ephemeral(self) -> bool {
+        matches!(self.0, 20000..=29999)
+    }
+
+    /// Events of which only the latest per pubkey, kind and `d` tag is stored.
+    pub fn is_addressable(self) -> bool {
+        matches!(self.0, 30000..=39999)
+    }
+}
+
+impl From<u32> for Kind {
+    fn from(kind: u32) -> Self {
+        Kind(kind)
+    }
+}
+
+impl From<Kind> for u32 {
+    fn from(kind: Kind) -> Self {
+        kind.0
+    }
+}
+
+/// The sha256 of the serialized event data, which identifies an event.
+///
+/// Serialized as 32-bytes lowercase hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EventId(pub [u8; 32]);
+
+impl EventId {
+    /// Computes the id of an event with the given fields, see [`calculate_event_id`].
+    pub fn compute(
+        pubkey: &PublicKey,
+        created_at: u64,
+        kind: u32,
+        tags: &[Vec<String>],
+        content: &str,
+    ) -> EventId {
+        let mut sink = HashSink::new();
+        write_canonical(&mut sink, pubkey, created_at, kind, tags, content);
+        sink.finalize()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventId({})", self)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(EventId(decode_hex(s)?))
+    }
+}
+
+/// The x-only public key of an event creator.
+///
+/// Serialized as 32-bytes lowercase hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PublicKey(pub XOnlyPublicKey);
+
+impl PublicKey {
+    /// Returns the public key of a keypair.
+    pub fn from_keypair(keypair: &Keypair) -> PublicKey {
+        let (xonly_pubkey, _parity) = XOnlyPublicKey::from_keypair(keypair);
+        PublicKey(xonly_pubkey)
+    }
+
+    pub fn serialize(&self) -> [u8; 32] {
+        self.0.serialize()
+    }
+}
+
+impl From<XOnlyPublicKey> for PublicKey {
+    fn from(pubkey: XOnlyPublicKey) -> Self {
+        PublicKey(pubkey)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.serialize()))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; 32] = decode_hex(s)?;
+        XOnlyPublicKey::from_slice(&bytes)
+            .map(PublicKey)
+            .map_err(ParseError::Secp256k1)
+    }
+}
+
+/// A Schnorr signature of an event id.
+///
+/// Serialized as 64-bytes lowercase hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Signature(pub schnorr::Signature);
+
+impl Signature {
+    pub fn serialize(&self) -> [u8; 64] {
+        self.0.serialize()
+    }
+}
+
+impl From<schnorr::Signature> for Signature {
+    fn from(signature: schnorr::Signature) -> Self {
+        Signature(signature)
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.serialize()))
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Signature({})", self)
+    }
+}
+
+impl FromStr for Signature {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; 64] = decode_hex(s)?;
+        schnorr::Signature::from_slice(&bytes)
+            .map(Signature)
+            .map_err(ParseError::Secp256k1)
+    }
+}
+
+/// Serializes the hex newtypes as lowercase hex strings and parses them back with [`FromStr`].
+macro_rules! hex_serde {
+    ($($name:ident),*) => {$(
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    )*};
+}
+
+hex_serde!(EventId, PublicKey, Signature);
+
+/// Error parsing an [`EventId`], [`PublicKey`] or [`Signature`] from hex, or a tag value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The string is not lowercase hex of the expected length.
+    Hex { expected: usize },
+    /// The bytes are not a valid key or signature.
+    Secp256k1(secp256k1::Error),
+    /// The string is not an `e` tag marker.
+    Marker,
+    /// The string is not a `<kind>:<pubkey>:<d tag>` coordinate.
+    Coordinate,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Hex { expected } => {
+                write!(f, "expected {} lowercase hex characters", expected)
+            }
+            ParseError::Secp256k1(e) => write!(f, "{}", e),
+            ParseError::Marker => write!(f, "expected root, reply or mention"),
+            ParseError::Coordinate => write!(f, "expected <kind>:<pubkey>:<d tag>"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Decodes exactly `N` bytes from lowercase hex, rejecting uppercase digits.
+fn decode_hex<const N: usize>(s: &str) -> Result<[u8; N], ParseError> {
+    let error = ParseError::Hex { expected: N * 2 };
+    if s.len() != N * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err(error);
+    }
+    let mut bytes = [0u8; N];
+    hex::decode_to_slice(s, &mut bytes).map_err(|_| error)?;
+    Ok(bytes)
 }
 
 /// Calculates the ID for a Nostr event.
@@ -75,25 +580,319 @@ pub struct Event {
 ///   - A backspace, (`0x08`), use `\b`
 ///   - A form feed, (`0x0C`), use `\f`
 #[allow(dead_code)]
-pub fn calculate_event_id(event: &Event) -> String {
-    let serialized = serialize_event(event);
-    let mut hasher = Sha256::new();
-    hasher.update(serialized);
-    hex::encode(hasher.finalize())
+pub fn calculate_event_id(event: &Event) -> EventId {
+    EventId::compute(
+        &event.pubkey,
+        event.created_at,
+        event.kind,
+        &event.tags,
+        &event.content,
+    )
 }
 
 /// Serializes an event for ID calculation and signing.
 #[allow(dead_code)]
 pub fn serialize_event(event: &Event) -> Vec<u8> {
-    let serialized = format!(
-        "[0,\"{}\",{},{},{},{}]",
-        event.pubkey,
+    let mut serialized = Vec::new();
+    write_canonical(
+        &mut serialized,
+        &event.pubkey,
         event.created_at,
         event.kind,
-        serde_json::to_string(&event.tags).unwrap(),
-        serde_json::to_string(&event.content).unwrap()
+        &event.tags,
+        &event.content,
     );
-    serialized.into_bytes()
+    serialized
+}
+
+/// Destination of the canonical serialization: a buffer, or a hasher to compute the id without one.
+trait Sink {
+    fn write(&mut self, bytes: &[u8]);
+}
+
+impl Sink for Vec<u8> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+/// Feeds the hasher in chunks, as most writes are a few bytes long and each update call has a fixed cost.
+struct HashSink {
+    hasher: Sha256,
+    buffer: [u8; 4096],
+    len: usize,
+}
+
+impl HashSink {
+    fn new() -> Self {
+        HashSink {
+            hasher: Sha256::new(),
+            buffer: [0; 4096],
+            len: 0,
+        }
+    }
+
+    fn finalize(mut self) -> EventId {
+        self.hasher.update(&self.buffer[..self.len]);
+        EventId(self.hasher.finalize().into())
+    }
+
+    /// Hashes the buffered bytes followed by `bytes`, which didn't fit in the buffer.
+    #[cold]
+    fn flush(&mut self, bytes: &[u8]) {
+        self.hasher.update(&self.buffer[..self.len]);
+        self.hasher.update(bytes);
+        self.len = 0;
+    }
+}
+
+impl Sink for HashSink {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        match self.buffer.get_mut(self.len..self.len + bytes.len()) {
+            Some(free) => {
+                free.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            None => self.flush(bytes),
+        }
+    }
+}
+
+/// Streams `[0,<pubkey>,<created_at>,<kind>,<tags>,<content>]` into the sink without intermediate allocations.
+fn write_canonical(
+    sink: &mut impl Sink,
+    pubkey: &PublicKey,
+    created_at: u64,
+    kind: u32,
+    tags: &[Vec<String>],
+    content: &str,
+) {
+    let mut pubkey_hex = [0u8; 64];
+    hex::encode_to_slice(pubkey.serialize(), &mut pubkey_hex)
+        .expect("64 bytes hold 32 bytes of hex");
+
+    sink.write(b"[0,\"");
+    sink.write(&pubkey_hex);
+    sink.write(b"\",");
+    write_number(sink, created_at);
+    sink.write(b",");
+    write_number(sink, kind.into());
+    sink.write(b",[");
+    for (i, tag) in tags.iter().enumerate() {
+        sink.write(if i == 0 { b"[" } else { b",[" });
+        for (j, value) in tag.iter().enumerate() {
+            if j > 0 {
+                sink.write(b",");
+            }
+            write_string(sink, value);
+        }
+        sink.write(b"]");
+    }
+    sink.write(b"],");
+    write_string(sink, content);
+    sink.write(b"]");
+}
+
+fn write_number(sink: &mut impl Sink, mut n: u64) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    sink.write(&digits[start..]);
+}
+
+/// Writes a JSON string with the NIP-01 escapes, `\u00XX` for the other control characters as `serde_json` does, and
+/// everything else verbatim.
+fn write_string(sink: &mut impl Sink, s: &str) {
+    sink.write(b"\"");
+    let bytes = s.as_bytes();
+    let mut verbatim = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let escape = ESCAPE[byte as usize];
+        if escape == 0 {
+            continue;
+        }
+        sink.write(&bytes[verbatim..i]);
+        if escape == b'u' {
+            const HEX: &[u8; 16] = b"0123456789abcdef";
+            sink.write(&[
+                b'\\',
+                b'u',
+                b'0',
+                b'0',
+                HEX[(byte >> 4) as usize],
+                HEX[(byte & 0xf) as usize],
+            ]);
+        } else {
+            sink.write(&[b'\\', escape]);
+        }
+        verbatim = i + 1;
+    }
+    sink.write(&bytes[verbatim..]);
+    sink.write(b"\"");
+}
+
+/// The character following the backslash for each byte that must be escaped, `u` for `\u00XX`, 0 for the others.
+static ESCAPE: [u8; 256] = {
+    let mut escape = [0; 256];
+    let mut byte = 0;
+    while byte < 0x20 {
+        escape[byte] = b'u';
+        byte += 1;
+    }
+    escape[b'\n' as usize] = b'n';
+    escape[b'"' as usize] = b'"';
+    escape[b'\\' as usize] = b'\\';
+    escape[b'\r' as usize] = b'r';
+    escape[b'\t' as usize] = b't';
+    escape[0x08] = b'b';
+    escape[0x0c] = b'f';
+    escape
+};
+
+/// A subscription filter, as sent in `REQ` messages.
+///
+/// From NIP-01:
+///
+/// ```json
+/// {
+///   "ids": <a list of event ids>,
+///   "authors": <a list of lowercase pubkeys, the pubkey of an event must be one of these>,
+///   "kinds": <a list of a kind numbers>,
+///   "#<single-letter (a-zA-Z)>": <a list of tag values, for #e — a list of event ids, for #p — a list of pubkeys, etc.>,
+///   "since": <an integer unix timestamp in seconds. Events must have a created_at >= to this to pass>,
+///   "until": <an integer unix timestamp in seconds. Events must have a created_at <= to this to pass>,
+///   "limit": <maximum number of events relays SHOULD return in the initial query>
+/// }
+/// ```
+///
+/// All conditions that are specified must match for an event to pass the filter.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(try_from = "RawFilter", into = "RawFilter")]
+pub struct Filter {
+    /// Event ids, the id of an event must be one of these.
+    pub ids: Option<Vec<EventId>>,
+    /// Lowercase pubkeys, the pubkey of an event must be one of these.
+    pub authors: Option<Vec<PublicKey>>,
+    /// Kind numbers, the kind of an event must be one of these.
+    pub kinds: Option<Vec<u32>>,
+    /// Tag queries keyed by single-letter tag name (`#e`, `#p`, ...). The event must have at least one tag with
+    /// that name whose value is one of these.
+    pub tags: BTreeMap<char, Vec<String>>,
+    /// Events must have a `created_at` >= to this to pass.
+    pub since: Option<u64>,
+    /// Events must have a `created_at` <= to this to pass.
+    pub until: Option<u64>,
+    /// Maximum number of events relays should return in the initial query.
+    pub limit: Option<usize>,
+    /// Kind numbers the kind of an event must not be.
+    ///
+    /// Not part of NIP-01, so never sent or received: relays use it to leave out the events a connection may not
+    /// read before `limit` applies.
+    pub excluded_kinds: Vec<u32>,
+}
+
+impl Filter {
+    /// Returns true if the event passes every condition of this filter.
+    ///
+    /// `limit` only applies to the initial query and is ignored here.
+    pub fn matches(&self, event: &Event) -> bool {
+        self.ids.as_ref().is_none_or(|ids| ids.contains(&event.id))
+            && self
+                .authors
+                .as_ref()
+                .is_none_or(|authors| authors.contains(&event.pubkey))
+            && self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.contains(&event.kind))
+            && !self.excluded_kinds.contains(&event.kind)
+            && self.since.is_none_or(|since| event.created_at >= since)
+            && self.until.is_none_or(|until| event.created_at <= until)
+            && self.tags.iter().all(|(name, values)| {
+                event.tags.iter().any(|tag| {
+                    tag.len() >= 2
+                        && tag[0].len() == 1
+                        && tag[0].starts_with(*name)
+                        && values.contains(&tag[1])
+                })
+            })
+    }
+}
+
+/// Wire representation of a [`Filter`], with the `#<letter>` tag queries left as plain object keys.
+#[derive(Serialize, Deserialize)]
+struct RawFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ids: Option<Vec<EventId>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authors: Option<Vec<PublicKey>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kinds: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    #[serde(flatten)]
+    rest: BTreeMap<String, Value>,
+}
+
+impl TryFrom<RawFilter> for Filter {
+    type Error = String;
+
+    fn try_from(raw: RawFilter) -> Result<Self, Self::Error> {
+        let mut tags = BTreeMap::new();
+        for (key, value) in raw.rest {
+            let name = match key
+                .strip_prefix('#')
+                .map(|name| name.chars().collect::<Vec<_>>())
+            {
+                Some(name) if name.len() == 1 && name[0].is_ascii_alphabetic() => name[0],
+                _ => return Err(format!("unknown filter field `{}`", key)),
+            };
+            let values = serde_json::from_value::<Vec<String>>(value)
+                .map_err(|e| format!("invalid values for `{}`: {}", key, e))?;
+            tags.insert(name, values);
+        }
+
+        Ok(Filter {
+            ids: raw.ids,
+            authors: raw.authors,
+            kinds: raw.kinds,
+            tags,
+            since: raw.since,
+            until: raw.until,
+            limit: raw.limit,
+            excluded_kinds: Vec::new(),
+        })
+    }
+}
+
+impl From<Filter> for RawFilter {
+    fn from(filter: Filter) -> Self {
+        RawFilter {
+            ids: filter.ids,
+            authors: filter.authors,
+            kinds: filter.kinds,
+            since: filter.since,
+            until: filter.until,
+            limit: filter.limit,
+            rest: filter
+                .tags
+                .into_iter()
+                .map(|(name, values)| (format!("#{}", name), Value::from(values)))
+                .collect(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -104,10 +903,10 @@ mod tests {
         Event {
             content: "Thank you!".to_string(),
             created_at: 1725316278,
-            id: "4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6".to_string(),
+            id: "4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6".parse().unwrap(),
             kind: 1,
-            pubkey: "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453".to_string(),
-            sig: "44b4b5e4087504f7ca44bb72cb89c119e680f459739a476023a036075e93a5219dc21380fbda14af4c5008185c1fc86a08acb433fb7097eff175cc81174a345c".to_string(),
+            pubkey: "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453".parse().unwrap(),
+            sig: "44b4b5e4087504f7ca44bb72cb89c119e680f459739a476023a036075e93a5219dc21380fbda14af4c5008185c1fc86a08acb433fb7097eff175cc81174a345c".parse().unwrap(),
             tags: vec![
                 vec!["e".to_string(),"f14669da001fc23052bbfa3e4124699a85dc14b3ecb65023a86ed16a317c1cc3".to_string(),"".to_string(),"root".to_string()],
                 vec!["e".to_string(),"32928056b07792e9a92193720c67d3458351ea66fbc568cdc87be41a5faa92ce".to_string(),"wss://nos.lol".to_string(),"reply".to_string()],
@@ -120,7 +919,7 @@ mod tests {
     fn test_event_id_calculation() {
         let event = test_event();
 
-        let id = calculate_event_id(&event);
+        let id = calculate_event_id(&event).to_string();
         assert_eq!(id.len(), 64);
         assert!(hex::decode(&id).is_ok());
     }
@@ -181,17 +980,398 @@ mod tests {
 
         for (content, expected) in test_cases {
             let event = Event {
-                id: String::new(),
-                pubkey: "pubkey".to_string(),
                 created_at: 1234567890,
                 kind: 1,
                 tags: vec![],
                 content: content.to_string(),
-                sig: String::new(),
+                ..test_event()
             };
 
             let serialized = serialize_event(&event);
-            assert_eq!(String::from_utf8(serialized).unwrap(), expected);
+            assert_eq!(
+                String::from_utf8(serialized).unwrap(),
+                expected.replace("pubkey", &event.pubkey.to_string())
+            );
         }
     }
+
+    #[test]
+    fn test_serialize_event_matches_serde_json() {
+        let mut content: String = (0u8..0x80).map(char::from).collect();
+        content.push_str("héllo wörld 🎉 \u{2028}\u{feff}");
+        let event = Event {
+            tags: vec![
+                vec![],
+                vec![content.clone()],
+                vec!["e".to_string(), "\"\\\n".to_string(), String::new()],
+            ],
+            content,
+            created_at: u64::MAX,
+            kind: 0,
+            ..test_event()
+        };
+
+        let expected = format!(
+            "[0,\"{}\",{},{},{},{}]",
+            event.pubkey,
+            event.created_at,
+            event.kind,
+            serde_json::to_string(&event.tags).unwrap(),
+            serde_json::to_string(&event.content).unwrap()
+        );
+        assert_eq!(
+            String::from_utf8(serialize_event(&event)).unwrap(),
+            expected
+        );
+
+        let mut hasher = Sha256::new();
+        hasher.update(expected);
+        assert_eq!(
+            calculate_event_id(&event),
+            EventId(hasher.finalize().into())
+        );
+    }
+
+    #[test]
+    fn test_hex_newtypes_round_trip() {
+        let event = test_event();
+
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.contains(
+            r#""id":"4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6""#
+        ));
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), event);
+
+        assert_eq!(event.id.to_string().parse::<EventId>().unwrap(), event.id);
+        assert_eq!(
+            event.pubkey.to_string().parse::<PublicKey>().unwrap(),
+            event.pubkey
+        );
+        assert_eq!(
+            event.sig.to_string().parse::<Signature>().unwrap(),
+            event.sig
+        );
+    }
+
+    #[test]
+    fn test_hex_newtypes_reject_bad_input() {
+        let id = "4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6";
+
+        assert_eq!(
+            id.to_uppercase().parse::<EventId>(),
+            Err(ParseError::Hex { expected: 64 })
+        );
+        assert_eq!(
+            id[..62].parse::<EventId>(),
+            Err(ParseError::Hex { expected: 64 })
+        );
+        assert_eq!(
+            id.parse::<Signature>(),
+            Err(ParseError::Hex { expected: 128 })
+        );
+        // Not the x coordinate of a point on the curve
+        assert!(matches!(
+            "0".repeat(64).parse::<PublicKey>(),
+            Err(ParseError::Secp256k1(_))
+        ));
+
+        let mut json = serde_json::to_value(test_event()).unwrap();
+        json["pubkey"] =
+            Value::from("ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e45");
+        assert!(serde_json::from_value::<Event>(json).is_err());
+
+        assert!(serde_json::from_str::<Filter>(r#"{"authors":["abcdef"]}"#).is_err());
+    }
+
+    #[test]
+    fn test_filter_round_trip() {
+        let json = r##"{"ids":["4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6"],"kinds":[1,7],"since":1725316000,"limit":10,"#e":["f14669da001fc23052bbfa3e4124699a85dc14b3ecb65023a86ed16a317c1cc3"],"#p":["2f5759825226f1d57ef1652ba66114b2f938f7f5c50dc505708e5d8b31e4f3c9"]}"##;
+
+        let filter: Filter = serde_json::from_str(json).unwrap();
+        assert_eq!(filter.kinds, Some(vec![1, 7]));
+        assert_eq!(filter.limit, Some(10));
+        assert_eq!(filter.tags.len(), 2);
+        assert!(filter.authors.is_none());
+
+        assert_eq!(serde_json::to_string(&filter).unwrap(), json);
+    }
+
+    #[test]
+    fn test_filter_rejects_unknown_fields() {
+        assert!(serde_json::from_str::<Filter>(r#"{"search":"nostr"}"#).is_err());
+        assert!(serde_json::from_str::<Filter>(r##"{"#ee":["x"]}"##).is_err());
+        assert!(serde_json::from_str::<Filter>(r##"{"#e":"x"}"##).is_err());
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let event = test_event();
+
+        assert!(Filter::default().matches(&event));
+
+        let filter = Filter {
+            ids: Some(vec![event.id]),
+            authors: Some(vec![event.pubkey]),
+            kinds: Some(vec![1]),
+            since: Some(event.created_at),
+            until: Some(event.created_at),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert!(filter.matches(&event));
+
+        let filter = Filter {
+            kinds: Some(vec![0, 3]),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&event));
+
+        let filter = Filter {
+            since: Some(event.created_at + 1),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&event));
+
+        let filter = Filter {
+            until: Some(event.created_at - 1),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&event));
+    }
+
+    #[test]
+    fn test_filter_matches_tags() {
+        let event = test_event();
+
+        let filter: Filter = serde_json::from_str(
+            r##"{"#e":["32928056b07792e9a92193720c67d3458351ea66fbc568cdc87be41a5faa92ce","unknown"]}"##,
+        )
+        .unwrap();
+        assert!(filter.matches(&event));
+
+        let filter: Filter = serde_json::from_str(
+            r##"{"#e":["32928056b07792e9a92193720c67d3458351ea66fbc568cdc87be41a5faa92ce"],"#p":["unknown"]}"##,
+        )
+        .unwrap();
+        assert!(!filter.matches(&event));
+
+        let filter: Filter = serde_json::from_str(
+            r##"{"#t":["32928056b07792e9a92193720c67d3458351ea66fbc568cdc87be41a5faa92ce"]}"##,
+        )
+        .unwrap();
+        assert!(!filter.matches(&event));
+    }
+
+    #[test]
+    fn test_kind_classes() {
+        let classes = |kind: u32| {
+            let kind = Kind(kind);
+            (
+                kind.is_regular(),
+                kind.is_replaceable(),
+                kind.is_ephemeral(),
+                kind.is_addressable(),
+            )
+        };
+
+        for kind in [1, 2, 4, 44, 1000, 9999] {
+            assert_eq!(classes(kind), (true, false, false, false), "kind {}", kind);
+        }
+        for kind in [0, 3, 10000, 19999] {
+            assert_eq!(classes(kind), (false, true, false, false), "kind {}", kind);
+        }
+        for kind in [20000, 29999] {
+            assert_eq!(classes(kind), (false, false, true, false), "kind {}", kind);
+        }
+        for kind in [30000, 39999] {
+            assert_eq!(classes(kind), (false, false, false, true), "kind {}", kind);
+        }
+        for kind in [45, 999, 40000, 65535] {
+            assert_eq!(classes(kind), (false, false, false, false), "kind {}", kind);
+        }
+    }
+
+    #[test]
+    fn test_replaces() {
+        let metadata = Event {
+            kind: Kind::METADATA.into(),
+            tags: vec![],
+            ..test_event()
+        };
+        let newer = Event {
+            created_at: metadata.created_at + 1,
+            ..metadata.clone()
+        };
+        assert!(newer.replaces(&metadata));
+        assert!(!metadata.replaces(&newer));
+        assert!(!metadata.replaces(&metadata));
+
+        let same_time_lower_id = Event {
+            id: EventId([0; 32]),
+            ..metadata.clone()
+        };
+        assert!(same_time_lower_id.replaces(&metadata));
+
+        let other_author = Event {
+            pubkey: "2f5759825226f1d57ef1652ba66114b2f938f7f5c50dc505708e5d8b31e4f3c9"
+                .parse()
+                .unwrap(),
+            ..newer.clone()
+        };
+        assert!(!other_author.replaces(&metadata));
+
+        // Regular events never replace each other
+        let note = test_event();
+        let newer_note = Event {
+            created_at: note.created_at + 1,
+            ..note.clone()
+        };
+        assert!(!newer_note.replaces(&note));
+    }
+
+    #[test]
+    fn test_addressable_replaces_by_identifier() {
+        let article = |d: Option<&str>, created_at: u64| Event {
+            kind: 30023,
+            created_at,
+            tags: d
+                .map(|d| vec![vec!["d".to_string(), d.to_string()]])
+                .unwrap_or_default(),
+            ..test_event()
+        };
+
+        assert!(article(Some("a"), 2).replaces(&article(Some("a"), 1)));
+        assert!(!article(Some("b"), 2).replaces(&article(Some("a"), 1)));
+        // A missing d tag is the same as an empty one
+        assert!(article(None, 2).replaces(&article(Some(""), 1)));
+        assert_eq!(article(None, 1).identifier(), None);
+        assert_eq!(article(Some("a"), 1).identifier(), Some("a"));
+    }
+
+    #[test]
+    fn test_builder_signs_final_fields() {
+        let keypair = crate::crypto::generate_keypair();
+        let event = EventBuilder::new(30023, "article")
+            .tag(["d", "intro"])
+            .tags(vec![vec!["t".to_string(), "nostr".to_string()]])
+            .created_at(1725316278)
+            .sign(&keypair);
+
+        assert_eq!(event.kind, 30023);
+        assert_eq!(event.created_at, 1725316278);
+        assert_eq!(event.tags, vec![vec!["d", "intro"], vec!["t", "nostr"]]);
+        assert_eq!(event.pubkey, PublicKey::from_keypair(&keypair));
+        assert_eq!(crate::crypto::verify_event(&event), Ok(()));
+    }
+
+    #[test]
+    fn test_builder_convenience_constructors() {
+        let keypair = crate::crypto::generate_keypair();
+        let note = test_event();
+
+        let reaction = EventBuilder::reaction(&note, "+").sign(&keypair);
+        assert_eq!(reaction.kind, 7);
+        assert_eq!(
+            reaction.tags,
+            vec![
+                vec!["e".to_string(), note.id.to_string()],
+                vec!["p".to_string(), note.pubkey.to_string()],
+            ]
+        );
+
+        let repost = EventBuilder::repost(&note, "wss://nos.lol").sign(&keypair);
+        assert_eq!(repost.kind, 6);
+        assert_eq!(
+            serde_json::from_str::<Event>(&repost.content).unwrap(),
+            note
+        );
+        assert_eq!(repost.tags[0][2], "wss://nos.lol");
+
+        let article = Event {
+            kind: 30023,
+            ..note
+        };
+        let repost = EventBuilder::repost(&article, "").sign(&keypair);
+        assert_eq!(
+            (repost.kind, &repost.tags[0]),
+            (16, &vec!["k".to_string(), "30023".to_string()])
+        );
+
+        let contacts = EventBuilder::contacts(&[article.pubkey]).sign(&keypair);
+        assert_eq!((contacts.kind, contacts.tags.len()), (3, 1));
+    }
+
+    #[test]
+    fn test_thread_accessors() {
+        let event = test_event();
+        let root = "f14669da001fc23052bbfa3e4124699a85dc14b3ecb65023a86ed16a317c1cc3";
+        let reply = "32928056b07792e9a92193720c67d3458351ea66fbc568cdc87be41a5faa92ce";
+        assert_eq!(event.root_id(), Some(root.parse().unwrap()));
+        assert_eq!(event.reply_to(), Some(reply.parse().unwrap()));
+        assert_eq!(
+            event.mentioned_pubkeys(),
+            vec![
+                "2f5759825226f1d57ef1652ba66114b2f938f7f5c50dc505708e5d8b31e4f3c9"
+                    .parse()
+                    .unwrap()
+            ]
+        );
+        assert_eq!(
+            event.parsed_tags().map(Vec::from).collect::<Vec<_>>(),
+            event.tags
+        );
+
+        // Direct reply to the root only marks the root
+        let direct = Event {
+            tags: vec![event.tags[0].clone()],
+            ..test_event()
+        };
+        assert_eq!(direct.reply_to(), Some(root.parse().unwrap()));
+
+        // Deprecated positional `e` tags: first is the root, last the reply
+        let positional = Event {
+            tags: vec![
+                vec!["e".to_string(), root.to_string()],
+                vec!["e".to_string(), reply.to_string()],
+            ],
+            ..test_event()
+        };
+        assert_eq!(positional.root_id(), Some(root.parse().unwrap()));
+        assert_eq!(positional.reply_to(), Some(reply.parse().unwrap()));
+
+        // Empty relays and markers, and values after them, don't hide the ids and pubkeys
+        let pubkey = "2f5759825226f1d57ef1652ba66114b2f938f7f5c50dc505708e5d8b31e4f3c9";
+        let tag = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let empty_values = Event {
+            tags: vec![
+                tag(&["e", root, ""]),
+                tag(&["e", reply, "", ""]),
+                tag(&["p", pubkey, ""]),
+                tag(&["p", pubkey, "", "alice"]),
+            ],
+            ..test_event()
+        };
+        assert_eq!(empty_values.root_id(), Some(root.parse().unwrap()));
+        assert_eq!(empty_values.reply_to(), Some(reply.parse().unwrap()));
+        assert_eq!(
+            empty_values.mentioned_pubkeys(),
+            vec![pubkey.parse().unwrap(), pubkey.parse().unwrap()]
+        );
+
+        let marked = Event {
+            tags: vec![
+                tag(&["e", root, "", "root"]),
+                tag(&["e", reply, "", "reply", ""]),
+            ],
+            ..test_event()
+        };
+        assert_eq!(marked.root_id(), Some(root.parse().unwrap()));
+        assert_eq!(marked.reply_to(), Some(reply.parse().unwrap()));
+
+        let note = Event {
+            tags: vec![],
+            ..test_event()
+        };
+        assert_eq!((note.root_id(), note.reply_to()), (None, None));
+    }
 }
@@ -0,0 +1,319 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::{EventId, Kind, ParseError, PublicKey};
+
+/// A typed event tag.
+///
+/// Tags are parsed from and converted back to their wire form, an array of strings, without loss: tags that don't
+/// fit one of the typed variants exactly, including ones with extra or empty trailing values, are kept as
+/// [`Tag::Custom`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tag {
+    /// `["e", <event id>, <relay url>, <marker>, <pubkey>]`, a reference to an event (NIP-01, NIP-10).
+    Event {
+        id: EventId,
+        relay: Option<String>,
+        marker: Option<Marker>,
+        pubkey: Option<PublicKey>,
+    },
+    /// `["q", <event id>, <relay url>, <pubkey>]`, an event quoted in the content (NIP-18).
+    Quote {
+        id: EventId,
+        relay: Option<String>,
+        pubkey: Option<PublicKey>,
+    },
+    /// `["p", <pubkey>, <relay url>]`, a reference to a user.
+    PubKey {
+        pubkey: PublicKey,
+        relay: Option<String>,
+    },
+    /// `["a", <kind>:<pubkey>:<d tag>, <relay url>]`, a reference to an addressable or replaceable event.
+    Address {
+        coordinate: Coordinate,
+        relay: Option<String>,
+    },
+    /// `["d", <identifier>]`, the identifier of an addressable event.
+    Identifier(String),
+    /// `["t", <hashtag>]`, a hashtag without the leading `#`.
+    Hashtag(String),
+    /// Any other tag, as it appears on the wire.
+    Custom(Vec<String>),
+}
+
+/// Role of an `e` tag in a thread, from NIP-10.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marker {
+    /// The root of the thread.
+    Root,
+    /// The event being directly replied to.
+    Reply,
+    /// An event quoted or mentioned in the content.
+    Mention,
+}
+
+impl Tag {
+    /// Parses a tag from its wire form.
+    ///
+    /// To find the events and pubkeys an event refers to, use the accessors of [`Event`](super::Event) such as
+    /// [`mentioned_pubkeys`](super::Event::mentioned_pubkeys) instead: they also read the `e` and `p` tags that are
+    /// kept as [`Tag::Custom`].
+    pub fn parse(tag: &[String]) -> Tag {
+        match Self::parse_typed(tag) {
+            Some(typed) if typed.to_vec() == tag => typed,
+            _ => Tag::Custom(tag.to_vec()),
+        }
+    }
+
+    fn parse_typed(tag: &[String]) -> Option<Tag> {
+        let value = tag.get(1)?;
+        // Empty values stand for unset ones followed by set ones
+        let get = |i: usize| tag.get(i).filter(|value| !value.is_empty());
+        let relay = || get(2).cloned();
+        let typed = match tag[0].as_str() {
+            "e" => Tag::Event {
+                id: value.parse().ok()?,
+                relay: relay(),
+                marker: match get(3) {
+                    Some(marker) => Some(marker.parse().ok()?),
+                    None => None,
+                },
+                pubkey: match get(4) {
+                    Some(pubkey) => Some(pubkey.parse().ok()?),
+                    None => None,
+                },
+            },
+            "q" => Tag::Quote {
+                id: value.parse().ok()?,
+                relay: relay(),
+                pubkey: match get(3) {
+                    Some(pubkey) => Some(pubkey.parse().ok()?),
+                    None => None,
+                },
+            },
+            "p" => Tag::PubKey {
+                pubkey: value.parse().ok()?,
+                relay: relay(),
+            },
+            "a" => Tag::Address {
+                coordinate: value.parse().ok()?,
+                relay: relay(),
+            },
+            "d" => Tag::Identifier(value.clone()),
+            "t" => Tag::Hashtag(value.clone()),
+            _ => return None,
+        };
+        Some(typed)
+    }
+
+    /// Returns the wire form of the tag, leaving out trailing values that are not set.
+    pub fn to_vec(&self) -> Vec<String> {
+        let mut tag = match self {
+            Tag::Event {
+                id,
+                relay,
+                marker,
+                pubkey,
+            } => vec![
+                "e".to_string(),
+                id.to_string(),
+                relay.clone().unwrap_or_default(),
+                marker.map(|m| m.to_string()).unwrap_or_default(),
+                pubkey.map(|p| p.to_string()).unwrap_or_default(),
+            ],
+            Tag::Quote { id, relay, pubkey } => vec![
+                "q".to_string(),
+                id.to_string(),
+                relay.clone().unwrap_or_default(),
+                pubkey.map(|p| p.to_string()).unwrap_or_default(),
+            ],
+            Tag::PubKey { pubkey, relay } => vec![
+                "p".to_string(),
+                pubkey.to_string(),
+                relay.clone().unwrap_or_default(),
+            ],
+            Tag::Address { coordinate, relay } => vec![
+                "a".to_string(),
+                coordinate.to_string(),
+                relay.clone().unwrap_or_default(),
+            ],
+            Tag::Identifier(identifier) => return vec!["d".to_string(), identifier.clone()],
+            Tag::Hashtag(hashtag) => return vec!["t".to_string(), hashtag.clone()],
+            Tag::Custom(tag) => return tag.clone(),
+        };
+        while tag.last().is_some_and(String::is_empty) {
+            tag.pop();
+        }
+        tag
+    }
+}
+
+impl From<Tag> for Vec<String> {
+    fn from(tag: Tag) -> Self {
+        match tag {
+            Tag::Custom(tag) => tag,
+            tag => tag.to_vec(),
+        }
+    }
+}
+
+/// Lets typed tags be passed wherever a tag's strings are expected, such as [`super::EventBuilder::tag`].
+impl IntoIterator for Tag {
+    type Item = String;
+    type IntoIter = std::vec::IntoIter<String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Vec::from(self).into_iter()
+    }
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Marker::Root => "root",
+            Marker::Reply => "reply",
+            Marker::Mention => "mention",
+        })
+    }
+}
+
+impl FromStr for Marker {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "root" => Ok(Marker::Root),
+            "reply" => Ok(Marker::Reply),
+            "mention" => Ok(Marker::Mention),
+            _ => Err(ParseError::Marker),
+        }
+    }
+}
+
+/// The address of an addressable or replaceable event: `<kind>:<pubkey>:<d tag>`, with an empty `d` tag for
+/// replaceable events.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Coordinate {
+    pub kind: Kind,
+    pub pubkey: PublicKey,
+    pub identifier: String,
+}
+
+impl fmt::Display for Coordinate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.kind.0, self.pubkey, self.identifier)
+    }
+}
+
+impl FromStr for Coordinate {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(kind), Some(pubkey), Some(identifier)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ParseError::Coordinate);
+        };
+        Ok(Coordinate {
+            kind: Kind(kind.parse().map_err(|_| ParseError::Coordinate)?),
+            pubkey: pubkey.parse()?,
+            identifier: identifier.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "f14669da001fc23052bbfa3e4124699a85dc14b3ecb65023a86ed16a317c1cc3";
+    const PUBKEY: &str = "2f5759825226f1d57ef1652ba66114b2f938f7f5c50dc505708e5d8b31e4f3c9";
+
+    fn tag(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_typed_tags() {
+        assert_eq!(
+            Tag::parse(&tag(&["e", ID, "", "root"])),
+            Tag::Event {
+                id: ID.parse().unwrap(),
+                relay: None,
+                marker: Some(Marker::Root),
+                pubkey: None,
+            }
+        );
+        assert_eq!(
+            Tag::parse(&tag(&["p", PUBKEY, "wss://nos.lol"])),
+            Tag::PubKey {
+                pubkey: PUBKEY.parse().unwrap(),
+                relay: Some("wss://nos.lol".to_string()),
+            }
+        );
+        assert_eq!(
+            Tag::parse(&tag(&["a", &format!("30023:{}:my:article", PUBKEY)])),
+            Tag::Address {
+                coordinate: Coordinate {
+                    kind: Kind(30023),
+                    pubkey: PUBKEY.parse().unwrap(),
+                    identifier: "my:article".to_string(),
+                },
+                relay: None,
+            }
+        );
+        assert_eq!(Tag::parse(&tag(&["d", ""])), Tag::Identifier(String::new()));
+        assert_eq!(
+            Tag::parse(&tag(&["t", "nostr"])),
+            Tag::Hashtag("nostr".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_keeps_unusual_tags_as_custom() {
+        for values in [
+            vec!["e", ID, "", "fork"],
+            vec!["e", &ID[..10]],
+            vec!["t"],
+            vec!["t", "nostr", "extra"],
+            vec!["client", "cornostr"],
+        ] {
+            let values = tag(&values);
+            let parsed = Tag::parse(&values);
+            assert_eq!(parsed, Tag::Custom(values.clone()));
+            assert_eq!(Vec::from(parsed), values);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tags = [
+            Tag::Event {
+                id: ID.parse().unwrap(),
+                relay: Some("wss://nos.lol".to_string()),
+                marker: Some(Marker::Reply),
+                pubkey: Some(PUBKEY.parse().unwrap()),
+            },
+            Tag::Event {
+                id: ID.parse().unwrap(),
+                relay: None,
+                marker: None,
+                pubkey: Some(PUBKEY.parse().unwrap()),
+            },
+            Tag::PubKey {
+                pubkey: PUBKEY.parse().unwrap(),
+                relay: None,
+            },
+            Tag::Quote {
+                id: ID.parse().unwrap(),
+                relay: None,
+                pubkey: Some(PUBKEY.parse().unwrap()),
+            },
+            Tag::Hashtag("nostr".to_string()),
+        ];
+        for tag in tags {
+            assert_eq!(Tag::parse(&tag.to_vec()), tag);
+        }
+    }
+}
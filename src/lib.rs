@@ -1,5 +1,17 @@
 pub mod client;
 pub mod crypto;
+pub mod error;
 pub mod event;
+pub mod nip11;
+pub mod nip13;
+pub mod nip19;
+pub mod nip21;
+pub mod nip42;
+pub mod nip45;
+pub mod nip59;
 pub mod post;
 pub mod relay;
+pub mod store;
+pub mod thread;
+
+pub use error::Error;
@@ -1,9 +1,16 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use cornostr::client::Client;
-use cornostr::crypto::generate_keypair;
-use cornostr::post::create_note;
-use cornostr::relay::Relay;
+use cornostr::crypto::{self, generate_keypair, VerifyCache};
+use cornostr::event::{EventBuilder, PublicKey};
+use cornostr::nip11::RelayInformation;
+use cornostr::nip13;
+use cornostr::nip19::Nip19;
+use cornostr::relay::{Config, Relay};
+use cornostr::store::{EventStore, IndexedStore, SqliteStore};
+use secp256k1::{Keypair, SecretKey, SECP256K1};
 use std::error::Error;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -20,6 +27,10 @@ enum Commands {
         #[clap(short, long, default_value = "wss://relay.damus.io")]
         relay: String,
 
+        /// Secret key to sign events with, as nsec or hex. A new one is generated if not given
+        #[clap(short = 'k', long)]
+        secret_key: Option<String>,
+
         #[clap(subcommand)]
         action: ClientAction,
     },
@@ -28,7 +39,62 @@ enum Commands {
         /// Address to run the relay on
         #[clap(short, long)]
         address: String,
+
+        /// Where to keep the events the relay accepts
+        #[clap(short, long, value_enum, default_value_t = StoreKind::Memory)]
+        store: StoreKind,
+
+        /// Database file for the sqlite store
+        #[clap(short, long, default_value = "cornostr.db")]
+        database: String,
+
+        /// Minimum NIP-13 proof of work difficulty of accepted events
+        #[clap(long, default_value_t = 0)]
+        min_pow: u32,
+
+        /// URL clients connect to, which NIP-42 authentication events must name
+        #[clap(long)]
+        relay_url: Option<String>,
+
+        /// Only accept events and subscriptions from clients that authenticated with NIP-42
+        #[clap(long)]
+        require_auth: bool,
+
+        /// Name of the relay in its NIP-11 information document
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Description of the relay in its NIP-11 information document
+        #[clap(long)]
+        description: Option<String>,
+
+        /// Pubkey of the relay's administrator, as npub or hex
+        #[clap(long)]
+        pubkey: Option<String>,
+
+        /// Alternate contact of the relay's administrator, such as a mailto: URI
+        #[clap(long)]
+        contact: Option<String>,
+
+        /// Number of verified events to remember, so that copies skip signature verification
+        #[clap(long, default_value_t = crypto::DEFAULT_CAPACITY)]
+        verify_cache_size: usize,
     },
+    /// Generate a new keypair
+    Keygen,
+    /// Decode an npub, nsec, note, nprofile, nevent or naddr
+    Decode {
+        /// The bech32 string to decode
+        entity: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StoreKind {
+    /// Keep events in indexed memory, they are lost when the relay stops
+    Memory,
+    /// Persist events to an SQLite database
+    Sqlite,
 }
 
 #[derive(Subcommand)]
@@ -48,7 +114,19 @@ enum ClientAction {
         /// Message content to publish
         #[clap(short, long)]
         message: String,
+
+        /// NIP-13 proof of work difficulty to mine the event to, at most 256
+        #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(..=256))]
+        pow: u32,
+    },
+    /// Count the events matching a filter, without downloading them
+    Count {
+        /// JSON filter of the events to count
+        #[clap(short, long, default_value = r#"{"kinds": [1]}"#)]
+        filter: String,
     },
+    /// Print the relay's NIP-11 information document
+    Info,
 }
 
 #[tokio::main]
@@ -56,9 +134,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Client { relay, action } => {
+        Commands::Client {
+            relay,
+            secret_key,
+            action,
+        } => {
+            let keypair = match secret_key {
+                Some(secret_key) => {
+                    Keypair::from_secret_key(SECP256K1, &parse_secret_key(secret_key)?)
+                }
+                None => generate_keypair(),
+            };
             let mut client = Client::new();
-            client.generate_keypair();
+            client.set_keypair(keypair);
+            if let ClientAction::Info = action {
+                let info = client.fetch_relay_info(relay).await?;
+                println!("{}", serde_json::to_string_pretty(&info)?);
+                return Ok(());
+            }
             client.connect(relay).await?;
 
             match action {
@@ -69,19 +162,138 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     client.subscribe(subscription_id, filter).await?;
                     client.receive_events().await?;
                 }
-                ClientAction::Publish { message } => {
-                    let keypair = generate_keypair();
-                    let mut event = create_note(&keypair, message);
-                    client.publish_event(&mut event).await?;
-                    println!("Message published successfully!");
+                ClientAction::Publish { message, pow } => {
+                    let builder = EventBuilder::text_note(message);
+                    let event = if *pow > 0 {
+                        nip13::mine(builder, &keypair, *pow, &AtomicBool::new(false))?
+                    } else {
+                        builder.sign(&keypair)
+                    };
+                    client.publish_event(&event).await?;
+                    println!(
+                        "Message published successfully: {}",
+                        Nip19::Note(event.id).encode()?
+                    );
+                    println!("Signed by: {}", Nip19::PublicKey(event.pubkey).encode()?);
                 }
+                ClientAction::Count { filter } => {
+                    let count = client.count(&[serde_json::from_str(filter)?]).await?;
+                    let approximate = if count.approximate {
+                        " (approximate)"
+                    } else {
+                        ""
+                    };
+                    println!("{} events{}", count.count, approximate);
+                }
+                ClientAction::Info => unreachable!("handled before connecting"),
             }
         }
-        Commands::Relay { address } => {
-            let relay = Relay::new();
+        Commands::Relay {
+            address,
+            store,
+            database,
+            min_pow,
+            relay_url,
+            require_auth,
+            name,
+            description,
+            pubkey,
+            contact,
+            verify_cache_size,
+        } => {
+            let store: Box<dyn EventStore> = match store {
+                StoreKind::Memory => Box::new(IndexedStore::new()),
+                StoreKind::Sqlite => {
+                    Box::new(SqliteStore::open(database).map_err(|e| e as Box<dyn Error>)?)
+                }
+            };
+            let relay = Relay::with_store(store)
+                .with_config(Config {
+                    min_pow_difficulty: *min_pow,
+                    relay_url: relay_url.clone(),
+                    require_auth: *require_auth,
+                    info: RelayInformation {
+                        name: name.clone(),
+                        description: description.clone(),
+                        pubkey: pubkey.as_deref().map(parse_public_key).transpose()?,
+                        contact: contact.clone(),
+                        ..RelayInformation::default()
+                    },
+                })
+                .with_verify_cache(Arc::new(VerifyCache::new(*verify_cache_size)));
             relay.run(address).await?;
         }
+        Commands::Keygen => {
+            let keypair = generate_keypair();
+            println!("{}", Nip19::SecretKey(keypair.secret_key()).encode()?);
+            println!(
+                "{}",
+                Nip19::PublicKey(PublicKey::from_keypair(&keypair)).encode()?
+            );
+        }
+        Commands::Decode { entity } => print_entity(&entity.parse()?),
     }
 
     Ok(())
 }
+
+/// Parses a secret key given as nsec or hex.
+fn parse_secret_key(s: &str) -> Result<SecretKey, Box<dyn Error>> {
+    if s.starts_with("nsec1") {
+        match s.parse()? {
+            Nip19::SecretKey(secret_key) => Ok(secret_key),
+            _ => unreachable!("nsec always decodes to a secret key"),
+        }
+    } else {
+        Ok(s.parse()?)
+    }
+}
+
+/// Parses a public key given as npub or hex.
+fn parse_public_key(s: &str) -> Result<PublicKey, Box<dyn Error>> {
+    if s.starts_with("npub1") {
+        match s.parse()? {
+            Nip19::PublicKey(pubkey) => Ok(pubkey),
+            _ => unreachable!("npub always decodes to a public key"),
+        }
+    } else {
+        Ok(s.parse()?)
+    }
+}
+
+/// Prints the fields of a NIP-19 entity as hex, one per line.
+fn print_entity(entity: &Nip19) {
+    match entity {
+        Nip19::PublicKey(pubkey) => println!("pubkey: {}", pubkey),
+        Nip19::SecretKey(secret_key) => {
+            println!("secret key: {}", hex::encode(secret_key.secret_bytes()))
+        }
+        Nip19::Note(id) => println!("id: {}", id),
+        Nip19::Profile(profile) => {
+            println!("pubkey: {}", profile.pubkey);
+            for relay in &profile.relays {
+                println!("relay: {}", relay);
+            }
+        }
+        Nip19::Event(pointer) => {
+            println!("id: {}", pointer.id);
+            for relay in &pointer.relays {
+                println!("relay: {}", relay);
+            }
+            if let Some(author) = pointer.author {
+                println!("author: {}", author);
+            }
+            if let Some(kind) = pointer.kind {
+                println!("kind: {}", kind.0);
+            }
+        }
+        Nip19::Address(pointer) => {
+            println!("kind: {}", pointer.coordinate.kind.0);
+            println!("author: {}", pointer.coordinate.pubkey);
+            println!("identifier: {}", pointer.coordinate.identifier);
+            for relay in &pointer.relays {
+                println!("relay: {}", relay);
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use crate::event::PublicKey;
+
+/*
+## NIP-11: Relay Information Document
+
+Relays describe themselves to clients that request their websocket URL over HTTP with the
+`Accept: application/nostr+json` header:
+
+```json
+{
+  "name": <string identifying relay>,
+  "description": <string with detailed information>,
+  "pubkey": <administrative contact pubkey>,
+  "contact": <administrative alternate contact>,
+  "supported_nips": <a list of NIP numbers supported by the relay>,
+  "software": <string identifying relay software URL>,
+  "version": <string version identifier>,
+  "limitation": <limits the relay enforces>
+}
+```
+
+Every field is optional. Relays must answer cross-origin requests, so that web clients can read the document too.
+*/
+
+/// The media type clients accept to ask for the relay information document instead of a websocket.
+pub const MEDIA_TYPE: &str = "application/nostr+json";
+
+/// A relay information document.
+///
+/// Fields that are not set are left out of the document.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayInformation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Pubkey of the relay's administrator, who can be contacted with NIP-17 private messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<PublicKey>,
+    /// Another way to contact the administrator, such as a `mailto:` or `https:` URI.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contact: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub supported_nips: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub software: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limitation: Option<Limitation>,
+}
+
+/// Limits a relay enforces, so clients can stay within them instead of being refused.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limitation {
+    /// Maximum number of open subscriptions per connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_subscriptions: Option<usize>,
+    /// Maximum length of a subscription id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_subid_length: Option<usize>,
+    /// Maximum number of events returned for each filter of a subscription.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_limit: Option<usize>,
+    /// Number of events returned for each filter of a subscription that doesn't set a `limit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_limit: Option<usize>,
+    /// Minimum NIP-13 proof of work difficulty of accepted events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_pow_difficulty: Option<u32>,
+    /// Whether NIP-42 authentication is required before anything else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_required: Option<bool>,
+    /// Whether a payment is required before anything else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_required: Option<bool>,
+    /// Whether events are only accepted under some condition, such as authentication or proof of work.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restricted_writes: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let json = r#"{
+            "name": "relay.example.com",
+            "pubkey": "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453",
+            "supported_nips": [1, 11],
+            "limitation": {"max_subscriptions": 20, "auth_required": false},
+            "retention": [{"kinds": [0, 1], "time": 3600}]
+        }"#;
+        let info: RelayInformation = serde_json::from_str(json).unwrap();
+        assert_eq!(info.name.as_deref(), Some("relay.example.com"));
+        assert_eq!(info.supported_nips, vec![1, 11]);
+        let limitation = info.limitation.as_ref().unwrap();
+        assert_eq!(limitation.max_subscriptions, Some(20));
+        assert_eq!(limitation.auth_required, Some(false));
+        assert_eq!(limitation.min_pow_difficulty, None);
+
+        // Unknown fields are ignored, and unset ones left out
+        assert_eq!(
+            serde_json::to_value(&info).unwrap(),
+            serde_json::json!({
+                "name": "relay.example.com",
+                "pubkey": "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453",
+                "supported_nips": [1, 11],
+                "limitation": {"max_subscriptions": 20, "auth_required": false},
+            })
+        );
+        assert_eq!(
+            serde_json::to_string(&RelayInformation::default()).unwrap(),
+            "{}"
+        );
+    }
+}
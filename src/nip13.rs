@@ -0,0 +1,241 @@
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use secp256k1::Keypair;
+
+use crate::event::{Event, EventBuilder, EventId, PublicKey};
+
+/*
+## NIP-13: Proof of Work
+
+The difficulty of an event is the number of leading zero bits of its id. To reach a difficulty, a `nonce` tag is
+added and its value changed until the id has enough leading zero bits:
+
+```json
+["nonce", "776797", "20"]
+```
+
+The third entry commits to the target difficulty, so that an event mined for a lower target that happens to reach a
+higher difficulty can be told apart from one mined for it.
+*/
+
+/// Highest possible difficulty, that of an id made of zero bits only.
+pub const MAX_DIFFICULTY: u32 = 256;
+
+/// Why mining stopped without an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MineError {
+    /// The target is higher than [`MAX_DIFFICULTY`], so no nonce can reach it.
+    Target(u32),
+    /// Mining was cancelled before a nonce was found.
+    Cancelled,
+}
+
+impl fmt::Display for MineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MineError::Target(target) => write!(
+                f,
+                "target difficulty {} is more than {}",
+                target, MAX_DIFFICULTY
+            ),
+            MineError::Cancelled => write!(f, "mining was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for MineError {}
+
+/// Returns the number of leading zero bits of an event id.
+pub fn difficulty(id: &EventId) -> u32 {
+    let mut zeros = 0;
+    for byte in id.0 {
+        zeros += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// Returns the target difficulty committed in the event's `nonce` tag, if any.
+pub fn committed_target(event: &Event) -> Option<u32> {
+    event
+        .tags
+        .iter()
+        .find(|tag| tag.first().is_some_and(|name| name == "nonce"))
+        .and_then(|tag| tag.get(2))
+        .and_then(|target| target.parse().ok())
+}
+
+/// Checks that an event reaches the minimum difficulty, and that it was mined for it if it commits to a target.
+///
+/// Returns the reason the event doesn't otherwise.
+pub fn check(event: &Event, min_difficulty: u32) -> Result<(), String> {
+    let actual = difficulty(&event.id);
+    if actual < min_difficulty {
+        return Err(format!(
+            "difficulty {} is less than {}",
+            actual, min_difficulty
+        ));
+    }
+    match committed_target(event) {
+        Some(target) if target < min_difficulty => Err(format!(
+            "committed target difficulty {} is less than {}",
+            target, min_difficulty
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Mines a `nonce` tag until the event id reaches `target` leading zero bits, then signs the event.
+///
+/// Spreads the work over all available cores. Fails if `target` is more than [`MAX_DIFFICULTY`], or if `cancel` is
+/// set before a nonce is found.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::atomic::AtomicBool;
+/// use cornostr::crypto::generate_keypair;
+/// use cornostr::event::EventBuilder;
+/// use cornostr::nip13::{difficulty, mine};
+///
+/// let builder = EventBuilder::text_note("Hello, Nostr!");
+/// let event = mine(builder, &generate_keypair(), 8, &AtomicBool::new(false)).unwrap();
+/// assert!(difficulty(&event.id) >= 8);
+/// ```
+pub fn mine(
+    builder: EventBuilder,
+    keypair: &Keypair,
+    target: u32,
+    cancel: &AtomicBool,
+) -> Result<Event, MineError> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    mine_with_threads(builder, keypair, target, threads, cancel)
+}
+
+/// Like [`mine`], with the number of threads to use.
+pub fn mine_with_threads(
+    builder: EventBuilder,
+    keypair: &Keypair,
+    target: u32,
+    threads: usize,
+    cancel: &AtomicBool,
+) -> Result<Event, MineError> {
+    if target > MAX_DIFFICULTY {
+        return Err(MineError::Target(target));
+    }
+    let pubkey = PublicKey::from_keypair(keypair);
+    let (kind, content, mut tags, created_at) = builder.into_parts();
+    tags.retain(|tag| tag.first().is_none_or(|name| name != "nonce"));
+
+    let threads = threads.max(1) as u64;
+    let found: Mutex<Option<Vec<Vec<String>>>> = Mutex::new(None);
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for start in 0..threads {
+            let mut tags = tags.clone();
+            tags.push(vec!["nonce".to_string(), String::new(), target.to_string()]);
+            let (content, found, done) = (&content, &found, &done);
+            scope.spawn(move || {
+                // Each thread tries every `threads`-th nonce from its own start
+                let mut nonce = start;
+                let last = tags.len() - 1;
+                while !done.load(Ordering::Relaxed) && !cancel.load(Ordering::Relaxed) {
+                    // Reuse the nonce's string rather than allocating one per attempt
+                    let value = &mut tags[last][1];
+                    value.clear();
+                    write!(value, "{}", nonce).unwrap();
+                    let id = EventId::compute(&pubkey, created_at, kind, &tags, content);
+                    if difficulty(&id) >= target {
+                        done.store(true, Ordering::Relaxed);
+                        found.lock().unwrap().get_or_insert(tags);
+                        return;
+                    }
+                    nonce += threads;
+                }
+            });
+        }
+    });
+
+    let tags = found.into_inner().unwrap().ok_or(MineError::Cancelled)?;
+    Ok(EventBuilder::new(kind, content)
+        .tags(tags)
+        .created_at(created_at)
+        .sign(keypair))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{generate_keypair, verify_event};
+
+    #[test]
+    fn test_difficulty() {
+        // Example from NIP-13
+        let id: EventId = "000000000e9d97a1ab09fc381030b346cdd7a142ad57e6df0b46dc9bef6c7e2d"
+            .parse()
+            .unwrap();
+        assert_eq!(difficulty(&id), 36);
+        assert_eq!(difficulty(&EventId([0; 32])), 256);
+        assert_eq!(difficulty(&EventId([0xff; 32])), 0);
+    }
+
+    #[test]
+    fn test_mine() {
+        let keypair = generate_keypair();
+        let builder = EventBuilder::text_note("Hello, Nostr!").tag(["t", "pow"]);
+        let event = mine_with_threads(builder, &keypair, 10, 4, &AtomicBool::new(false)).unwrap();
+
+        assert!(difficulty(&event.id) >= 10);
+        assert_eq!(verify_event(&event), Ok(()));
+        assert_eq!(event.tags[0], vec!["t", "pow"]);
+        assert_eq!(event.tags[1][0], "nonce");
+        assert_eq!(committed_target(&event), Some(10));
+        assert_eq!(check(&event, 10), Ok(()));
+    }
+
+    #[test]
+    fn test_mine_cancelled() {
+        let builder = EventBuilder::text_note("Hello, Nostr!");
+        let cancel = AtomicBool::new(true);
+        assert_eq!(
+            mine(builder, &generate_keypair(), MAX_DIFFICULTY, &cancel),
+            Err(MineError::Cancelled)
+        );
+    }
+
+    #[test]
+    fn test_mine_unreachable_target() {
+        let builder = EventBuilder::text_note("Hello, Nostr!");
+        let cancel = AtomicBool::new(false);
+        assert_eq!(
+            mine(builder, &generate_keypair(), 300, &cancel),
+            Err(MineError::Target(300))
+        );
+    }
+
+    #[test]
+    fn test_check_committed_target() {
+        let id: EventId = "000000000e9d97a1ab09fc381030b346cdd7a142ad57e6df0b46dc9bef6c7e2d"
+            .parse()
+            .unwrap();
+        let event = Event {
+            id,
+            tags: vec![vec!["nonce".to_string(), "1".to_string(), "8".to_string()]],
+            ..EventBuilder::text_note("").sign(&generate_keypair())
+        };
+        assert_eq!(check(&event, 8), Ok(()));
+        assert_eq!(
+            check(&event, 20),
+            Err("committed target difficulty 8 is less than 20".to_string())
+        );
+        assert_eq!(
+            check(&event, 40),
+            Err("difficulty 36 is less than 40".to_string())
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use std::fmt;
+use std::str::FromStr;
+
+use bech32::primitives::decode::{CheckedHrpstring, CheckedHrpstringError};
+use bech32::{Bech32, Hrp};
+use secp256k1::SecretKey;
+
+use crate::event::{Coordinate, EventId, Kind, PublicKey};
+
+/*
+## NIP-19: bech32-encoded entities
+
+Keys and ids are shown to users as bech32 strings with a prefix saying what they are: `npub`, `nsec` and `note` for
+bare 32-bytes values, and `nprofile`, `nevent` and `naddr` for TLV (type-length-value) lists that add relay hints
+and other metadata. These forms are for display and sharing only, never for the wire.
+*/
+
+/// TLV type of the main value: pubkey for `nprofile`, id for `nevent` and `d` tag for `naddr`.
+const SPECIAL: u8 = 0;
+/// TLV type of a relay where the entity is likely to be found, as an ascii url.
+const RELAY: u8 = 1;
+/// TLV type of the 32-bytes pubkey of the event author.
+const AUTHOR: u8 = 2;
+/// TLV type of the event kind, as a 32-bit big-endian unsigned integer.
+const KIND: u8 = 3;
+
+/// A NIP-19 entity.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::nip19::Nip19;
+///
+/// let npub = "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg";
+/// let Nip19::PublicKey(pubkey) = npub.parse().unwrap() else {
+///     panic!("not a public key");
+/// };
+/// assert_eq!(
+///     pubkey.to_string(),
+///     "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e"
+/// );
+/// assert_eq!(Nip19::PublicKey(pubkey).encode().unwrap(), npub);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Nip19 {
+    /// `npub`
+    PublicKey(PublicKey),
+    /// `nsec`
+    SecretKey(SecretKey),
+    /// `note`
+    Note(EventId),
+    /// `nprofile`
+    Profile(Profile),
+    /// `nevent`
+    Event(EventPointer),
+    /// `naddr`
+    Address(AddressPointer),
+}
+
+/// A pubkey with relays where its events are likely to be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub pubkey: PublicKey,
+    pub relays: Vec<String>,
+}
+
+/// An event id with relays where it is likely to be found and optionally its author and kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventPointer {
+    pub id: EventId,
+    pub relays: Vec<String>,
+    pub author: Option<PublicKey>,
+    pub kind: Option<Kind>,
+}
+
+/// The coordinate of an addressable or replaceable event with relays where it is likely to be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressPointer {
+    pub coordinate: Coordinate,
+    pub relays: Vec<String>,
+}
+
+impl Nip19 {
+    /// Returns the bech32 string for the entity.
+    ///
+    /// Fails if a relay url is longer than 255 bytes or the string would be longer than bech32 allows.
+    pub fn encode(&self) -> Result<String, Nip19Error> {
+        let (prefix, data) = match self {
+            Nip19::PublicKey(pubkey) => ("npub", pubkey.serialize().to_vec()),
+            Nip19::SecretKey(secret_key) => ("nsec", secret_key.secret_bytes().to_vec()),
+            Nip19::Note(id) => ("note", id.0.to_vec()),
+            Nip19::Profile(profile) => {
+                let mut tlv = vec![];
+                push_tlv(&mut tlv, SPECIAL, &profile.pubkey.serialize())?;
+                push_relays(&mut tlv, &profile.relays)?;
+                ("nprofile", tlv)
+            }
+            Nip19::Event(pointer) => {
+                let mut tlv = vec![];
+                push_tlv(&mut tlv, SPECIAL, &pointer.id.0)?;
+                push_relays(&mut tlv, &pointer.relays)?;
+                if let Some(author) = &pointer.author {
+                    push_tlv(&mut tlv, AUTHOR, &author.serialize())?;
+                }
+                if let Some(kind) = pointer.kind {
+                    push_tlv(&mut tlv, KIND, &kind.0.to_be_bytes())?;
+                }
+                ("nevent", tlv)
+            }
+            Nip19::Address(pointer) => {
+                let coordinate = &pointer.coordinate;
+                let mut tlv = vec![];
+                push_tlv(&mut tlv, SPECIAL, coordinate.identifier.as_bytes())?;
+                push_relays(&mut tlv, &pointer.relays)?;
+                push_tlv(&mut tlv, AUTHOR, &coordinate.pubkey.serialize())?;
+                push_tlv(&mut tlv, KIND, &coordinate.kind.0.to_be_bytes())?;
+                ("naddr", tlv)
+            }
+        };
+        bech32::encode::<Bech32>(Hrp::parse_unchecked(prefix), &data)
+            .map_err(|_| Nip19Error::TooLong)
+    }
+
+    /// Decodes a bech32 string.
+    ///
+    /// Unlike NIP-19 suggests, TLVs of unknown types are reported as [`Nip19Error::UnknownTlv`] instead of being
+    /// ignored, so nothing a user pasted is silently dropped.
+    pub fn decode(s: &str) -> Result<Nip19, Nip19Error> {
+        let checked = CheckedHrpstring::new::<Bech32>(s).map_err(Nip19Error::Bech32)?;
+        let prefix = checked.hrp().to_lowercase();
+        let data: Vec<u8> = checked.byte_iter().collect();
+
+        match prefix.as_str() {
+            "npub" => Ok(Nip19::PublicKey(pubkey(&data)?)),
+            "nsec" => Ok(Nip19::SecretKey(SecretKey::from_slice(&fixed::<32>(
+                &data,
+            )?)?)),
+            "note" => Ok(Nip19::Note(EventId(fixed(&data)?))),
+            "nprofile" => {
+                let mut pubkey_tlv = None;
+                let mut relays = vec![];
+                for (t, value) in parse_tlv(&data)? {
+                    match t {
+                        SPECIAL => pubkey_tlv = pubkey_tlv.or(Some(value)),
+                        RELAY => relays.push(relay(value)?),
+                        _ => return Err(Nip19Error::UnknownTlv(t)),
+                    }
+                }
+                Ok(Nip19::Profile(Profile {
+                    pubkey: pubkey(pubkey_tlv.ok_or(Nip19Error::MissingTlv(SPECIAL))?)?,
+                    relays,
+                }))
+            }
+            "nevent" => {
+                let mut id = None;
+                let mut pointer_relays = vec![];
+                let mut author = None;
+                let mut kind = None;
+                for (t, value) in parse_tlv(&data)? {
+                    match t {
+                        SPECIAL => id = id.or(Some(value)),
+                        RELAY => pointer_relays.push(relay(value)?),
+                        AUTHOR => author = Some(pubkey(value)?),
+                        KIND => kind = Some(Kind(u32::from_be_bytes(fixed(value)?))),
+                        _ => return Err(Nip19Error::UnknownTlv(t)),
+                    }
+                }
+                Ok(Nip19::Event(EventPointer {
+                    id: EventId(fixed(id.ok_or(Nip19Error::MissingTlv(SPECIAL))?)?),
+                    relays: pointer_relays,
+                    author,
+                    kind,
+                }))
+            }
+            "naddr" => {
+                let mut identifier = None;
+                let mut pointer_relays = vec![];
+                let mut author = None;
+                let mut kind = None;
+                for (t, value) in parse_tlv(&data)? {
+                    match t {
+                        SPECIAL => identifier = identifier.or(Some(value)),
+                        RELAY => pointer_relays.push(relay(value)?),
+                        AUTHOR => author = Some(pubkey(value)?),
+                        KIND => kind = Some(Kind(u32::from_be_bytes(fixed(value)?))),
+                        _ => return Err(Nip19Error::UnknownTlv(t)),
+                    }
+                }
+                let identifier = identifier.ok_or(Nip19Error::MissingTlv(SPECIAL))?;
+                Ok(Nip19::Address(AddressPointer {
+                    coordinate: Coordinate {
+                        kind: kind.ok_or(Nip19Error::MissingTlv(KIND))?,
+                        pubkey: author.ok_or(Nip19Error::MissingTlv(AUTHOR))?,
+                        identifier: String::from_utf8(identifier.to_vec())
+                            .map_err(|_| Nip19Error::InvalidTlv(SPECIAL))?,
+                    },
+                    relays: pointer_relays,
+                }))
+            }
+            _ => Err(Nip19Error::UnknownPrefix(prefix)),
+        }
+    }
+}
+
+impl FromStr for Nip19 {
+    type Err = Nip19Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Nip19::decode(s)
+    }
+}
+
+fn push_tlv(tlv: &mut Vec<u8>, t: u8, value: &[u8]) -> Result<(), Nip19Error> {
+    let length = u8::try_from(value.len()).map_err(|_| Nip19Error::TooLong)?;
+    tlv.push(t);
+    tlv.push(length);
+    tlv.extend_from_slice(value);
+    Ok(())
+}
+
+fn push_relays(tlv: &mut Vec<u8>, relays: &[String]) -> Result<(), Nip19Error> {
+    relays
+        .iter()
+        .try_for_each(|relay| push_tlv(tlv, RELAY, relay.as_bytes()))
+}
+
+/// Splits TLV data into its types and values.
+fn parse_tlv(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, Nip19Error> {
+    let mut entries = vec![];
+    while let [t, length, rest @ ..] = data {
+        let length = *length as usize;
+        if rest.len() < length {
+            return Err(Nip19Error::InvalidTlv(*t));
+        }
+        entries.push((*t, &rest[..length]));
+        data = &rest[length..];
+    }
+    if !data.is_empty() {
+        return Err(Nip19Error::InvalidTlv(data[0]));
+    }
+    Ok(entries)
+}
+
+fn fixed<const N: usize>(data: &[u8]) -> Result<[u8; N], Nip19Error> {
+    data.try_into().map_err(|_| Nip19Error::InvalidLength {
+        expected: N,
+        found: data.len(),
+    })
+}
+
+fn pubkey(data: &[u8]) -> Result<PublicKey, Nip19Error> {
+    Ok(PublicKey(secp256k1::XOnlyPublicKey::from_slice(&fixed::<
+        32,
+    >(
+        data
+    )?)?))
+}
+
+fn relay(data: &[u8]) -> Result<String, Nip19Error> {
+    String::from_utf8(data.to_vec()).map_err(|_| Nip19Error::InvalidTlv(RELAY))
+}
+
+/// Error encoding or decoding a NIP-19 entity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Nip19Error {
+    /// The string is not valid bech32, for instance because the checksum doesn't match.
+    Bech32(CheckedHrpstringError),
+    /// The prefix is not one of the NIP-19 ones.
+    UnknownPrefix(String),
+    /// A key or id has the wrong number of bytes.
+    InvalidLength { expected: usize, found: usize },
+    /// The bytes are not a valid key.
+    Key(secp256k1::Error),
+    /// A TLV of this type is truncated or its value is malformed.
+    InvalidTlv(u8),
+    /// A TLV of this type is not defined for the entity.
+    UnknownTlv(u8),
+    /// A required TLV of this type is missing.
+    MissingTlv(u8),
+    /// A TLV value or the whole entity is too long to encode.
+    TooLong,
+}
+
+impl fmt::Display for Nip19Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Nip19Error::Bech32(e) => write!(f, "invalid bech32: {}", e),
+            Nip19Error::UnknownPrefix(prefix) => write!(f, "unknown prefix: {}", prefix),
+            Nip19Error::InvalidLength { expected, found } => {
+                write!(f, "expected {} bytes, found {}", expected, found)
+            }
+            Nip19Error::Key(e) => write!(f, "invalid key: {}", e),
+            Nip19Error::InvalidTlv(t) => write!(f, "invalid TLV of type {}", t),
+            Nip19Error::UnknownTlv(t) => write!(f, "unknown TLV type {}", t),
+            Nip19Error::MissingTlv(t) => write!(f, "missing TLV of type {}", t),
+            Nip19Error::TooLong => write!(f, "too long to encode"),
+        }
+    }
+}
+
+impl std::error::Error for Nip19Error {}
+
+impl From<secp256k1::Error> for Nip19Error {
+    fn from(e: secp256k1::Error) -> Self {
+        Nip19Error::Key(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBKEY: &str = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+
+    #[test]
+    fn test_nip19_vectors() {
+        let nsec = "nsec1vl029mgpspedva04g90vltkh6fvh240zqtv9k0t9af8935ke9laqsnlfe5";
+        let Nip19::SecretKey(secret_key) = nsec.parse().unwrap() else {
+            panic!("not a secret key");
+        };
+        assert_eq!(
+            hex::encode(secret_key.secret_bytes()),
+            "67dea2ed018072d675f5415ecfaed7d2597555e202d85b3d65ea4e58d2d92ffa"
+        );
+        assert_eq!(Nip19::SecretKey(secret_key).encode().unwrap(), nsec);
+
+        let nprofile = "nprofile1qqsrhuxx8l9ex335q7he0f09aej04zpazpl0ne2cgukyawd24mayt8gpp4mhxue69uhhytnc9e3k7mgpz4mhxue69uhkg6nzv9ejuumpv34kytnrdaksjlyr9p";
+        let profile = Nip19::Profile(Profile {
+            pubkey: PUBKEY.parse().unwrap(),
+            relays: vec![
+                "wss://r.x.com".to_string(),
+                "wss://djbas.sadkb.com".to_string(),
+            ],
+        });
+        assert_eq!(Nip19::decode(nprofile), Ok(profile.clone()));
+        assert_eq!(profile.encode().unwrap(), nprofile);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let pubkey: PublicKey = PUBKEY.parse().unwrap();
+        let id = EventId([7; 32]);
+        let entities = [
+            Nip19::PublicKey(pubkey),
+            Nip19::Note(id),
+            Nip19::Event(EventPointer {
+                id,
+                relays: vec!["wss://nos.lol".to_string()],
+                author: Some(pubkey),
+                kind: Some(Kind::TEXT_NOTE),
+            }),
+            Nip19::Event(EventPointer {
+                id,
+                relays: vec![],
+                author: None,
+                kind: None,
+            }),
+            Nip19::Address(AddressPointer {
+                coordinate: Coordinate {
+                    kind: Kind(30023),
+                    pubkey,
+                    identifier: "my-article".to_string(),
+                },
+                relays: vec!["wss://nos.lol".to_string()],
+            }),
+        ];
+        for entity in entities {
+            let encoded = entity.encode().unwrap();
+            assert_eq!(Nip19::decode(&encoded), Ok(entity), "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let npub = Nip19::PublicKey(PUBKEY.parse().unwrap()).encode().unwrap();
+        let mut bad_checksum = npub.clone();
+        let last = bad_checksum.pop().unwrap();
+        bad_checksum.push(if last == 'q' { 'p' } else { 'q' });
+        assert!(matches!(
+            Nip19::decode(&bad_checksum),
+            Err(Nip19Error::Bech32(CheckedHrpstringError::Checksum(_)))
+        ));
+
+        let encode = |prefix: &str, data: &[u8]| {
+            bech32::encode::<Bech32>(Hrp::parse(prefix).unwrap(), data).unwrap()
+        };
+        let pubkey = PUBKEY.parse::<PublicKey>().unwrap().serialize();
+
+        let mut unknown_tlv = vec![SPECIAL, 32];
+        unknown_tlv.extend_from_slice(&pubkey);
+        unknown_tlv.extend_from_slice(&[9, 1, 0]);
+        assert_eq!(
+            Nip19::decode(&encode("nprofile", &unknown_tlv)),
+            Err(Nip19Error::UnknownTlv(9))
+        );
+
+        let truncated = [SPECIAL, 32, 1, 2, 3];
+        assert_eq!(
+            Nip19::decode(&encode("nevent", &truncated)),
+            Err(Nip19Error::InvalidTlv(SPECIAL))
+        );
+
+        let mut no_kind = vec![SPECIAL, 0, AUTHOR, 32];
+        no_kind.extend_from_slice(&pubkey);
+        assert_eq!(
+            Nip19::decode(&encode("naddr", &no_kind)),
+            Err(Nip19Error::MissingTlv(KIND))
+        );
+
+        assert_eq!(
+            Nip19::decode(&encode("npub", &pubkey[..31])),
+            Err(Nip19Error::InvalidLength {
+                expected: 32,
+                found: 31
+            })
+        );
+        assert_eq!(
+            Nip19::decode(&encode("nkey", &pubkey)),
+            Err(Nip19Error::UnknownPrefix("nkey".to_string()))
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::ops::Range;
+
+use crate::nip19::Nip19;
+
+/*
+## NIP-21: `nostr:` URI scheme
+
+NIP-19 entities can be linked to as `nostr:<entity>`, for instance `nostr:npub1...`. Notes mention profiles and
+other events this way in their content (NIP-27), and clients replace the URIs with links or previews. `nsec` is
+not allowed in URIs.
+*/
+
+/// The scheme prefixing entities in URIs.
+pub const SCHEME: &str = "nostr:";
+
+/// A `nostr:` URI found in a text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Byte range of the whole URI in the text, scheme included.
+    pub span: Range<usize>,
+    /// The entity the URI points to.
+    pub entity: Nip19,
+}
+
+/// Returns the `nostr:` URIs in a text, such as the content of an event, in order.
+///
+/// URIs that don't decode to an entity, or that contain a secret key, are skipped.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::nip19::Nip19;
+/// use cornostr::nip21::references;
+///
+/// let content = "Follow nostr:npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg!";
+/// let found = references(content);
+/// assert_eq!(found.len(), 1);
+/// assert_eq!(&content[found[0].span.clone()], &content[7..content.len() - 1]);
+/// assert!(matches!(found[0].entity, Nip19::PublicKey(_)));
+/// ```
+pub fn references(text: &str) -> Vec<Reference> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while let Some(position) = text[offset..].find(SCHEME) {
+        let start = offset + position;
+        let entity_start = start + SCHEME.len();
+        let entity_end = text[entity_start..]
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .map_or(text.len(), |length| entity_start + length);
+
+        match Nip19::decode(&text[entity_start..entity_end]) {
+            Ok(Nip19::SecretKey(_)) | Err(_) => {}
+            Ok(entity) => found.push(Reference {
+                span: start..entity_end,
+                entity,
+            }),
+        }
+        offset = entity_end;
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{EventId, PublicKey};
+    use crate::nip19::EventPointer;
+
+    #[test]
+    fn test_references_with_spans() {
+        let pubkey: PublicKey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d"
+            .parse()
+            .unwrap();
+        let nevent = Nip19::Event(EventPointer {
+            id: EventId([1; 32]),
+            relays: vec!["wss://nos.lol".to_string()],
+            author: Some(pubkey),
+            kind: None,
+        });
+        let npub_uri = format!("nostr:{}", Nip19::PublicKey(pubkey).encode().unwrap());
+        let nevent_uri = format!("nostr:{}", nevent.encode().unwrap());
+        let content = format!(
+            "gm {}, did you see\n{}? nostr:npub1invalid nostr:",
+            npub_uri, nevent_uri
+        );
+
+        let found = references(&content);
+        assert_eq!(found.len(), 2);
+        assert_eq!(&content[found[0].span.clone()], npub_uri);
+        assert_eq!(found[0].entity, Nip19::PublicKey(pubkey));
+        assert_eq!(&content[found[1].span.clone()], nevent_uri);
+        assert_eq!(found[1].entity, nevent);
+    }
+
+    #[test]
+    fn test_secret_keys_are_skipped() {
+        let content = "nostr:nsec1vl029mgpspedva04g90vltkh6fvh240zqtv9k0t9af8935ke9laqsnlfe5";
+        assert!(references(content).is_empty());
+    }
+}
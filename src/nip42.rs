@@ -0,0 +1,173 @@
+use rand::rngs::OsRng;
+use rand::RngCore;
+use secp256k1::Keypair;
+
+use crate::event::{unix_time, Event, EventBuilder, Kind};
+
+/*
+## NIP-42: Authentication of clients to relays
+
+A relay sends a challenge when it wants the client to authenticate:
+
+```json
+["AUTH", <challenge-string>]
+```
+
+The client answers with a signed, ephemeral kind 22242 event, which is never stored or broadcast:
+
+```json
+["AUTH", {"kind": 22242, "tags": [["relay", "wss://relay.example.com/"], ["challenge", "challengestringhere"]], ...}]
+```
+
+The relay checks that the event is recent, that it answers the challenge of this connection and that it names this
+relay, and answers with `OK`. Messages refused for lack of authentication use the `auth-required:` prefix.
+*/
+
+/// How far the `created_at` of an authentication event may be from the relay's time, in seconds.
+pub const TIME_WINDOW: u64 = 10 * 60;
+
+/// Returns a new random challenge.
+pub fn challenge() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Creates the event answering a relay's challenge.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::generate_keypair;
+/// use cornostr::nip42::{auth_event, check};
+///
+/// let event = auth_event(&generate_keypair(), "wss://relay.example.com", "challenge");
+/// assert_eq!(check(&event, "challenge", Some("wss://relay.example.com/")), Ok(()));
+/// ```
+pub fn auth_event(keypair: &Keypair, relay_url: &str, challenge: &str) -> Event {
+    EventBuilder::new(Kind::CLIENT_AUTHENTICATION, "")
+        .tag(["relay", relay_url])
+        .tag(["challenge", challenge])
+        .sign(keypair)
+}
+
+/// Checks that an authentication event answers `challenge` for the relay at `relay_url`, any relay if `None`, and
+/// that it was created within [`TIME_WINDOW`]. The signature is not verified.
+///
+/// Returns the reason the event doesn't otherwise.
+pub fn check(event: &Event, challenge: &str, relay_url: Option<&str>) -> Result<(), String> {
+    if event.kind != u32::from(Kind::CLIENT_AUTHENTICATION) {
+        return Err(format!(
+            "kind must be {}",
+            u32::from(Kind::CLIENT_AUTHENTICATION)
+        ));
+    }
+    if unix_time().abs_diff(event.created_at) > TIME_WINDOW {
+        return Err("created_at is too far from the current time".to_string());
+    }
+    if tag_value(event, "challenge") != Some(challenge) {
+        return Err("challenge does not match".to_string());
+    }
+    match (relay_url, tag_value(event, "relay")) {
+        (_, None) => Err("missing relay tag".to_string()),
+        (Some(expected), Some(relay)) if !same_relay(expected, relay) => {
+            Err(format!("relay must be {}", expected))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns the first value of the first tag with the given name.
+fn tag_value<'a>(event: &'a Event, name: &str) -> Option<&'a str> {
+    event
+        .tags
+        .iter()
+        .find(|tag| tag.first().is_some_and(|n| n == name))
+        .and_then(|tag| tag.get(1))
+        .map(String::as_str)
+}
+
+/// Compares relay URLs, ignoring the case of the scheme and host and a trailing slash.
+fn same_relay(a: &str, b: &str) -> bool {
+    fn normalize(url: &str) -> String {
+        let url = url.trim_end_matches('/');
+        match url.split_once("://") {
+            Some((scheme, rest)) => {
+                let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+                format!(
+                    "{}://{}{}",
+                    scheme.to_lowercase(),
+                    host.to_lowercase(),
+                    path
+                )
+            }
+            None => url.to_lowercase(),
+        }
+    }
+    normalize(a) == normalize(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{generate_keypair, verify_event};
+
+    #[test]
+    fn test_auth_event() {
+        let event = auth_event(&generate_keypair(), "wss://relay.example.com/", "abc");
+        assert_eq!(event.kind, 22242);
+        assert_eq!(verify_event(&event), Ok(()));
+        assert_eq!(
+            check(&event, "abc", Some("wss://relay.example.com/")),
+            Ok(())
+        );
+        assert_eq!(
+            check(&event, "abc", Some("WSS://Relay.Example.com")),
+            Ok(())
+        );
+        assert_eq!(check(&event, "abc", None), Ok(()));
+    }
+
+    #[test]
+    fn test_check_rejects() {
+        let keypair = generate_keypair();
+        let relay = "wss://relay.example.com";
+        let event = auth_event(&keypair, relay, "abc");
+
+        assert_eq!(
+            check(&event, "abd", Some(relay)),
+            Err("challenge does not match".to_string())
+        );
+        assert_eq!(
+            check(&event, "abc", Some("wss://other.example.com")),
+            Err("relay must be wss://other.example.com".to_string())
+        );
+
+        let old = EventBuilder::new(Kind::CLIENT_AUTHENTICATION, "")
+            .tag(["relay", relay])
+            .tag(["challenge", "abc"])
+            .created_at(unix_time() - TIME_WINDOW - 60)
+            .sign(&keypair);
+        assert_eq!(
+            check(&old, "abc", Some(relay)),
+            Err("created_at is too far from the current time".to_string())
+        );
+
+        let no_relay = EventBuilder::new(Kind::CLIENT_AUTHENTICATION, "")
+            .tag(["challenge", "abc"])
+            .sign(&keypair);
+        assert_eq!(
+            check(&no_relay, "abc", None),
+            Err("missing relay tag".to_string())
+        );
+
+        let note = EventBuilder::text_note("")
+            .tag(["relay", relay])
+            .tag(["challenge", "abc"])
+            .sign(&keypair);
+        assert_eq!(
+            check(&note, "abc", Some(relay)),
+            Err("kind must be 22242".to_string())
+        );
+    }
+}
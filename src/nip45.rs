@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+/*
+## NIP-45: Event Counts
+
+Clients ask relays how many events match filters, instead of downloading them all:
+
+```json
+["COUNT", <query_id>, <filters JSON>...]
+```
+
+Relays answer with the number of matching events, which is approximate if they say so:
+
+```json
+["COUNT", <query_id>, {"count": <integer>, "approximate": <boolean>}]
+```
+
+or refuse with `CLOSED`, like a `REQ`.
+*/
+
+/// The number of events matching a `COUNT` query.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Count {
+    pub count: usize,
+    /// Whether the count is an estimate, or a lower bound when combined from several relays.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub approximate: bool,
+}
+
+impl Count {
+    /// Combines the counts of the same query from two relays.
+    ///
+    /// Relays usually share many events, so the larger count is kept rather than their sum. Unless both relays
+    /// counted the same number of events, the result is approximate: it is only known that the relays have at least
+    /// that many events together.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use cornostr::nip45::Count;
+    ///
+    /// let count = Count { count: 3, approximate: false };
+    /// assert_eq!(count.merge(count), count);
+    /// assert_eq!(
+    ///     count.merge(Count { count: 5, approximate: false }),
+    ///     Count { count: 5, approximate: true }
+    /// );
+    /// ```
+    pub fn merge(self, other: Count) -> Count {
+        Count {
+            count: self.count.max(other.count),
+            approximate: self.approximate || other.approximate || self.count != other.count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialization() {
+        let exact = Count {
+            count: 12,
+            approximate: false,
+        };
+        assert_eq!(serde_json::to_string(&exact).unwrap(), r#"{"count":12}"#);
+        assert_eq!(
+            serde_json::from_str::<Count>(r#"{"count":12}"#).unwrap(),
+            exact
+        );
+
+        let approximate = Count {
+            count: 93412452,
+            approximate: true,
+        };
+        let json = r#"{"count":93412452,"approximate":true}"#;
+        assert_eq!(serde_json::to_string(&approximate).unwrap(), json);
+        assert_eq!(serde_json::from_str::<Count>(json).unwrap(), approximate);
+    }
+
+    #[test]
+    fn test_merge() {
+        let count = |count, approximate| Count { count, approximate };
+        assert_eq!(count(3, false).merge(count(3, false)), count(3, false));
+        assert_eq!(count(3, false).merge(count(3, true)), count(3, true));
+        assert_eq!(count(2, false).merge(count(7, false)), count(7, true));
+        assert_eq!(count(7, true).merge(count(2, false)), count(7, true));
+        assert_eq!(Count::default().merge(count(0, false)), count(0, false));
+    }
+}
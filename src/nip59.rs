@@ -0,0 +1,266 @@
+use rand::rngs::OsRng;
+use rand::Rng;
+use secp256k1::Keypair;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{generate_keypair, nip44, verify_event, VerifyError};
+use crate::event::{unix_time, Event, EventBuilder, EventId, Kind, PublicKey, Tag};
+use crate::Error;
+
+/*
+## NIP-59: Gift Wrap
+
+An event is hidden from everyone but its recipient in three layers:
+
+- The **rumor** is the event itself, with an id but without a signature, so it can't be proven to anyone else if it
+  leaks.
+- The **seal** (kind 13) is the rumor NIP-44 encrypted to the recipient and signed by the author. It has no tags,
+  and its `created_at` is randomized so it doesn't reveal when the rumor was written.
+- The **gift wrap** (kind 1059) is the seal NIP-44 encrypted to the recipient and signed by a throwaway key, with a
+  `p` tag for the recipient so relays can deliver it, and a randomized `created_at` too.
+
+Relays only see a random pubkey sending something to the recipient.
+*/
+
+/// How far in the past the `created_at` of seals and gift wraps is randomized, in seconds.
+const MAX_TIMESTAMP_TWEAK: u64 = 2 * 24 * 60 * 60;
+
+/// An unsigned event, as sealed inside a gift wrap.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rumor {
+    pub id: EventId,
+    pub pubkey: PublicKey,
+    pub created_at: u64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+}
+
+impl Rumor {
+    /// Creates the event a builder would create for `author`, without signing it.
+    pub fn new(builder: EventBuilder, author: &PublicKey) -> Rumor {
+        let (kind, content, tags, created_at) = builder.into_parts();
+        Rumor {
+            id: EventId::compute(author, created_at, kind, &tags, &content),
+            pubkey: *author,
+            created_at,
+            kind,
+            tags,
+            content,
+        }
+    }
+
+    /// Returns true if the id is the hash of the rumor's other fields.
+    pub fn has_valid_id(&self) -> bool {
+        EventId::compute(
+            &self.pubkey,
+            self.created_at,
+            self.kind,
+            &self.tags,
+            &self.content,
+        ) == self.id
+    }
+}
+
+/// A rumor taken out of a gift wrap, with the author of its seal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnwrappedGift {
+    /// The author of the rumor, proven by the signature of the seal.
+    pub sender: PublicKey,
+    pub rumor: Rumor,
+}
+
+/// Seals a rumor for `recipient`, signed by the rumor's author.
+///
+/// Returns [`Error::Protocol`] if `sender` is not the author of the rumor.
+pub fn seal(rumor: &Rumor, sender: &Keypair, recipient: &PublicKey) -> Result<Event, Error> {
+    if rumor.pubkey != PublicKey::from_keypair(sender) {
+        return Err(Error::Protocol(
+            "only the author of a rumor can seal it".to_string(),
+        ));
+    }
+    let content = nip44::encrypt(sender, recipient, &serde_json::to_string(rumor)?)?;
+    Ok(EventBuilder::new(Kind::SEAL, content)
+        .created_at(random_timestamp())
+        .sign(sender))
+}
+
+/// Wraps a seal for `recipient`, signed by a key generated for this gift wrap only.
+pub fn gift_wrap(seal: &Event, recipient: &PublicKey) -> Result<Event, Error> {
+    let throwaway = generate_keypair();
+    let content = nip44::encrypt(&throwaway, recipient, &serde_json::to_string(seal)?)?;
+    Ok(EventBuilder::new(Kind::GIFT_WRAP, content)
+        .tag(Tag::PubKey {
+            pubkey: *recipient,
+            relay: None,
+        })
+        .created_at(random_timestamp())
+        .sign(&throwaway))
+}
+
+/// Seals a rumor and wraps the seal for `recipient`.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::generate_keypair;
+/// use cornostr::event::{EventBuilder, PublicKey};
+/// use cornostr::nip59::{unwrap, wrap, Rumor};
+///
+/// let alice = generate_keypair();
+/// let bob = generate_keypair();
+/// let alice_pubkey = PublicKey::from_keypair(&alice);
+///
+/// let rumor = Rumor::new(EventBuilder::text_note("Hello, Bob!"), &alice_pubkey);
+/// let gift = wrap(&rumor, &alice, &PublicKey::from_keypair(&bob)).unwrap();
+/// assert_ne!(gift.pubkey, alice_pubkey);
+///
+/// let unwrapped = unwrap(&gift, &bob).unwrap();
+/// assert_eq!(unwrapped.sender, alice_pubkey);
+/// assert_eq!(unwrapped.rumor, rumor);
+/// ```
+pub fn wrap(rumor: &Rumor, sender: &Keypair, recipient: &PublicKey) -> Result<Event, Error> {
+    gift_wrap(&seal(rumor, sender, recipient)?, recipient)
+}
+
+/// Opens a gift wrap addressed to `keypair` and its seal, and returns the rumor with its verified sender.
+///
+/// The seal's signature is verified, and the rumor must have a valid id and the same author as the seal, so that no
+/// one can seal a rumor in someone else's name.
+pub fn unwrap(gift_wrap: &Event, keypair: &Keypair) -> Result<UnwrappedGift, Error> {
+    if gift_wrap.kind != u32::from(Kind::GIFT_WRAP) {
+        return Err(Error::Protocol(format!(
+            "kind {} is not a gift wrap",
+            gift_wrap.kind
+        )));
+    }
+    let seal: Event = serde_json::from_str(&nip44::decrypt(
+        keypair,
+        &gift_wrap.pubkey,
+        &gift_wrap.content,
+    )?)?;
+    if seal.kind != u32::from(Kind::SEAL) {
+        return Err(Error::Protocol(format!(
+            "gift wrap contains a kind {} event instead of a seal",
+            seal.kind
+        )));
+    }
+    verify_event(&seal)?;
+
+    let rumor: Rumor =
+        serde_json::from_str(&nip44::decrypt(keypair, &seal.pubkey, &seal.content)?)?;
+    if rumor.pubkey != seal.pubkey {
+        return Err(Error::Protocol(
+            "rumor was sealed by someone other than its author".to_string(),
+        ));
+    }
+    if !rumor.has_valid_id() {
+        return Err(Error::Signature(VerifyError::Id));
+    }
+    Ok(UnwrappedGift {
+        sender: seal.pubkey,
+        rumor,
+    })
+}
+
+/// A time up to two days in the past, so that seals and gift wraps don't reveal when they were created.
+fn random_timestamp() -> u64 {
+    unix_time() - OsRng.gen_range(0..MAX_TIMESTAMP_TWEAK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rumor(author: &Keypair) -> Rumor {
+        Rumor::new(
+            EventBuilder::text_note("Are you going to the party tonight?"),
+            &PublicKey::from_keypair(author),
+        )
+    }
+
+    #[test]
+    fn test_wrap_layers() {
+        let alice = generate_keypair();
+        let bob = generate_keypair();
+        let bob_pubkey = PublicKey::from_keypair(&bob);
+        let rumor = rumor(&alice);
+
+        let seal = seal(&rumor, &alice, &bob_pubkey).unwrap();
+        assert_eq!(seal.kind, 13);
+        assert_eq!(seal.pubkey, rumor.pubkey);
+        assert!(seal.tags.is_empty());
+        assert!(seal.created_at <= unix_time());
+        assert!(seal.created_at > unix_time() - MAX_TIMESTAMP_TWEAK - 10);
+
+        let gift = gift_wrap(&seal, &bob_pubkey).unwrap();
+        assert_eq!(gift.kind, 1059);
+        assert_ne!(gift.pubkey, rumor.pubkey);
+        assert_eq!(gift.mentioned_pubkeys(), vec![bob_pubkey]);
+        assert_eq!(verify_event(&gift), Ok(()));
+
+        // Every gift wrap has its own throwaway key
+        assert_ne!(gift_wrap(&seal, &bob_pubkey).unwrap().pubkey, gift.pubkey);
+
+        let unwrapped = unwrap(&gift, &bob).unwrap();
+        assert_eq!(unwrapped.sender, rumor.pubkey);
+        assert_eq!(unwrapped.rumor, rumor);
+    }
+
+    #[test]
+    fn test_unwrap_for_someone_else_fails() {
+        let alice = generate_keypair();
+        let gift = wrap(
+            &rumor(&alice),
+            &alice,
+            &PublicKey::from_keypair(&generate_keypair()),
+        )
+        .unwrap();
+        assert!(matches!(
+            unwrap(&gift, &generate_keypair()),
+            Err(Error::Encryption(_))
+        ));
+    }
+
+    #[test]
+    fn test_impersonation_is_rejected() {
+        let alice = generate_keypair();
+        let mallory = generate_keypair();
+        let bob = generate_keypair();
+        let bob_pubkey = PublicKey::from_keypair(&bob);
+        let rumor = rumor(&alice);
+
+        assert!(matches!(
+            seal(&rumor, &mallory, &bob_pubkey),
+            Err(Error::Protocol(_))
+        ));
+
+        // Seal Alice's rumor by hand, as Mallory
+        let content = nip44::encrypt(
+            &mallory,
+            &bob_pubkey,
+            &serde_json::to_string(&rumor).unwrap(),
+        )
+        .unwrap();
+        let forged = EventBuilder::new(Kind::SEAL, content).sign(&mallory);
+        assert!(matches!(
+            unwrap(&gift_wrap(&forged, &bob_pubkey).unwrap(), &bob),
+            Err(Error::Protocol(_))
+        ));
+
+        // Or seal a rumor in Alice's name that she didn't write, keeping the id
+        let mut modified = rumor.clone();
+        modified.content = "Modified content".to_string();
+        let content = nip44::encrypt(
+            &alice,
+            &bob_pubkey,
+            &serde_json::to_string(&modified).unwrap(),
+        )
+        .unwrap();
+        let seal = EventBuilder::new(Kind::SEAL, content).sign(&alice);
+        assert!(matches!(
+            unwrap(&gift_wrap(&seal, &bob_pubkey).unwrap(), &bob),
+            Err(Error::Signature(VerifyError::Id))
+        ));
+    }
+}
@@ -1,7 +1,10 @@
-use crate::crypto::sign_event;
-use crate::event::{calculate_event_id, Event};
-use secp256k1::{Keypair, XOnlyPublicKey};
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::crypto::nip04;
+use crate::event::{Event, EventBuilder, Kind, Marker, PublicKey, Tag};
+use crate::nip19::{EventPointer, Nip19, Nip19Error, Profile};
+use crate::nip21::SCHEME;
+use crate::nip59::{self, Rumor};
+use crate::Error;
+use secp256k1::Keypair;
 
 /// Creates a new text note Nostr event.
 ///
@@ -17,28 +20,434 @@ use std::time::{SystemTime, UNIX_EPOCH};
 /// ```
 ///
 pub fn create_note(keypair: &Keypair, content: &str) -> Event {
-    let (xonly_pubkey, _parity) = XOnlyPublicKey::from_keypair(keypair);
-
-    let created_at = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs();
-
-    let mut event = Event {
-        id: String::new(),
-        pubkey: hex::encode(xonly_pubkey.serialize()),
-        created_at,
-        kind: 1, // Text note
-        tags: vec![],
-        content: content.to_string(),
-        sig: String::new(),
+    EventBuilder::text_note(content).sign(keypair)
+}
+
+/// Creates a text note that starts a thread.
+///
+/// The root of a thread has no `e` tags: replies point back to it with an `e` tag marked `root`.
+pub fn create_thread_root(keypair: &Keypair, content: &str) -> Event {
+    create_note(keypair, content)
+}
+
+/// Creates a text note replying to `parent`, with NIP-10 marked tags.
+///
+/// The reply has an `e` tag marked `root` for the root of the thread and, unless `parent` is the root itself, one
+/// marked `reply` for `parent`. It has `p` tags for the author of `parent` and everyone `parent` mentions.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::generate_keypair;
+/// use cornostr::post::{create_reply, create_thread_root};
+///
+/// let root = create_thread_root(&generate_keypair(), "Hello, Nostr!");
+/// let reply = create_reply(&generate_keypair(), &root, "Hello!");
+/// let nested = create_reply(&generate_keypair(), &reply, "Hi!");
+/// assert_eq!(nested.root_id(), Some(root.id));
+/// assert_eq!(nested.reply_to(), Some(reply.id));
+/// assert_eq!(nested.mentioned_pubkeys(), vec![reply.pubkey, root.pubkey]);
+/// ```
+pub fn create_reply(keypair: &Keypair, parent: &Event, content: &str) -> Event {
+    let parent_tag = |marker| Tag::Event {
+        id: parent.id,
+        relay: None,
+        marker: Some(marker),
+        pubkey: Some(parent.pubkey),
+    };
+
+    let mut builder = EventBuilder::text_note(content);
+    builder = match root_tag(parent) {
+        Some(root) => builder.tag(root).tag(parent_tag(Marker::Reply)),
+        None => builder.tag(parent_tag(Marker::Root)),
+    };
+
+    let mut pubkeys: Vec<PublicKey> = vec![parent.pubkey];
+    for pubkey in parent.mentioned_pubkeys() {
+        if !pubkeys.contains(&pubkey) {
+            pubkeys.push(pubkey);
+        }
+    }
+    for pubkey in pubkeys {
+        builder = builder.tag(Tag::PubKey {
+            pubkey,
+            relay: None,
+        });
+    }
+
+    builder.sign(keypair)
+}
+
+/// Returns the `e` tag a reply to `parent` uses for the root of the thread, keeping the relay hint and pubkey of
+/// `parent`'s own root tag. `None` if `parent` is the root.
+fn root_tag(parent: &Event) -> Option<Tag> {
+    let root_id = parent.root_id()?;
+    let root = parent.tags.iter().find(|tag| {
+        tag.first().is_some_and(|name| name == "e")
+            && tag.get(1).and_then(|id| id.parse().ok()) == Some(root_id)
+    });
+    // Read leniently like `Event::root_id`, as the tag may have empty values and not parse as `Tag::Event`
+    let value = |i: usize| {
+        root.and_then(|tag| tag.get(i))
+            .filter(|value| !value.is_empty())
     };
+    Some(Tag::Event {
+        id: root_id,
+        relay: value(2).cloned(),
+        marker: Some(Marker::Root),
+        pubkey: value(4).and_then(|pubkey| pubkey.parse().ok()),
+    })
+}
+
+/// Creates a legacy NIP-04 encrypted direct message (kind 4) to `recipient`, with a `p` tag for them.
+///
+/// Kind 4 events reveal who talks to whom and when: only use them with clients that don't support NIP-17 private
+/// messages.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::{generate_keypair, nip04};
+/// use cornostr::event::PublicKey;
+/// use cornostr::post::create_dm;
+///
+/// let alice = generate_keypair();
+/// let bob = generate_keypair();
+/// let dm = create_dm(&alice, &PublicKey::from_keypair(&bob), "Hello, Bob!");
+/// assert_eq!(dm.mentioned_pubkeys(), vec![PublicKey::from_keypair(&bob)]);
+/// assert_eq!(nip04::decrypt(&bob, &dm.pubkey, &dm.content).unwrap(), "Hello, Bob!");
+/// ```
+pub fn create_dm(keypair: &Keypair, recipient: &PublicKey, plaintext: &str) -> Event {
+    let content = nip04::encrypt(keypair, recipient, plaintext);
+    EventBuilder::new(Kind::ENCRYPTED_DIRECT_MESSAGE, content)
+        .tag(Tag::PubKey {
+            pubkey: *recipient,
+            relay: None,
+        })
+        .sign(keypair)
+}
+
+/// Creates a NIP-17 private direct message (kind 14) to `receivers`, returning the gift wraps to publish: one for
+/// each receiver and one for the sender to keep a copy.
+///
+/// Unlike [`create_dm`], relays only see throwaway keys sending gift wraps to the receivers.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::generate_keypair;
+/// use cornostr::event::PublicKey;
+/// use cornostr::nip59::unwrap;
+/// use cornostr::post::create_private_message;
+///
+/// let alice = generate_keypair();
+/// let bob = generate_keypair();
+/// let wraps = create_private_message(&alice, &[PublicKey::from_keypair(&bob)], "Hello, Bob!").unwrap();
+/// assert_eq!(wraps.len(), 2);
+///
+/// let message = unwrap(&wraps[0], &bob).unwrap();
+/// assert_eq!(message.sender, PublicKey::from_keypair(&alice));
+/// assert_eq!(message.rumor.content, "Hello, Bob!");
+/// ```
+pub fn create_private_message(
+    keypair: &Keypair,
+    receivers: &[PublicKey],
+    message: &str,
+) -> Result<Vec<Event>, Error> {
+    let builder = receivers.iter().fold(
+        EventBuilder::new(Kind::PRIVATE_DIRECT_MESSAGE, message),
+        |builder, pubkey| {
+            builder.tag(Tag::PubKey {
+                pubkey: *pubkey,
+                relay: None,
+            })
+        },
+    );
+    let sender = PublicKey::from_keypair(keypair);
+    let rumor = Rumor::new(builder, &sender);
+
+    let mut recipients = receivers.to_vec();
+    if !recipients.contains(&sender) {
+        recipients.push(sender);
+    }
+    recipients
+        .iter()
+        .map(|recipient| nip59::wrap(&rumor, keypair, recipient))
+        .collect()
+}
+
+/// Composes a text note that mentions profiles and events, following NIP-27.
+///
+/// Each mention appends a `nostr:` URI to the content and adds the tags clients use to notify and resolve the
+/// mentioned profiles and events.
+///
+/// # Example
+///
+/// ```
+/// use cornostr::crypto::generate_keypair;
+/// use cornostr::event::PublicKey;
+/// use cornostr::nip19::Profile;
+/// use cornostr::post::{create_note, Composer};
+///
+/// let note = create_note(&generate_keypair(), "Hello, Nostr!");
+/// let event = Composer::new()
+///     .text("Look what ")
+///     .mention_profile(Profile { pubkey: note.pubkey, relays: vec![] })
+///     .unwrap()
+///     .text(" wrote: ")
+///     .quote(&note, None)
+///     .unwrap()
+///     .build()
+///     .sign(&generate_keypair());
+/// assert!(event.content.starts_with("Look what nostr:npub1"));
+/// assert_eq!(event.mentioned_pubkeys(), vec![note.pubkey]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Composer {
+    content: String,
+    tags: Vec<Tag>,
+}
+
+impl Composer {
+    pub fn new() -> Self {
+        Composer::default()
+    }
+
+    /// Appends plain text.
+    pub fn text(mut self, text: &str) -> Self {
+        self.content.push_str(text);
+        self
+    }
+
+    /// Appends an `npub`, or `nprofile` if there are relay hints, and adds a `p` tag for the profile.
+    pub fn mention_profile(mut self, profile: Profile) -> Result<Self, Nip19Error> {
+        let relay = profile.relays.first().cloned();
+        let pubkey = profile.pubkey;
+        if profile.relays.is_empty() {
+            self.push_uri(&Nip19::PublicKey(pubkey))?;
+        } else {
+            self.push_uri(&Nip19::Profile(profile))?;
+        }
+        self.add_pubkey(pubkey, relay);
+        Ok(self)
+    }
+
+    /// Appends a `note`, or `nevent` if the pointer has relay hints, author or kind, and adds an `e` tag marked
+    /// `mention` and a `p` tag for the author when known.
+    pub fn mention_event(mut self, pointer: EventPointer) -> Result<Self, Nip19Error> {
+        let tag = Tag::Event {
+            id: pointer.id,
+            relay: pointer.relays.first().cloned(),
+            marker: Some(Marker::Mention),
+            pubkey: pointer.author,
+        };
+        let author = pointer.author;
+        if pointer.relays.is_empty() && pointer.author.is_none() && pointer.kind.is_none() {
+            self.push_uri(&Nip19::Note(pointer.id))?;
+        } else {
+            self.push_uri(&Nip19::Event(pointer))?;
+        }
+        self.add_tag(tag);
+        if let Some(author) = author {
+            self.add_pubkey(author, None);
+        }
+        Ok(self)
+    }
+
+    /// Appends an `nevent` for an event seen on `relay`, and adds a `q` tag (NIP-18) and a `p` tag for its author.
+    pub fn quote(mut self, event: &Event, relay: Option<&str>) -> Result<Self, Nip19Error> {
+        let relay = relay.map(str::to_string);
+        self.push_uri(&Nip19::Event(EventPointer {
+            id: event.id,
+            relays: relay.iter().cloned().collect(),
+            author: Some(event.pubkey),
+            kind: Some(Kind(event.kind)),
+        }))?;
+        self.add_tag(Tag::Quote {
+            id: event.id,
+            relay,
+            pubkey: Some(event.pubkey),
+        });
+        self.add_pubkey(event.pubkey, None);
+        Ok(self)
+    }
+
+    /// Returns a text note builder with the composed content and tags.
+    pub fn build(self) -> EventBuilder {
+        EventBuilder::text_note(self.content).tags(self.tags.into_iter().map(Vec::from))
+    }
+
+    fn push_uri(&mut self, entity: &Nip19) -> Result<(), Nip19Error> {
+        let encoded = entity.encode()?;
+        self.content.push_str(SCHEME);
+        self.content.push_str(&encoded);
+        Ok(())
+    }
+
+    fn add_tag(&mut self, tag: Tag) {
+        if !self.tags.contains(&tag) {
+            self.tags.push(tag);
+        }
+    }
+
+    /// Adds a `p` tag unless the pubkey already has one.
+    fn add_pubkey(&mut self, pubkey: PublicKey, relay: Option<String>) {
+        let tagged = self
+            .tags
+            .iter()
+            .any(|tag| matches!(tag, Tag::PubKey { pubkey: p, .. } if *p == pubkey));
+        if !tagged {
+            self.tags.push(Tag::PubKey { pubkey, relay });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_keypair;
+
+    /// The test event from `crypto::tests`, itself a reply in a thread.
+    fn parent() -> Event {
+        serde_json::from_value(serde_json::json!({
+            "id": "4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6",
+            "pubkey": "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453",
+            "created_at": 1725316278,
+            "kind": 1,
+            "tags": [
+                ["e", "f14669da001fc23052bbfa3e4124699a85dc14b3ecb65023a86ed16a317c1cc3", "", "root"],
+                ["e", "32928056b07792e9a92193720c67d3458351ea66fbc568cdc87be41a5faa92ce", "wss://nos.lol", "reply"],
+                ["p", "2f5759825226f1d57ef1652ba66114b2f938f7f5c50dc505708e5d8b31e4f3c9"]
+            ],
+            "content": "Thank you!",
+            "sig": "44b4b5e4087504f7ca44bb72cb89c119e680f459739a476023a036075e93a5219dc21380fbda14af4c5008185c1fc86a08acb433fb7097eff175cc81174a345c"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_create_reply_tags() {
+        let parent = parent();
+        let reply = create_reply(&generate_keypair(), &parent, "You're welcome!");
+
+        assert_eq!(
+            reply.tags,
+            vec![
+                vec![
+                    "e",
+                    "f14669da001fc23052bbfa3e4124699a85dc14b3ecb65023a86ed16a317c1cc3",
+                    "",
+                    "root"
+                ],
+                vec![
+                    "e",
+                    "4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6",
+                    "",
+                    "reply",
+                    "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453"
+                ],
+                vec![
+                    "p",
+                    "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453"
+                ],
+                vec![
+                    "p",
+                    "2f5759825226f1d57ef1652ba66114b2f938f7f5c50dc505708e5d8b31e4f3c9"
+                ],
+            ]
+        );
+        assert_eq!(reply.root_id(), parent.root_id());
+        assert_eq!(reply.reply_to(), Some(parent.id));
+    }
+
+    #[test]
+    fn test_create_reply_to_positional_reply() {
+        let keypair = generate_keypair();
+        let root = create_thread_root(&keypair, "root");
+        let tag = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let positional = EventBuilder::text_note("positional")
+            .tags([
+                tag(&["e", &root.id.to_string(), "wss://nos.lol", ""]),
+                tag(&["p", &root.pubkey.to_string(), ""]),
+            ])
+            .sign(&keypair);
+
+        let reply = create_reply(&keypair, &positional, "marked");
+
+        assert_eq!(
+            reply.tags[0],
+            tag(&["e", &root.id.to_string(), "wss://nos.lol", "root"])
+        );
+        assert_eq!(reply.root_id(), Some(root.id));
+        assert_eq!(reply.reply_to(), Some(positional.id));
+        assert_eq!(reply.mentioned_pubkeys(), vec![root.pubkey]);
+    }
+
+    #[test]
+    fn test_create_reply_to_root() {
+        let keypair = generate_keypair();
+        let root = create_thread_root(&keypair, "Hello, Nostr!");
+        let reply = create_reply(&keypair, &root, "Hello, me!");
+
+        assert_eq!(reply.tags.len(), 2);
+        assert_eq!(reply.tags[0][3], "root");
+        assert_eq!(reply.root_id(), Some(root.id));
+        assert_eq!(reply.reply_to(), Some(root.id));
+        assert_eq!(reply.mentioned_pubkeys(), vec![root.pubkey]);
+    }
 
-    // Calculate the event ID
-    event.id = calculate_event_id(&event);
+    #[test]
+    fn test_composer_adds_tags_for_mentions() {
+        let parent = parent();
+        let mentioned = EventPointer {
+            id: parent.root_id().unwrap(),
+            relays: vec!["wss://nos.lol".to_string()],
+            author: None,
+            kind: None,
+        };
+        let event = Composer::new()
+            .text("cc ")
+            .mention_profile(Profile {
+                pubkey: parent.pubkey,
+                relays: vec!["wss://nos.lol".to_string()],
+            })
+            .unwrap()
+            .text(", see ")
+            .mention_event(mentioned.clone())
+            .unwrap()
+            .text(" and ")
+            .quote(&parent, None)
+            .unwrap()
+            .build()
+            .sign(&generate_keypair());
 
-    // Sign the event
-    event.sig = sign_event(&event, keypair);
+        let references = crate::nip21::references(&event.content);
+        let entities: Vec<Nip19> = references.into_iter().map(|r| r.entity).collect();
+        assert_eq!(entities.len(), 3);
+        assert!(matches!(&entities[0], Nip19::Profile(p) if p.pubkey == parent.pubkey));
+        assert_eq!(entities[1], Nip19::Event(mentioned));
+        assert!(matches!(&entities[2], Nip19::Event(e) if e.id == parent.id));
 
-    event
+        let tags: Vec<Tag> = event.parsed_tags().collect();
+        assert_eq!(
+            tags,
+            vec![
+                Tag::PubKey {
+                    pubkey: parent.pubkey,
+                    relay: Some("wss://nos.lol".to_string()),
+                },
+                Tag::Event {
+                    id: parent.root_id().unwrap(),
+                    relay: Some("wss://nos.lol".to_string()),
+                    marker: Some(Marker::Mention),
+                    pubkey: None,
+                },
+                Tag::Quote {
+                    id: parent.id,
+                    relay: None,
+                    pubkey: Some(parent.pubkey),
+                },
+            ]
+        );
+    }
 }
@@ -1,68 +1,218 @@
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::io::Cursor;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
 
-use crate::event::Event;
+use crate::crypto::{VerifyCache, VerifyError};
+use crate::event::{Event, Filter, Kind, ParseError, PublicKey};
+use crate::nip11::{self, Limitation, RelayInformation};
+use crate::nip45::Count;
+use crate::store::{merge_results, EventStore, IndexedStore, Saved, StoreError};
+use crate::Error;
+use crate::{nip13, nip42};
+
+/// Maximum length of a subscription id, per NIP-01.
+const MAX_SUBSCRIPTION_ID_LENGTH: usize = 64;
+
+/// Maximum number of open subscriptions per connection.
+const MAX_SUBSCRIPTIONS: usize = 20;
+
+/// Maximum number of stored events returned for each filter of a `REQ`, which is also the limit of filters without
+/// one.
+const MAX_LIMIT: usize = 500;
+
+/// Number of messages that can wait to be written to a connection. A connection too slow to keep this many in
+/// flight loses messages instead of holding up the others.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Maximum number of waiting messages of a connection that are handled as one batch.
+const MAX_BATCH_SIZE: usize = 256;
+
+/// Maximum size of the head of the HTTP request that opens a connection.
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+/// Maximum number of headers of the HTTP request that opens a connection.
+const MAX_REQUEST_HEADERS: usize = 64;
+
+/// NIPs the relay supports, advertised in its information document unless the configuration lists others.
+const SUPPORTED_NIPS: [u32; 5] = [1, 11, 13, 42, 45];
+
+/// Kinds only served to connections authenticated as their author or one of their `p`-tagged recipients, since even
+/// encrypted, who talks to whom and when is private.
+const PRIVATE_KINDS: [Kind; 4] = [
+    Kind::ENCRYPTED_DIRECT_MESSAGE,
+    Kind::SEAL,
+    Kind::PRIVATE_DIRECT_MESSAGE,
+    Kind::GIFT_WRAP,
+];
 
 struct Client {
     tx: mpsc::Sender<Message>,
-    subscriptions: HashMap<String, ()>,
+    /// Filters of each open subscription, keyed by subscription id.
+    subscriptions: HashMap<String, Vec<Filter>>,
+    /// The NIP-42 challenge sent when the connection opened.
+    challenge: String,
+    /// The pubkey the connection authenticated as, if it answered the challenge.
+    pubkey: Option<PublicKey>,
+}
+
+impl Client {
+    fn new(tx: mpsc::Sender<Message>) -> Self {
+        Client {
+            tx,
+            subscriptions: HashMap::new(),
+            challenge: nip42::challenge(),
+            pubkey: None,
+        }
+    }
+
+    /// Queues a message without waiting, so that it can be sent while holding the relay's locks.
+    ///
+    /// Returns false if the connection's queue is full or the connection is gone. Connections too slow to keep up
+    /// are then disconnected, by removing them from the relay's clients.
+    fn send(&self, message: Value) -> bool {
+        self.tx.try_send(Message::Text(message.to_string())).is_ok()
+    }
+}
+
+/// Settings of a relay.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Minimum NIP-13 proof of work difficulty of accepted events, 0 to accept events without proof of work.
+    pub min_pow_difficulty: u32,
+    /// URL clients connect to, which NIP-42 authentication events must name. Any URL is accepted if not set.
+    pub relay_url: Option<String>,
+    /// Only accept events and subscriptions from connections that authenticated with NIP-42.
+    pub require_auth: bool,
+    /// The NIP-11 information document. `supported_nips`, `software` and `version` default to this relay's, and
+    /// the limitation block always lists the limits actually enforced.
+    pub info: RelayInformation,
 }
 
 pub struct Relay {
-    events: Arc<Mutex<Vec<Event>>>,
+    store: Arc<Mutex<Box<dyn EventStore>>>,
     clients: Arc<Mutex<HashMap<usize, Client>>>,
+    config: Arc<Config>,
+    verify_cache: Arc<VerifyCache>,
     next_client_id: AtomicUsize,
 }
 
 impl Relay {
+    /// Creates a relay that keeps events in memory only.
     pub fn new() -> Self {
+        Self::with_store(Box::new(IndexedStore::new()))
+    }
+
+    /// Creates a relay that keeps events in the given store.
+    pub fn with_store(store: Box<dyn EventStore>) -> Self {
         Relay {
-            events: Arc::new(Mutex::new(Vec::new())),
+            store: Arc::new(Mutex::new(store)),
             clients: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(Config::default()),
+            verify_cache: Arc::new(VerifyCache::default()),
             next_client_id: AtomicUsize::new(0),
         }
     }
 
-    pub async fn run(&self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    /// Replaces the default settings.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// Replaces the default cache of verified events, to share it with other relays or clients.
+    pub fn with_verify_cache(mut self, cache: Arc<VerifyCache>) -> Self {
+        self.verify_cache = cache;
+        self
+    }
+
+    /// Returns the cache of verified events, to read its statistics.
+    pub fn verify_cache(&self) -> &Arc<VerifyCache> {
+        &self.verify_cache
+    }
+
+    pub async fn run(&self, addr: &str) -> Result<(), Error> {
         let listener = TcpListener::bind(addr).await?;
         println!("Relay listening on: {}", addr);
+        self.run_on(listener).await
+    }
 
+    /// Serves the connections of a listener that is already bound, such as one on an OS-assigned port.
+    pub async fn run_on(&self, listener: TcpListener) -> Result<(), Error> {
         while let Ok((stream, _)) = listener.accept().await {
-            let ws_stream = accept_async(stream).await?;
-            let (write, read) = ws_stream.split();
-            let (tx, rx) = mpsc::channel(100);
             let client_id = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+            let clients = Arc::clone(&self.clients);
+            let store = Arc::clone(&self.store);
+            let config = Arc::clone(&self.config);
+            let cache = Arc::clone(&self.verify_cache);
 
-            self.clients.lock().await.insert(
-                client_id,
-                Client {
-                    tx: tx.clone(),
-                    subscriptions: HashMap::new(),
-                },
-            );
+            // Each connection is opened in its own task, so that a slow or broken one doesn't hold up the others
+            tokio::spawn(async move {
+                let result =
+                    Self::handle_connection(client_id, stream, clients, store, config, cache).await;
+                if let Err(e) = result {
+                    eprintln!("Connection {} failed: {}", client_id, e);
+                }
+            });
+        }
 
-            let clients = Arc::clone(&self.clients);
-            let events = Arc::clone(&self.events);
+        Ok(())
+    }
 
-            tokio::spawn(Self::client_writer(write, rx));
-            tokio::spawn(Self::client_reader(client_id, read, clients, events));
+    /// Answers the HTTP request that opens a connection: NIP-11 requests get the relay information document, and
+    /// websocket upgrades are served the relay until they close.
+    async fn handle_connection(
+        client_id: usize,
+        stream: TcpStream,
+        clients: Arc<Mutex<HashMap<usize, Client>>>,
+        store: Arc<Mutex<Box<dyn EventStore>>>,
+        config: Arc<Config>,
+        cache: Arc<VerifyCache>,
+    ) -> Result<(), Error> {
+        let (mut read, mut write) = stream.into_split();
+        let head = read_request_head(&mut read).await?;
+        let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        request
+            .parse(&head)
+            .map_err(|e| Error::Protocol(format!("invalid HTTP request: {}", e)))?;
+        if !header_lists(&request, "upgrade", "websocket") {
+            write.write_all(&http_response(&request, &config)).await?;
+            write.shutdown().await?;
+            return Ok(());
         }
 
+        // The handshake reads the request again, from the bytes already read then from the connection
+        let stream = tokio::io::join(Cursor::new(head).chain(read), write);
+        let ws_stream = accept_async(stream).await?;
+        let (write, read) = ws_stream.split();
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+        // Challenge every connection right away, so clients can authenticate before they need to
+        let client = Client::new(tx);
+        client.send(auth_message(&client.challenge));
+        clients.lock().await.insert(client_id, client);
+
+        let (closed_tx, closed_rx) = oneshot::channel();
+        tokio::spawn(Self::client_writer(write, rx, closed_tx));
+        Self::client_reader(client_id, read, closed_rx, clients, store, config, cache).await;
         Ok(())
     }
 
-    async fn client_writer(
-        mut write: futures_util::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-            Message,
-        >,
+    /// Writes the messages queued for a connection until it is closed or disconnected, then drops `closed` to stop
+    /// reading from it.
+    async fn client_writer<S: AsyncRead + AsyncWrite + Unpin>(
+        mut write: SplitSink<WebSocketStream<S>, Message>,
         mut rx: mpsc::Receiver<Message>,
+        closed: oneshot::Sender<()>,
     ) {
         while let Some(message) = rx.recv().await {
             if let Err(e) = write.send(message).await {
@@ -70,22 +220,54 @@ impl Relay {
                 break;
             }
         }
+        // Stop reading as well, whether the connection failed or the relay disconnected it for being too slow
+        drop(closed);
+        let _ = write.close().await;
     }
 
-    async fn client_reader(
+    async fn client_reader<S: AsyncRead + AsyncWrite + Unpin>(
         client_id: usize,
-        mut read: futures_util::stream::SplitStream<
-            tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-        >,
+        read: SplitStream<WebSocketStream<S>>,
+        mut closed: oneshot::Receiver<()>,
         clients: Arc<Mutex<HashMap<usize, Client>>>,
-        events: Arc<Mutex<Vec<Event>>>,
+        store: Arc<Mutex<Box<dyn EventStore>>>,
+        config: Arc<Config>,
+        cache: Arc<VerifyCache>,
     ) {
-        while let Some(Ok(message)) = read.next().await {
-            if let Ok(text) = message.into_text() {
-                if let Ok(json) = serde_json::from_str::<Value>(&text) {
-                    Self::handle_message(client_id, json, &clients, &events).await;
-                }
+        // Messages that are already waiting are handled together, so that their events are verified as one batch
+        let mut batches = read.ready_chunks(MAX_BATCH_SIZE);
+        let mut connected = true;
+        while connected {
+            let batch = tokio::select! {
+                batch = batches.next() => batch,
+                _ = &mut closed => None,
+            };
+            let Some(batch) = batch else {
+                break;
+            };
+            let mut messages = Vec::with_capacity(batch.len());
+            for message in batch {
+                let notice = match message {
+                    Ok(Message::Text(text)) => match serde_json::from_str::<Value>(&text) {
+                        Ok(json) => {
+                            messages.push(json);
+                            continue;
+                        }
+                        Err(e) => notice_message(&format!("could not parse message: {}", e)),
+                    },
+                    Ok(Message::Binary(_)) => notice_message("binary messages are not supported"),
+                    Ok(_) => continue,
+                    Err(_) => {
+                        connected = false;
+                        break;
+                    }
+                };
+                // Answer the earlier messages first to keep replies in order
+                let earlier = std::mem::take(&mut messages);
+                Self::handle_messages(client_id, earlier, &clients, &store, &config, &cache).await;
+                Self::send_to(client_id, &clients, notice).await;
             }
+            Self::handle_messages(client_id, messages, &clients, &store, &config, &cache).await;
         }
         clients.lock().await.remove(&client_id);
     }
@@ -94,35 +276,160 @@ impl Relay {
         client_id: usize,
         json: Value,
         clients: &Arc<Mutex<HashMap<usize, Client>>>,
-        events: &Arc<Mutex<Vec<Event>>>,
+        store: &Arc<Mutex<Box<dyn EventStore>>>,
+        config: &Config,
+        cache: &Arc<VerifyCache>,
     ) {
         match json[0].as_str() {
-            Some("EVENT") => Self::handle_event(json, events, clients).await,
-            Some("REQ") => Self::handle_req(client_id, json, clients).await,
+            Some("EVENT") => {
+                Self::handle_event(client_id, json, store, clients, config, cache).await
+            }
+            Some("REQ") => Self::handle_req(client_id, json, clients, store, config).await,
             Some("CLOSE") => Self::handle_close(client_id, json, clients).await,
-            _ => println!("Unknown message type"),
+            Some("COUNT") => Self::handle_count(client_id, json, clients, store, config).await,
+            Some("AUTH") => Self::handle_auth(client_id, json, clients, config, cache).await,
+            Some(other) => {
+                let notice = notice_message(&format!("unknown message type: {}", other));
+                Self::send_to(client_id, clients, notice).await;
+            }
+            None => {
+                let notice = notice_message("messages must be JSON arrays starting with a type");
+                Self::send_to(client_id, clients, notice).await;
+            }
+        }
+    }
+
+    /// Handles messages that arrived together, verifying the events of consecutive `EVENT` messages as one batch.
+    async fn handle_messages(
+        client_id: usize,
+        messages: Vec<Value>,
+        clients: &Arc<Mutex<HashMap<usize, Client>>>,
+        store: &Arc<Mutex<Box<dyn EventStore>>>,
+        config: &Config,
+        cache: &Arc<VerifyCache>,
+    ) {
+        let mut events = Vec::new();
+        for json in messages {
+            if json[0] == "EVENT" {
+                events.push(json);
+                continue;
+            }
+            if !events.is_empty() {
+                let events = std::mem::take(&mut events);
+                Self::handle_events(client_id, events, store, clients, config, cache).await;
+            }
+            Self::handle_message(client_id, json, clients, store, config, cache).await;
+        }
+        if !events.is_empty() {
+            Self::handle_events(client_id, events, store, clients, config, cache).await;
         }
     }
 
     async fn handle_event(
+        client_id: usize,
         json: Value,
-        events: &Arc<Mutex<Vec<Event>>>,
+        store: &Arc<Mutex<Box<dyn EventStore>>>,
         clients: &Arc<Mutex<HashMap<usize, Client>>>,
+        config: &Config,
+        cache: &Arc<VerifyCache>,
     ) {
-        if let Ok(event) = serde_json::from_value::<Event>(json[1].clone()) {
-            events.lock().await.push(event.clone());
-            let clients = clients.lock().await;
-            for client in clients.values() {
-                for subscription_id in client.subscriptions.keys() {
-                    if event_matches_subscription(&event, subscription_id) {
-                        let message = serde_json::json!(["EVENT", subscription_id, event]);
-                        let _ = client
-                            .tx
-                            .send(Message::Text(serde_json::to_string(&message).unwrap()))
-                            .await;
-                        break; // Send the event only once per client, even if it matches multiple subscriptions
-                    }
+        Self::handle_events(client_id, vec![json], store, clients, config, cache).await
+    }
+
+    /// Handles `EVENT` messages in order, after verifying the signatures of all their events in parallel.
+    async fn handle_events(
+        client_id: usize,
+        messages: Vec<Value>,
+        store: &Arc<Mutex<Box<dyn EventStore>>>,
+        clients: &Arc<Mutex<HashMap<usize, Client>>>,
+        config: &Config,
+        cache: &Arc<VerifyCache>,
+    ) {
+        let authenticated = Self::authenticated_as(client_id, clients).await.is_some();
+        let checked: Vec<Result<Event, Value>> = messages
+            .iter()
+            .map(|json| check_event(json, config, authenticated))
+            .collect();
+        let events: Vec<Event> = checked.iter().flatten().cloned().collect();
+        // Verifying a single event is quicker than handing it to another thread
+        let mut verified = if events.len() > 1 {
+            let cache = Arc::clone(cache);
+            tokio::task::spawn_blocking(move || cache.verify_events(&events))
+                .await
+                .expect("verification never panics")
+        } else {
+            events.iter().map(|event| cache.verify(event)).collect()
+        }
+        .into_iter();
+
+        for checked in checked {
+            let event = match checked {
+                Ok(event) => event,
+                Err(message) => {
+                    Self::send_to(client_id, clients, message).await;
+                    continue;
+                }
+            };
+            match verified.next().expect("one result per checked event") {
+                Ok(()) => Self::save_event(client_id, event, store, clients).await,
+                Err(e) => {
+                    let message = status(Prefix::Invalid, &e.to_string());
+                    Self::send_to(client_id, clients, ok_message(event.id, false, &message)).await;
+                }
+            }
+        }
+    }
+
+    /// Stores a verified event, broadcasts it to the matching subscriptions and answers the sender with `OK`.
+    async fn save_event(
+        client_id: usize,
+        event: Event,
+        store: &Arc<Mutex<Box<dyn EventStore>>>,
+        clients: &Arc<Mutex<HashMap<usize, Client>>>,
+    ) {
+        // Keep the store locked while broadcasting so a concurrent REQ sees the event either in its
+        // stored events or live, never both
+        let mut store = store.lock().await;
+        match store.save(event.clone()) {
+            Ok(Saved::Stored | Saved::Ephemeral) => {}
+            Ok(Saved::Duplicate) => {
+                let message = status(Prefix::Duplicate, "already have this event");
+                Self::send_to(client_id, clients, ok_message(event.id, true, &message)).await;
+                return;
+            }
+            Ok(Saved::Outdated) => {
+                let message = status(Prefix::Duplicate, "have a newer version of this event");
+                Self::send_to(client_id, clients, ok_message(event.id, true, &message)).await;
+                return;
+            }
+            Err(e) => {
+                let message = status(Prefix::Error, &format!("could not store event: {}", e));
+                Self::send_to(client_id, clients, ok_message(event.id, false, &message)).await;
+                return;
+            }
+        }
+
+        // Subscribers too slow to take the event are disconnected rather than holding up everyone else
+        let mut clients = clients.lock().await;
+        clients.retain(|_, client| {
+            if !can_read(&event, client.pubkey.as_ref()) {
+                return true;
+            }
+            // Send the event only once per client, even if it matches multiple subscriptions
+            match client
+                .subscriptions
+                .iter()
+                .find(|(_, filters)| event_matches_subscription(&event, filters))
+            {
+                Some((subscription_id, _)) => {
+                    client.send(serde_json::json!(["EVENT", subscription_id, event]))
                 }
+                None => true,
+            }
+        });
+        if let Some(client) = clients.get(&client_id) {
+            if !client.send(ok_message(event.id, true, "")) {
+                clients.remove(&client_id);
             }
         }
     }
@@ -131,13 +438,187 @@ impl Relay {
         client_id: usize,
         json: Value,
         clients: &Arc<Mutex<HashMap<usize, Client>>>,
+        store: &Arc<Mutex<Box<dyn EventStore>>>,
+        config: &Config,
     ) {
-        if let (Some(subscription_id), Some(_filter)) = (json[1].as_str(), json[2].as_object()) {
-            let mut clients = clients.lock().await;
-            if let Some(client) = clients.get_mut(&client_id) {
-                client.subscriptions.insert(subscription_id.to_string(), ());
+        let Some((subscription_id, filters)) =
+            Self::check_query(client_id, &json, clients, config).await
+        else {
+            return;
+        };
+
+        let reader = Self::authenticated_as(client_id, clients).await;
+        let store = store.lock().await;
+        let stored = match query_readable(store.as_ref(), &limit_filters(&filters), reader.as_ref())
+        {
+            Ok(stored) => stored,
+            Err(e) => {
+                drop(store);
+                let message = status(Prefix::Error, &format!("could not query events: {}", e));
+                Self::send_to(
+                    client_id,
+                    clients,
+                    closed_message(subscription_id, &message),
+                )
+                .await;
+                return;
+            }
+        };
+
+        let mut clients = clients.lock().await;
+        let Some(client) = clients.get_mut(&client_id) else {
+            return;
+        };
+        // A new REQ with the same id replaces the previous subscription, which doesn't count against the limit
+        if !client.subscriptions.contains_key(subscription_id)
+            && client.subscriptions.len() >= MAX_SUBSCRIPTIONS
+        {
+            let message = status(
+                Prefix::Blocked,
+                &format!(
+                    "no more than {} open subscriptions allowed",
+                    MAX_SUBSCRIPTIONS
+                ),
+            );
+            if !client.send(closed_message(subscription_id, &message)) {
+                clients.remove(&client_id);
+            }
+            return;
+        }
+
+        // Stored events and EOSE are queued before the locks are released, so live events always follow them. They
+        // are queued without waiting, so that a connection that doesn't read can't hold up the others.
+        let replay: Vec<Value> = stored
+            .iter()
+            .map(|event| serde_json::json!(["EVENT", subscription_id, event]))
+            .chain([serde_json::json!(["EOSE", subscription_id])])
+            .collect();
+        if replay.len() > client.tx.capacity() {
+            let message = status(
+                Prefix::Error,
+                "too many messages waiting to be sent, try again with a lower limit",
+            );
+            if !client.send(closed_message(subscription_id, &message)) {
+                clients.remove(&client_id);
+            }
+            return;
+        }
+        client
+            .subscriptions
+            .insert(subscription_id.to_string(), filters);
+        for message in replay {
+            client.send(message);
+        }
+    }
+
+    /// Answers a `COUNT` message with the number of stored events matching its filters that the connection may read.
+    async fn handle_count(
+        client_id: usize,
+        json: Value,
+        clients: &Arc<Mutex<HashMap<usize, Client>>>,
+        store: &Arc<Mutex<Box<dyn EventStore>>>,
+        config: &Config,
+    ) {
+        let Some((query_id, filters)) = Self::check_query(client_id, &json, clients, config).await
+        else {
+            return;
+        };
+
+        let reader = Self::authenticated_as(client_id, clients).await;
+        let readable: Vec<Filter> = filters
+            .iter()
+            .flat_map(|filter| readable_filters(filter, reader.as_ref()))
+            .collect();
+        let counted = store.lock().await.count(&readable);
+        let message = match counted {
+            Ok(count) => count_message(
+                query_id,
+                Count {
+                    count,
+                    approximate: false,
+                },
+            ),
+            Err(e) => {
+                let message = status(Prefix::Error, &format!("could not count events: {}", e));
+                closed_message(query_id, &message)
             }
+        };
+        Self::send_to(client_id, clients, message).await;
+    }
+
+    /// Checks the subscription id and filters of a `REQ` or `COUNT` message, and that the connection may run it.
+    ///
+    /// Returns the subscription id and filters, or `None` after answering the message if the checks failed.
+    async fn check_query<'a>(
+        client_id: usize,
+        json: &'a Value,
+        clients: &Arc<Mutex<HashMap<usize, Client>>>,
+        config: &Config,
+    ) -> Option<(&'a str, Vec<Filter>)> {
+        let Some(subscription_id) = json[1].as_str() else {
+            let notice = notice_message(&format!(
+                "{} must have a subscription id",
+                json[0].as_str().unwrap_or_default()
+            ));
+            Self::send_to(client_id, clients, notice).await;
+            return None;
+        };
+        if subscription_id.is_empty()
+            || subscription_id.chars().count() > MAX_SUBSCRIPTION_ID_LENGTH
+        {
+            let message = status(
+                Prefix::Invalid,
+                &format!(
+                    "subscription id must be between 1 and {} characters",
+                    MAX_SUBSCRIPTION_ID_LENGTH
+                ),
+            );
+            Self::send_to(
+                client_id,
+                clients,
+                closed_message(subscription_id, &message),
+            )
+            .await;
+            return None;
+        }
+        let filters = match json.as_array().unwrap()[2..]
+            .iter()
+            .map(|filter| serde_json::from_value::<Filter>(filter.clone()))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(filters) => filters,
+            Err(e) => {
+                let message = status(Prefix::Unsupported, &e.to_string());
+                Self::send_to(
+                    client_id,
+                    clients,
+                    closed_message(subscription_id, &message),
+                )
+                .await;
+                return None;
+            }
+        };
+
+        let authenticated = Self::authenticated_as(client_id, clients).await.is_some();
+        let unauthorized = if config.require_auth && !authenticated {
+            Some("we only serve authenticated users")
+        } else if requests_private_kinds(&filters) && !authenticated {
+            Some("private events are only served to their author and recipients")
+        } else {
+            None
+        };
+        if let Some(reason) = unauthorized {
+            let message = status(Prefix::AuthRequired, reason);
+            Self::send_to(
+                client_id,
+                clients,
+                closed_message(subscription_id, &message),
+            )
+            .await;
+            return None;
         }
+
+        Some((subscription_id, filters))
     }
 
     async fn handle_close(
@@ -150,15 +631,395 @@ impl Relay {
             if let Some(client) = clients.get_mut(&client_id) {
                 client.subscriptions.remove(subscription_id);
             }
+        } else {
+            let notice = notice_message("CLOSE must have a subscription id");
+            Self::send_to(client_id, clients, notice).await;
+        }
+    }
+
+    /// Authenticates the connection with the NIP-42 event of an `AUTH` message, answering with `OK`.
+    async fn handle_auth(
+        client_id: usize,
+        json: Value,
+        clients: &Arc<Mutex<HashMap<usize, Client>>>,
+        config: &Config,
+        cache: &VerifyCache,
+    ) {
+        let event = match serde_json::from_value::<Event>(json[1].clone()) {
+            Ok(event) => event,
+            Err(e) => {
+                let notice = notice_message(&format!("could not parse AUTH event: {}", e));
+                Self::send_to(client_id, clients, notice).await;
+                return;
+            }
+        };
+
+        let Some(challenge) = clients
+            .lock()
+            .await
+            .get(&client_id)
+            .map(|client| client.challenge.clone())
+        else {
+            return;
+        };
+        // Verify without holding the lock, so that other connections aren't held up
+        let result = nip42::check(&event, &challenge, config.relay_url.as_deref())
+            .and_then(|()| cache.verify(&event).map_err(|e| e.to_string()));
+
+        let mut clients = clients.lock().await;
+        let Some(client) = clients.get_mut(&client_id) else {
+            return;
+        };
+        let message = match result {
+            Ok(()) => {
+                client.pubkey = Some(event.pubkey);
+                ok_message(event.id, true, "")
+            }
+            Err(reason) => ok_message(event.id, false, &status(Prefix::Invalid, &reason)),
+        };
+        if !client.send(message) {
+            clients.remove(&client_id);
+        }
+    }
+
+    /// Returns the pubkey the connection authenticated as, if any.
+    async fn authenticated_as(
+        client_id: usize,
+        clients: &Arc<Mutex<HashMap<usize, Client>>>,
+    ) -> Option<PublicKey> {
+        clients.lock().await.get(&client_id)?.pubkey
+    }
+
+    /// Sends a message to a single connected client, if it is still connected, disconnecting it if it is too slow to
+    /// take the message.
+    async fn send_to(
+        client_id: usize,
+        clients: &Arc<Mutex<HashMap<usize, Client>>>,
+        message: Value,
+    ) {
+        let mut clients = clients.lock().await;
+        if clients
+            .get(&client_id)
+            .is_some_and(|client| !client.send(message))
+        {
+            clients.remove(&client_id);
+        }
+    }
+}
+
+/// Machine-readable prefixes of the message in `OK` and `CLOSED` replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefix {
+    Duplicate,
+    Pow,
+    Blocked,
+    RateLimited,
+    Invalid,
+    Unsupported,
+    Error,
+    AuthRequired,
+    Restricted,
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = match self {
+            Prefix::Duplicate => "duplicate",
+            Prefix::Pow => "pow",
+            Prefix::Blocked => "blocked",
+            Prefix::RateLimited => "rate-limited",
+            Prefix::Invalid => "invalid",
+            Prefix::Unsupported => "unsupported",
+            Prefix::Error => "error",
+            Prefix::AuthRequired => "auth-required",
+            Prefix::Restricted => "restricted",
+        };
+        f.write_str(prefix)
+    }
+}
+
+/// Formats a `"<prefix>: <message>"` string for `OK` and `CLOSED` replies.
+fn status(prefix: Prefix, message: &str) -> String {
+    format!("{}: {}", prefix, message)
+}
+
+/// `["OK", <event_id>, <true|false>, <message>]`
+fn ok_message(event_id: impl std::fmt::Display, accepted: bool, message: &str) -> Value {
+    serde_json::json!(["OK", event_id.to_string(), accepted, message])
+}
+
+/// `["CLOSED", <subscription_id>, <message>]`
+fn closed_message(subscription_id: &str, message: &str) -> Value {
+    serde_json::json!(["CLOSED", subscription_id, message])
+}
+
+/// `["AUTH", <challenge>]`
+fn auth_message(challenge: &str) -> Value {
+    serde_json::json!(["AUTH", challenge])
+}
+
+/// `["COUNT", <query_id>, {"count": <integer>}]`
+fn count_message(query_id: &str, count: Count) -> Value {
+    serde_json::json!(["COUNT", query_id, count])
+}
+
+/// `["NOTICE", <message>]`
+fn notice_message(message: &str) -> Value {
+    serde_json::json!(["NOTICE", message])
+}
+
+/// Parses the event of an `EVENT` message and runs the checks that are cheaper than verifying its id and signature.
+/// The formats of the id, pubkey and signature are checked when the event is deserialized.
+///
+/// Returns the message to answer with when the event is refused.
+fn check_event(json: &Value, config: &Config, authenticated: bool) -> Result<Event, Value> {
+    let event = match serde_json::from_value::<Event>(json[1].clone()) {
+        Ok(event) => event,
+        Err(e) => {
+            // Tell keys that aren't points on the curve apart from malformed hex
+            let reason = match json[1]["pubkey"].as_str().map(PublicKey::from_str) {
+                Some(Err(ParseError::Secp256k1(_))) => VerifyError::PublicKey.to_string(),
+                _ => e.to_string(),
+            };
+            // Without an id there is nothing to answer with OK
+            return Err(match json[1]["id"].as_str() {
+                Some(id) => ok_message(id, false, &status(Prefix::Invalid, &reason)),
+                None => notice_message(&format!("could not parse event: {}", e)),
+            });
+        }
+    };
+
+    if config.require_auth && !authenticated {
+        let message = status(
+            Prefix::AuthRequired,
+            "we only accept events from authenticated users",
+        );
+        return Err(ok_message(event.id, false, &message));
+    }
+
+    // Checking the proof of work first is cheap and rejects most spam before verifying signatures
+    if let Err(reason) = nip13::check(&event, config.min_pow_difficulty) {
+        return Err(ok_message(event.id, false, &status(Prefix::Pow, &reason)));
+    }
+
+    if event.kind > 65535 {
+        let message = status(Prefix::Invalid, "kind must be between 0 and 65535");
+        return Err(ok_message(event.id, false, &message));
+    }
+    if event.kind == u32::from(Kind::CLIENT_AUTHENTICATION) {
+        let message = status(
+            Prefix::Invalid,
+            "authentication events must be sent with AUTH",
+        );
+        return Err(ok_message(event.id, false, &message));
+    }
+    Ok(event)
+}
+
+/// Filters of a subscription are interpreted as `||` conditions: the event must match at least one of them.
+fn event_matches_subscription(event: &Event, filters: &[Filter]) -> bool {
+    filters.iter().any(|filter| filter.matches(event))
+}
+
+/// Returns true if a connection authenticated as `reader`, or not authenticated if `None`, may receive the event.
+///
+/// Events of [`PRIVATE_KINDS`] are only sent to their author and `p`-tagged recipients.
+fn can_read(event: &Event, reader: Option<&PublicKey>) -> bool {
+    if !PRIVATE_KINDS.contains(&Kind::from(event.kind)) {
+        return true;
+    }
+    reader
+        .is_some_and(|reader| event.pubkey == *reader || event.mentioned_pubkeys().contains(reader))
+}
+
+/// Returns true if a filter explicitly asks for events of [`PRIVATE_KINDS`].
+fn requests_private_kinds(filters: &[Filter]) -> bool {
+    filters.iter().any(|filter| {
+        filter.kinds.as_ref().is_some_and(|kinds| {
+            kinds
+                .iter()
+                .any(|&kind| PRIVATE_KINDS.contains(&Kind::from(kind)))
+        })
+    })
+}
+
+/// Returns the filters with their `limit` capped at [`MAX_LIMIT`], which is also the limit of filters without one.
+fn limit_filters(filters: &[Filter]) -> Vec<Filter> {
+    filters
+        .iter()
+        .map(|filter| Filter {
+            limit: Some(filter.limit.map_or(MAX_LIMIT, |limit| limit.min(MAX_LIMIT))),
+            ..filter.clone()
+        })
+        .collect()
+}
+
+/// Returns a filter split into filters that only match the events a connection authenticated as `reader`, or not
+/// authenticated if `None`, may read: events of [`PRIVATE_KINDS`] only if `reader` wrote them or is tagged in them.
+///
+/// The store can then apply limits to readable events only, without loading the others.
+fn readable_filters(filter: &Filter, reader: Option<&PublicKey>) -> Vec<Filter> {
+    let (public, private): (Option<Vec<u32>>, Vec<u32>) = match &filter.kinds {
+        None => (
+            None,
+            PRIVATE_KINDS.iter().map(|&kind| u32::from(kind)).collect(),
+        ),
+        Some(kinds) => {
+            let (private, public) = kinds
+                .iter()
+                .partition(|&&kind| PRIVATE_KINDS.contains(&Kind::from(kind)));
+            (Some(public), private)
+        }
+    };
+
+    let mut filters = Vec::new();
+    match public {
+        None => filters.push(Filter {
+            excluded_kinds: private.clone(),
+            ..filter.clone()
+        }),
+        Some(kinds) if !kinds.is_empty() => filters.push(Filter {
+            kinds: Some(kinds),
+            ..filter.clone()
+        }),
+        Some(_) => {}
+    }
+    let Some(reader) = reader.filter(|_| !private.is_empty()) else {
+        return filters;
+    };
+    if filter
+        .authors
+        .as_ref()
+        .is_none_or(|authors| authors.contains(reader))
+    {
+        filters.push(Filter {
+            kinds: Some(private.clone()),
+            authors: Some(vec![*reader]),
+            ..filter.clone()
+        });
+    }
+    let reader = reader.to_string();
+    if filter
+        .tags
+        .get(&'p')
+        .is_none_or(|pubkeys| pubkeys.contains(&reader))
+    {
+        let mut tags = filter.tags.clone();
+        tags.insert('p', vec![reader]);
+        filters.push(Filter {
+            kinds: Some(private),
+            tags,
+            ..filter.clone()
+        });
+    }
+    filters
+}
+
+/// Returns the stored events matching any of the filters that a connection authenticated as `reader` may read,
+/// newest first. Each filter's limit only counts readable events.
+fn query_readable(
+    store: &dyn EventStore,
+    filters: &[Filter],
+    reader: Option<&PublicKey>,
+) -> Result<Vec<Event>, StoreError> {
+    let mut results = Vec::with_capacity(filters.len());
+    for filter in filters {
+        let mut events = store.query(&readable_filters(filter, reader))?;
+        events.truncate(filter.limit.unwrap_or(usize::MAX));
+        results.push(events);
+    }
+    Ok(merge_results(results))
+}
+
+/// Returns the relay information document, with the limits the relay enforces.
+fn information_document(config: &Config) -> RelayInformation {
+    let info = config.info.clone();
+    RelayInformation {
+        supported_nips: if info.supported_nips.is_empty() {
+            SUPPORTED_NIPS.to_vec()
+        } else {
+            info.supported_nips
+        },
+        software: info
+            .software
+            .or_else(|| Some(env!("CARGO_PKG_NAME").to_string())),
+        version: info
+            .version
+            .or_else(|| Some(env!("CARGO_PKG_VERSION").to_string())),
+        limitation: Some(Limitation {
+            max_subscriptions: Some(MAX_SUBSCRIPTIONS),
+            max_subid_length: Some(MAX_SUBSCRIPTION_ID_LENGTH),
+            max_limit: Some(MAX_LIMIT),
+            default_limit: Some(MAX_LIMIT),
+            min_pow_difficulty: Some(config.min_pow_difficulty),
+            auth_required: Some(config.require_auth),
+            payment_required: Some(false),
+            restricted_writes: Some(config.require_auth || config.min_pow_difficulty > 0),
+        }),
+        ..info
+    }
+}
+
+/// Reads from a new connection until the end of the head of its HTTP request, and returns everything read.
+async fn read_request_head(read: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, Error> {
+    let mut head = Vec::with_capacity(1024);
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_HEAD_SIZE {
+            return Err(Error::Protocol(
+                "HTTP request head is too large".to_string(),
+            ));
+        }
+        if read.read_buf(&mut head).await? == 0 {
+            return Err(Error::Protocol(
+                "connection closed before the end of the HTTP request".to_string(),
+            ));
         }
     }
+    Ok(head)
+}
+
+/// Returns true if one of the request's headers with this name lists `value`, ignoring case and parameters.
+fn header_lists(request: &httparse::Request, name: &str, value: &str) -> bool {
+    request
+        .headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case(name))
+        .filter_map(|header| std::str::from_utf8(header.value).ok())
+        .flat_map(|values| values.split(','))
+        .any(|listed| {
+            let listed = listed.split(';').next().unwrap_or_default();
+            listed.trim().eq_ignore_ascii_case(value)
+        })
 }
 
-// You need to implement this function based on your subscription filter logic
-fn event_matches_subscription(_event: &Event, _subscription_id: &str) -> bool {
-    // Implement your filter logic here
-    // For now, we'll just return true to send all events to all subscriptions
-    true
+/// Answers an HTTP request that is not a websocket upgrade, allowing cross-origin requests as NIP-11 requires.
+fn http_response(request: &httparse::Request, config: &Config) -> Vec<u8> {
+    let (status, content_type, body) = if request.method == Some("OPTIONS") {
+        ("204 No Content", "text/plain", String::new())
+    } else if header_lists(request, "accept", nip11::MEDIA_TYPE) {
+        let body = serde_json::to_string(&information_document(config))
+            .expect("the information document always serializes");
+        ("200 OK", nip11::MEDIA_TYPE, body)
+    } else {
+        let body = "Please use a Nostr client to connect.\n".to_string();
+        ("200 OK", "text/plain", body)
+    };
+    format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Headers: *\r\n\
+         Access-Control-Allow-Methods: GET, OPTIONS\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
 }
 
 impl Default for Relay {
@@ -166,3 +1027,923 @@ impl Default for Relay {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{generate_keypair, sign_event};
+    use crate::event::{EventBuilder, Signature};
+    use crate::post::create_dm;
+    use secp256k1::{schnorr, Keypair};
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    type Clients = Arc<Mutex<HashMap<usize, Client>>>;
+
+    fn memory_store() -> Arc<Mutex<Box<dyn EventStore>>> {
+        Arc::new(Mutex::new(Box::new(IndexedStore::new())))
+    }
+
+    fn cache() -> Arc<VerifyCache> {
+        Arc::new(VerifyCache::default())
+    }
+
+    fn test_event() -> Event {
+        Event {
+            content: "Thank you!".to_string(),
+            created_at: 1725316278,
+            id: "4dc5e11a899e3a0496a31955a486a74800ba6d756e40fe0ceb67e3930bcb5dc6".parse().unwrap(),
+            kind: 1,
+            pubkey: "ae8ef5576370b5cb91d262cf0d31d5ce9f5ca26c3ad2d56d5c58f6023633e453".parse().unwrap(),
+            sig: "44b4b5e4087504f7ca44bb72cb89c119e680f459739a476023a036075e93a5219dc21380fbda14af4c5008185c1fc86a08acb433fb7097eff175cc81174a345c".parse().unwrap(),
+            tags: vec![
+                vec!["e".to_string(),"f14669da001fc23052bbfa3e4124699a85dc14b3ecb65023a86ed16a317c1cc3".to_string(),"".to_string(),"root".to_string()],
+                vec!["e".to_string(),"32928056b07792e9a92193720c67d3458351ea66fbc568cdc87be41a5faa92ce".to_string(),"wss://nos.lol".to_string(),"reply".to_string()],
+                vec!["p".to_string(),"2f5759825226f1d57ef1652ba66114b2f938f7f5c50dc505708e5d8b31e4f3c9".to_string()]
+                ]
+            }
+    }
+
+    fn signed_event(keypair: &Keypair, kind: u32, created_at: u64) -> Event {
+        EventBuilder::new(kind, "Hello, Nostr!")
+            .created_at(created_at)
+            .sign(keypair)
+    }
+
+    async fn connect(clients: &Clients, client_id: usize) -> mpsc::Receiver<Message> {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        clients.lock().await.insert(client_id, Client::new(tx));
+        rx
+    }
+
+    fn recv(rx: &mut mpsc::Receiver<Message>) -> Value {
+        let message = rx.try_recv().unwrap().into_text().unwrap();
+        serde_json::from_str(&message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_event_forwarded_to_matching_subscriptions_only() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut notes = connect(&clients, 0).await;
+        let mut metadata = connect(&clients, 1).await;
+
+        let req = serde_json::json!(["REQ", "notes", {"kinds": [1]}, {"kinds": [7]}]);
+        Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+        let req = serde_json::json!(["REQ", "metadata", {"kinds": [0]}]);
+        Relay::handle_message(1, req, &clients, &store, &Config::default(), &cache()).await;
+
+        assert_eq!(recv(&mut notes), serde_json::json!(["EOSE", "notes"]));
+        assert_eq!(recv(&mut metadata), serde_json::json!(["EOSE", "metadata"]));
+
+        let event = test_event();
+        Relay::handle_message(
+            1,
+            serde_json::json!(["EVENT", event]),
+            &clients,
+            &store,
+            &Config::default(),
+            &cache(),
+        )
+        .await;
+
+        let json = recv(&mut notes);
+        assert_eq!(json[0], "EVENT");
+        assert_eq!(json[1], "notes");
+        assert_eq!(json[2]["id"], event.id.to_string());
+
+        assert_eq!(
+            recv(&mut metadata),
+            serde_json::json!(["OK", event.id, true, ""])
+        );
+        assert!(notes.try_recv().is_err());
+        assert!(metadata.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_req_with_invalid_filter_is_closed() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+
+        let req = serde_json::json!(["REQ", "sub", {"kinds": [1]}, {"#ee": ["x"]}]);
+        Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+
+        let json = recv(&mut rx);
+        assert_eq!((&json[0], &json[1]), (&"CLOSED".into(), &"sub".into()));
+        assert!(json[2].as_str().unwrap().starts_with("unsupported: "));
+        assert!(clients.lock().await[&0].subscriptions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_req_beyond_subscription_limit_is_closed() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+
+        for i in 0..MAX_SUBSCRIPTIONS {
+            let req = serde_json::json!(["REQ", i.to_string(), {}]);
+            Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+            assert_eq!(recv(&mut rx)[0], "EOSE");
+        }
+
+        // Replacing an existing subscription is still allowed
+        let req = serde_json::json!(["REQ", "0", {"kinds": [1]}]);
+        Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+        assert_eq!(recv(&mut rx)[0], "EOSE");
+
+        let req = serde_json::json!(["REQ", "one too many", {}]);
+        Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+        let json = recv(&mut rx);
+        assert_eq!(json[0], "CLOSED");
+        assert!(json[2].as_str().unwrap().starts_with("blocked: "));
+    }
+
+    #[tokio::test]
+    async fn test_event_replies_with_ok() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+        let event = test_event();
+
+        Relay::handle_message(
+            0,
+            serde_json::json!(["EVENT", event]),
+            &clients,
+            &store,
+            &Config::default(),
+            &cache(),
+        )
+        .await;
+        assert_eq!(recv(&mut rx), serde_json::json!(["OK", event.id, true, ""]));
+
+        Relay::handle_message(
+            0,
+            serde_json::json!(["EVENT", event]),
+            &clients,
+            &store,
+            &Config::default(),
+            &cache(),
+        )
+        .await;
+        assert_eq!(
+            recv(&mut rx),
+            serde_json::json!(["OK", event.id, true, "duplicate: already have this event"])
+        );
+
+        let malformed = serde_json::json!({"id": event.id, "kind": "one"});
+        Relay::handle_message(
+            0,
+            serde_json::json!(["EVENT", malformed]),
+            &clients,
+            &store,
+            &Config::default(),
+            &cache(),
+        )
+        .await;
+        let json = recv(&mut rx);
+        assert_eq!(
+            (&json[1], &json[2]),
+            (&event.id.to_string().into(), &false.into())
+        );
+        assert!(json[3].as_str().unwrap().starts_with("invalid: "));
+
+        Relay::handle_message(
+            0,
+            serde_json::json!(["EVENT", {}]),
+            &clients,
+            &store,
+            &Config::default(),
+            &cache(),
+        )
+        .await;
+        assert_eq!(recv(&mut rx)[0], "NOTICE");
+
+        assert_eq!(store.lock().await.count(&[Filter::default()]).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_events_are_rejected() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+        let req = serde_json::json!(["REQ", "sub", {}]);
+        Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+        assert_eq!(recv(&mut rx)[0], "EOSE");
+
+        let mut forged_sig = test_event();
+        forged_sig.sig = Signature(schnorr::Signature::from_slice(&[0u8; 64]).unwrap());
+
+        let mut modified_content = test_event();
+        modified_content.content = "Modified content".to_string();
+
+        let mut resigned_by_other = test_event();
+        resigned_by_other.sig = sign_event(&resigned_by_other, &generate_keypair());
+
+        let mut kind_out_of_range = test_event();
+        kind_out_of_range.kind = 65536;
+
+        for (event, reason) in [
+            (forged_sig, "invalid: signature verification failed"),
+            (
+                modified_content,
+                "invalid: event id does not match the serialized event",
+            ),
+            (resigned_by_other, "invalid: signature verification failed"),
+            (
+                kind_out_of_range,
+                "invalid: kind must be between 0 and 65535",
+            ),
+        ] {
+            Relay::handle_message(
+                0,
+                serde_json::json!(["EVENT", event]),
+                &clients,
+                &store,
+                &Config::default(),
+                &cache(),
+            )
+            .await;
+            assert_eq!(
+                recv(&mut rx),
+                serde_json::json!(["OK", event.id, false, reason])
+            );
+        }
+
+        // Malformed ids, pubkeys and signatures don't even deserialize
+        let event = serde_json::to_value(test_event()).unwrap();
+        let id = event["id"].as_str().unwrap().to_string();
+        for (field, value) in [
+            ("id", id.to_uppercase()),
+            ("pubkey", id[..62].to_string()),
+            ("sig", id.clone()),
+        ] {
+            let mut malformed = event.clone();
+            malformed[field] = value.into();
+            let expected_id = malformed["id"].clone();
+            Relay::handle_message(
+                0,
+                serde_json::json!(["EVENT", malformed]),
+                &clients,
+                &store,
+                &Config::default(),
+                &cache(),
+            )
+            .await;
+            let json = recv(&mut rx);
+            assert_eq!((&json[1], &json[2]), (&expected_id, &false.into()));
+            assert!(json[3].as_str().unwrap().starts_with("invalid: "));
+        }
+
+        // Well-formed pubkeys that aren't valid keys are told apart
+        let mut invalid_pubkey = event.clone();
+        invalid_pubkey["pubkey"] = "f".repeat(64).into();
+        Relay::handle_message(
+            0,
+            serde_json::json!(["EVENT", invalid_pubkey]),
+            &clients,
+            &store,
+            &Config::default(),
+            &cache(),
+        )
+        .await;
+        assert_eq!(
+            recv(&mut rx),
+            serde_json::json!(["OK", id, false, "invalid: pubkey is not a valid public key"])
+        );
+
+        assert_eq!(store.lock().await.count(&[Filter::default()]).unwrap(), 0);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_batched_messages_are_answered_in_order() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+
+        let keypair = generate_keypair();
+        let events: Vec<Event> = (0..40)
+            .map(|i| signed_event(&keypair, 1, 100 + i))
+            .collect();
+        let mut forged = events[5].clone();
+        forged.content = "Modified content".to_string();
+
+        let mut messages: Vec<Value> = events
+            .iter()
+            .map(|event| serde_json::json!(["EVENT", event]))
+            .collect();
+        messages.insert(10, serde_json::json!(["EVENT", forged]));
+        messages.insert(20, serde_json::json!(["REQ", "sub", {"limit": 1}]));
+        messages.insert(30, serde_json::json!(["EVENT", {}]));
+        Relay::handle_messages(0, messages, &clients, &store, &Config::default(), &cache()).await;
+
+        for (i, event) in events.iter().enumerate() {
+            match i {
+                10 => {
+                    let message = "invalid: event id does not match the serialized event";
+                    assert_eq!(recv(&mut rx), ok_message(forged.id, false, message));
+                }
+                19 => {
+                    assert_eq!(recv(&mut rx)[2]["id"], events[18].id.to_string());
+                    assert_eq!(recv(&mut rx), serde_json::json!(["EOSE", "sub"]));
+                }
+                28 => assert_eq!(recv(&mut rx)[0], "NOTICE"),
+                _ => {}
+            }
+            // Events saved after the REQ are also broadcast to its subscription
+            if i >= 19 {
+                assert_eq!(recv(&mut rx)[2]["id"], event.id.to_string());
+            }
+            assert_eq!(recv(&mut rx), ok_message(event.id, true, ""));
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(store.lock().await.count(&[Filter::default()]).unwrap(), 40);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_events_hit_the_verify_cache() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let cache = cache();
+        let mut rx = connect(&clients, 0).await;
+        let event = test_event();
+
+        for _ in 0..3 {
+            let message = serde_json::json!(["EVENT", event]);
+            Relay::handle_message(0, message, &clients, &store, &Config::default(), &cache).await;
+            assert_eq!(recv(&mut rx)[2], true);
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (2, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+        let config = Config {
+            relay_url: Some("wss://relay.example.com".to_string()),
+            require_auth: true,
+            ..Config::default()
+        };
+        let keypair = generate_keypair();
+        let challenge = clients.lock().await[&0].challenge.clone();
+
+        let req = serde_json::json!(["REQ", "sub", {}]);
+        Relay::handle_message(0, req.clone(), &clients, &store, &config, &cache()).await;
+        let json = recv(&mut rx);
+        assert_eq!(json[0], "CLOSED");
+        assert!(json[2].as_str().unwrap().starts_with("auth-required: "));
+
+        let event = signed_event(&keypair, 1, 100);
+        let message = serde_json::json!(["EVENT", event]);
+        Relay::handle_message(0, message.clone(), &clients, &store, &config, &cache()).await;
+        let json = recv(&mut rx);
+        assert_eq!(json[2], false);
+        assert!(json[3].as_str().unwrap().starts_with("auth-required: "));
+
+        for (relay, challenge, reason) in [
+            (
+                "wss://relay.example.com",
+                "other",
+                "invalid: challenge does not match",
+            ),
+            (
+                "wss://other.example.com",
+                challenge.as_str(),
+                "invalid: relay must be wss://relay.example.com",
+            ),
+        ] {
+            let auth = nip42::auth_event(&keypair, relay, challenge);
+            let message = serde_json::json!(["AUTH", auth]);
+            Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+            assert_eq!(recv(&mut rx), ok_message(auth.id, false, reason));
+        }
+        assert_eq!(clients.lock().await[&0].pubkey, None);
+
+        let auth = nip42::auth_event(&keypair, "wss://relay.example.com/", &challenge);
+        let message = serde_json::json!(["AUTH", auth]);
+        Relay::handle_message(0, message.clone(), &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(&mut rx), ok_message(auth.id, true, ""));
+        assert_eq!(
+            clients.lock().await[&0].pubkey,
+            Some(PublicKey::from_keypair(&keypair))
+        );
+
+        Relay::handle_message(0, req, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(&mut rx), serde_json::json!(["EOSE", "sub"]));
+        let message = serde_json::json!(["EVENT", event]);
+        Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(&mut rx)[0], "EVENT");
+        assert_eq!(recv(&mut rx), ok_message(event.id, true, ""));
+
+        // Authentication events are never stored or broadcast
+        let message = serde_json::json!(["EVENT", auth]);
+        Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+        let json = recv(&mut rx);
+        assert_eq!(json[2], false);
+        assert!(json[3].as_str().unwrap().starts_with("invalid: "));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_private_events_only_reach_recipients() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let config = Config::default();
+        let (alice, bob, eve) = (generate_keypair(), generate_keypair(), generate_keypair());
+        let mut rxs = Vec::new();
+        for (client_id, keypair) in [Some(&alice), Some(&bob), Some(&eve), None]
+            .into_iter()
+            .enumerate()
+        {
+            rxs.push(connect(&clients, client_id).await);
+            let mut clients = clients.lock().await;
+            clients.get_mut(&client_id).unwrap().pubkey = keypair.map(PublicKey::from_keypair);
+        }
+        let [alice_rx, bob_rx, eve_rx, anonymous_rx] = &mut rxs[..] else {
+            unreachable!()
+        };
+
+        // Asking for private kinds requires authentication, other subscriptions just don't see them
+        let req = serde_json::json!(["REQ", "dms", {"kinds": [4, 1059]}]);
+        Relay::handle_message(3, req.clone(), &clients, &store, &config, &cache()).await;
+        let json = recv(anonymous_rx);
+        assert_eq!(json[0], "CLOSED");
+        assert!(json[2].as_str().unwrap().starts_with("auth-required: "));
+        let all = serde_json::json!(["REQ", "all", {}]);
+        Relay::handle_message(3, all, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(anonymous_rx), serde_json::json!(["EOSE", "all"]));
+
+        for (client_id, rx) in [(1, &mut *bob_rx), (2, &mut *eve_rx)] {
+            Relay::handle_message(client_id, req.clone(), &clients, &store, &config, &cache())
+                .await;
+            assert_eq!(recv(rx), serde_json::json!(["EOSE", "dms"]));
+        }
+
+        let dm = create_dm(&alice, &PublicKey::from_keypair(&bob), "Hello, Bob!");
+        let message = serde_json::json!(["EVENT", dm]);
+        Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(alice_rx), ok_message(dm.id, true, ""));
+        assert_eq!(recv(bob_rx), serde_json::json!(["EVENT", "dms", dm]));
+        assert!(eve_rx.try_recv().is_err());
+        assert!(anonymous_rx.try_recv().is_err());
+
+        let note = signed_event(&alice, 1, 100);
+        let message = serde_json::json!(["EVENT", note]);
+        Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(alice_rx), ok_message(note.id, true, ""));
+        assert_eq!(
+            recv(anonymous_rx),
+            serde_json::json!(["EVENT", "all", note])
+        );
+
+        // Stored private events are only returned to their author and recipients
+        let history = serde_json::json!(["REQ", "history", {"kinds": [4]}]);
+        for (client_id, rx, visible) in [
+            (0, &mut *alice_rx, true),
+            (1, &mut *bob_rx, true),
+            (2, &mut *eve_rx, false),
+        ] {
+            Relay::handle_message(
+                client_id,
+                history.clone(),
+                &clients,
+                &store,
+                &config,
+                &cache(),
+            )
+            .await;
+            if visible {
+                assert_eq!(recv(rx), serde_json::json!(["EVENT", "history", dm]));
+            }
+            assert_eq!(recv(rx), serde_json::json!(["EOSE", "history"]));
+        }
+
+        // Recipients are found in `p` tags with an empty relay hint too
+        for client_id in [0, 1] {
+            let close = serde_json::json!(["CLOSE", "history"]);
+            Relay::handle_message(client_id, close, &clients, &store, &config, &cache()).await;
+        }
+        let bob_pubkey = PublicKey::from_keypair(&bob).to_string();
+        let dm = EventBuilder::new(Kind::ENCRYPTED_DIRECT_MESSAGE, dm.content.clone())
+            .tag(["p", bob_pubkey.as_str(), ""])
+            .sign(&alice);
+        let message = serde_json::json!(["EVENT", dm]);
+        Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(alice_rx), ok_message(dm.id, true, ""));
+        assert_eq!(recv(bob_rx), serde_json::json!(["EVENT", "dms", dm]));
+        assert!(eve_rx.try_recv().is_err());
+
+        let history = serde_json::json!(["REQ", "hinted", {"ids": [dm.id]}]);
+        Relay::handle_message(1, history, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(bob_rx), serde_json::json!(["EVENT", "hinted", dm]));
+        assert_eq!(recv(bob_rx), serde_json::json!(["EOSE", "hinted"]));
+    }
+
+    #[tokio::test]
+    async fn test_count() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let config = Config::default();
+        let mut rx = connect(&clients, 0).await;
+        let (alice, bob) = (generate_keypair(), generate_keypair());
+        let bob_pubkey = PublicKey::from_keypair(&bob);
+
+        let note = signed_event(&alice, 1, 100);
+        let dm = create_dm(&alice, &bob_pubkey, "Hello, Bob!");
+        let mut events = vec![note.clone(), signed_event(&bob, 1, 200), dm];
+        for content in ["+", "🤙"] {
+            events.push(EventBuilder::reaction(&note, content).sign(&bob));
+        }
+        for event in events {
+            let message = serde_json::json!(["EVENT", event]);
+            Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+            assert_eq!(recv(&mut rx)[2], true);
+        }
+
+        // `["COUNT", "q", <filters>...]`
+        let query = |filters: Value| {
+            let mut message = vec![serde_json::json!("COUNT"), serde_json::json!("q")];
+            message.extend(filters.as_array().unwrap().iter().cloned());
+            Value::Array(message)
+        };
+        for (filters, expected) in [
+            (serde_json::json!([{"kinds": [1]}]), 2),
+            (serde_json::json!([{"kinds": [1], "limit": 1}]), 2),
+            (serde_json::json!([{"kinds": [7], "#e": [note.id]}]), 2),
+            (
+                serde_json::json!([{"kinds": [7]}, {"authors": [bob_pubkey]}]),
+                3,
+            ),
+            // Private events the connection may not read are left out
+            (serde_json::json!([{"authors": [note.pubkey]}]), 1),
+            (
+                serde_json::json!([{"kinds": [1]}, {"authors": [note.pubkey]}]),
+                2,
+            ),
+            (serde_json::json!([{}]), 4),
+        ] {
+            Relay::handle_message(0, query(filters), &clients, &store, &config, &cache()).await;
+            assert_eq!(
+                recv(&mut rx),
+                serde_json::json!(["COUNT", "q", {"count": expected}])
+            );
+        }
+
+        let private = query(serde_json::json!([{"kinds": [4]}]));
+        Relay::handle_message(0, private.clone(), &clients, &store, &config, &cache()).await;
+        let json = recv(&mut rx);
+        assert_eq!(json[0], "CLOSED");
+        assert!(json[2].as_str().unwrap().starts_with("auth-required: "));
+
+        clients.lock().await.get_mut(&0).unwrap().pubkey = Some(bob_pubkey);
+        for (message, expected) in [
+            (private, 1),
+            (query(serde_json::json!([{"authors": [note.pubkey]}])), 2),
+            (query(serde_json::json!([{"#p": [bob_pubkey]}])), 1),
+            (query(serde_json::json!([{}])), 5),
+        ] {
+            Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+            assert_eq!(
+                recv(&mut rx),
+                serde_json::json!(["COUNT", "q", {"count": expected}])
+            );
+        }
+
+        let message = serde_json::json!(["COUNT", "", {}]);
+        Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(&mut rx)[0], "CLOSED");
+    }
+
+    #[test]
+    fn test_information_document() {
+        let info = information_document(&Config::default());
+        assert_eq!(info.supported_nips, SUPPORTED_NIPS);
+        assert_eq!(info.software.as_deref(), Some("cornostr"));
+        assert_eq!(info.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert_eq!(
+            info.limitation,
+            Some(Limitation {
+                max_subscriptions: Some(MAX_SUBSCRIPTIONS),
+                max_subid_length: Some(MAX_SUBSCRIPTION_ID_LENGTH),
+                max_limit: Some(MAX_LIMIT),
+                default_limit: Some(MAX_LIMIT),
+                min_pow_difficulty: Some(0),
+                auth_required: Some(false),
+                payment_required: Some(false),
+                restricted_writes: Some(false),
+            })
+        );
+
+        let config = Config {
+            min_pow_difficulty: 8,
+            require_auth: true,
+            info: RelayInformation {
+                name: Some("Test relay".to_string()),
+                version: Some("1.2.3".to_string()),
+                limitation: Some(Limitation::default()),
+                ..RelayInformation::default()
+            },
+            ..Config::default()
+        };
+        let info = information_document(&config);
+        assert_eq!(info.name.as_deref(), Some("Test relay"));
+        assert_eq!(info.version.as_deref(), Some("1.2.3"));
+        let limitation = info.limitation.unwrap();
+        assert_eq!(limitation.min_pow_difficulty, Some(8));
+        assert_eq!(limitation.auth_required, Some(true));
+        assert_eq!(limitation.restricted_writes, Some(true));
+    }
+
+    #[test]
+    fn test_http_response() {
+        let respond = |head: &str| {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+            let mut request = httparse::Request::new(&mut headers);
+            request.parse(head.as_bytes()).unwrap();
+            String::from_utf8(http_response(&request, &Config::default())).unwrap()
+        };
+
+        let response =
+            respond("GET / HTTP/1.1\r\nAccept: text/html, Application/Nostr+JSON; q=0.9\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: application/nostr+json\r\n"));
+        assert!(head.contains("Access-Control-Allow-Origin: *\r\n"));
+        let info: RelayInformation = serde_json::from_str(body).unwrap();
+        assert_eq!(info, information_document(&Config::default()));
+
+        let response = respond("GET / HTTP/1.1\r\nAccept: text/html\r\n\r\n");
+        assert!(response.contains("Content-Type: text/plain\r\n"));
+        let response = respond("OPTIONS / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(response.contains("Access-Control-Allow-Methods: GET, OPTIONS\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_messages_get_notice() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+
+        for message in [
+            serde_json::json!(["AUTHORIZE", "sub"]),
+            serde_json::json!({"type": "REQ"}),
+            serde_json::json!(["REQ"]),
+            serde_json::json!(["CLOSE"]),
+        ] {
+            Relay::handle_message(0, message, &clients, &store, &Config::default(), &cache()).await;
+            assert_eq!(recv(&mut rx)[0], "NOTICE");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_req_replays_stored_events_before_eose() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+
+        let keypair = generate_keypair();
+        for created_at in [100, 300, 200] {
+            let event = signed_event(&keypair, 1, created_at);
+            Relay::handle_message(
+                1,
+                serde_json::json!(["EVENT", event]),
+                &clients,
+                &store,
+                &Config::default(),
+                &cache(),
+            )
+            .await;
+        }
+
+        let req = serde_json::json!(["REQ", "sub", {"kinds": [1], "limit": 2}]);
+        Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+
+        let json = recv(&mut rx);
+        assert_eq!((&json[0], &json[1]), (&"EVENT".into(), &"sub".into()));
+        assert_eq!(json[2]["created_at"], 300);
+        assert_eq!(recv(&mut rx)[2]["created_at"], 200);
+        assert_eq!(recv(&mut rx), serde_json::json!(["EOSE", "sub"]));
+
+        let event = signed_event(&keypair, 1, 50);
+        Relay::handle_message(
+            1,
+            serde_json::json!(["EVENT", event]),
+            &clients,
+            &store,
+            &Config::default(),
+            &cache(),
+        )
+        .await;
+        assert_eq!(recv(&mut rx)[2]["created_at"], 50);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_req_limit_is_capped() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+
+        let keypair = generate_keypair();
+        for created_at in 0..MAX_LIMIT as u64 + 5 {
+            let event = signed_event(&keypair, 1, created_at);
+            store.lock().await.save(event).unwrap();
+        }
+
+        for req in [
+            serde_json::json!(["REQ", "sub", {}]),
+            serde_json::json!(["REQ", "sub", {"limit": MAX_LIMIT + 1}]),
+        ] {
+            Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+            for _ in 0..MAX_LIMIT {
+                assert_eq!(recv(&mut rx)[0], "EVENT");
+            }
+            assert_eq!(recv(&mut rx), serde_json::json!(["EOSE", "sub"]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_req_limit_only_counts_readable_events() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let (alice, bob, carol) = (generate_keypair(), generate_keypair(), generate_keypair());
+        let carol_pubkey = PublicKey::from_keypair(&carol);
+        let mut rxs = Vec::new();
+        for (client_id, keypair) in [None, Some(&bob), Some(&carol)].into_iter().enumerate() {
+            rxs.push(connect(&clients, client_id).await);
+            let mut clients = clients.lock().await;
+            clients.get_mut(&client_id).unwrap().pubkey = keypair.map(PublicKey::from_keypair);
+        }
+
+        // The newest events are more private messages to Carol than the limit
+        let note = signed_event(&alice, 1, 100);
+        store.lock().await.save(note.clone()).unwrap();
+        let mut dms = Vec::new();
+        for i in 0..5 {
+            let dm = EventBuilder::new(Kind::ENCRYPTED_DIRECT_MESSAGE, "secret")
+                .tag(["p", carol_pubkey.to_string().as_str()])
+                .created_at(200 + i)
+                .sign(&alice);
+            store.lock().await.save(dm.clone()).unwrap();
+            dms.push(dm);
+        }
+
+        let req = serde_json::json!(["REQ", "sub", {"limit": 3}]);
+        for (client_id, expected) in [
+            (0, vec![&note]),
+            (1, vec![&note]),
+            (2, vec![&dms[4], &dms[3], &dms[2]]),
+        ] {
+            let rx = &mut rxs[client_id];
+            Relay::handle_message(
+                client_id,
+                req.clone(),
+                &clients,
+                &store,
+                &Config::default(),
+                &cache(),
+            )
+            .await;
+            for event in expected {
+                assert_eq!(recv(rx), serde_json::json!(["EVENT", "sub", event]));
+            }
+            assert_eq!(recv(rx), serde_json::json!(["EOSE", "sub"]));
+        }
+
+        // Asking for private kinds among others keeps the public ones for the rest of the limit
+        let req = serde_json::json!(["REQ", "mixed", {"kinds": [1, 4], "limit": 2}]);
+        Relay::handle_message(1, req, &clients, &store, &Config::default(), &cache()).await;
+        assert_eq!(
+            recv(&mut rxs[1]),
+            serde_json::json!(["EVENT", "mixed", note])
+        );
+        assert_eq!(recv(&mut rxs[1]), serde_json::json!(["EOSE", "mixed"]));
+    }
+
+    #[tokio::test]
+    async fn test_req_does_not_wait_for_slow_connections() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+
+        let keypair = generate_keypair();
+        for created_at in [100, 200, 300] {
+            store
+                .lock()
+                .await
+                .save(signed_event(&keypair, 1, created_at))
+                .unwrap();
+        }
+
+        // The connection stopped reading and only has room for two more messages
+        let notice = notice_message("filler");
+        while clients.lock().await[&0].tx.capacity() > 2 {
+            assert!(clients.lock().await[&0].send(notice.clone()));
+        }
+
+        let req = serde_json::json!(["REQ", "sub", {}]);
+        let (config, cache) = (Config::default(), cache());
+        let handled = Relay::handle_message(0, req, &clients, &store, &config, &cache);
+        assert!(tokio::time::timeout(Duration::from_secs(1), handled)
+            .await
+            .is_ok());
+        assert!(clients.lock().await[&0].subscriptions.is_empty());
+
+        for _ in 0..CHANNEL_CAPACITY - 2 {
+            assert_eq!(recv(&mut rx), notice);
+        }
+        let json = recv(&mut rx);
+        assert_eq!((&json[0], &json[1]), (&"CLOSED".into(), &"sub".into()));
+        assert!(json[2].as_str().unwrap().starts_with("error: "));
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscribers_are_disconnected() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let config = Config::default();
+        let mut rx = connect(&clients, 0).await;
+        let _slow = connect(&clients, 1).await;
+        let mut fast = connect(&clients, 2).await;
+
+        let req = serde_json::json!(["REQ", "sub", {}]);
+        for client_id in [1, 2] {
+            Relay::handle_message(client_id, req.clone(), &clients, &store, &config, &cache())
+                .await;
+        }
+        assert_eq!(recv(&mut fast), serde_json::json!(["EOSE", "sub"]));
+        // The slow connection stopped reading, and its queue is full
+        let notice = notice_message("filler");
+        while clients.lock().await[&1].send(notice.clone()) {}
+
+        let event = signed_event(&generate_keypair(), 1, 100);
+        let message = serde_json::json!(["EVENT", event]);
+        let cache = cache();
+        let handled = Relay::handle_message(0, message, &clients, &store, &config, &cache);
+        assert!(tokio::time::timeout(Duration::from_secs(1), handled)
+            .await
+            .is_ok());
+        assert_eq!(recv(&mut rx), ok_message(event.id, true, ""));
+        assert_eq!(recv(&mut fast), serde_json::json!(["EVENT", "sub", event]));
+        assert!(!clients.lock().await.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_events_are_broadcast_but_not_stored() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+        let req = serde_json::json!(["REQ", "sub", {"kinds": [20001]}]);
+        Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+        assert_eq!(recv(&mut rx)[0], "EOSE");
+
+        let event = signed_event(&generate_keypair(), 20001, 100);
+        Relay::handle_message(
+            1,
+            serde_json::json!(["EVENT", event]),
+            &clients,
+            &store,
+            &Config::default(),
+            &cache(),
+        )
+        .await;
+        assert_eq!(recv(&mut rx)[2]["id"], event.id.to_string());
+
+        let req = serde_json::json!(["REQ", "sub", {"kinds": [20001]}]);
+        Relay::handle_message(0, req, &clients, &store, &Config::default(), &cache()).await;
+        assert_eq!(recv(&mut rx), serde_json::json!(["EOSE", "sub"]));
+    }
+
+    #[tokio::test]
+    async fn test_events_below_min_pow_are_rejected() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let store = memory_store();
+        let mut rx = connect(&clients, 0).await;
+        let config = Config {
+            min_pow_difficulty: 8,
+            ..Config::default()
+        };
+
+        let keypair = generate_keypair();
+        let mut event = signed_event(&keypair, 1, 100);
+        while nip13::difficulty(&event.id) >= 8 {
+            event = signed_event(&keypair, 1, event.created_at + 1);
+        }
+        let message = serde_json::json!(["EVENT", event]);
+        Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+        let json = recv(&mut rx);
+        assert_eq!(json[2], false);
+        assert!(json[3].as_str().unwrap().starts_with("pow: difficulty "));
+
+        let builder = EventBuilder::text_note("Hello, Nostr!");
+        let mined = nip13::mine(builder, &keypair, 8, &AtomicBool::new(false)).unwrap();
+        let message = serde_json::json!(["EVENT", mined]);
+        Relay::handle_message(0, message, &clients, &store, &config, &cache()).await;
+        assert_eq!(recv(&mut rx), ok_message(mined.id, true, ""));
+    }
+}
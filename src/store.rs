@@ -0,0 +1,367 @@
+use std::collections::HashSet;
+
+use crate::event::{Event, EventId, Filter, Kind};
+
+mod indexed;
+mod memory;
+mod sqlite;
+
+pub use indexed::IndexedStore;
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+/// Error returned by an [`EventStore`] backend.
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What [`EventStore::save`] did with an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Saved {
+    /// The event was stored, replacing any older version of it.
+    Stored,
+    /// The event is ephemeral and was not stored.
+    Ephemeral,
+    /// An event with the same id is already stored.
+    Duplicate,
+    /// A newer version of this replaceable or addressable event is already stored.
+    Outdated,
+}
+
+/// Storage backend for the events accepted by a relay.
+pub trait EventStore: Send {
+    /// Stores an event.
+    ///
+    /// Returns false if an event with the same id is already stored.
+    fn insert(&mut self, event: Event) -> Result<bool, StoreError>;
+
+    /// Returns the stored events matching any of the filters, newest first.
+    ///
+    /// Each filter's `limit` keeps only its newest `n` matches; in the case of ties the event with the lowest id
+    /// comes first.
+    fn query(&self, filters: &[Filter]) -> Result<Vec<Event>, StoreError>;
+
+    /// Deletes the event with the given id.
+    ///
+    /// Returns false if no such event was stored.
+    fn delete(&mut self, id: &EventId) -> Result<bool, StoreError>;
+
+    /// Counts the stored events matching any of the filters, ignoring `limit`.
+    fn count(&self, filters: &[Filter]) -> Result<usize, StoreError>;
+
+    /// Stores an event according to the NIP-01 kind classes.
+    ///
+    /// Ephemeral events are never stored, and replaceable and addressable events only keep their latest version.
+    fn save(&mut self, event: Event) -> Result<Saved, StoreError> {
+        let kind = Kind(event.kind);
+        if kind.is_ephemeral() {
+            return Ok(Saved::Ephemeral);
+        }
+        if !kind.is_replaceable() && !kind.is_addressable() {
+            return Ok(if self.insert(event)? {
+                Saved::Stored
+            } else {
+                Saved::Duplicate
+            });
+        }
+
+        let versions = self.query(&[Filter {
+            authors: Some(vec![event.pubkey]),
+            kinds: Some(vec![event.kind]),
+            ..Default::default()
+        }])?;
+        if versions.iter().any(|e| e.id == event.id) {
+            return Ok(Saved::Duplicate);
+        }
+        if versions.iter().any(|e| e.replaces(&event)) {
+            return Ok(Saved::Outdated);
+        }
+
+        let replaced: Vec<EventId> = versions
+            .into_iter()
+            .filter(|e| event.replaces(e))
+            .map(|e| e.id)
+            .collect();
+        if !self.insert(event)? {
+            return Ok(Saved::Duplicate);
+        }
+        for id in replaced {
+            self.delete(&id)?;
+        }
+        Ok(Saved::Stored)
+    }
+}
+
+/// Sorts events newest first, breaking ties by lowest id.
+fn sort_newest_first<E: std::borrow::Borrow<Event>>(events: &mut [E]) {
+    events.sort_by(|a, b| {
+        let (a, b) = (a.borrow(), b.borrow());
+        b.created_at
+            .cmp(&a.created_at)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+}
+
+/// Merges the per-filter results of a query into a single list without duplicates, newest first.
+pub(crate) fn merge_results<E: std::borrow::Borrow<Event>>(results: Vec<Vec<E>>) -> Vec<E> {
+    let mut seen = HashSet::new();
+    let mut merged: Vec<E> = results
+        .into_iter()
+        .flatten()
+        .filter(|e| seen.insert(e.borrow().id))
+        .collect();
+    sort_newest_first(&mut merged);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_keypair;
+    use crate::event::EventBuilder;
+
+    fn events() -> Vec<Event> {
+        let keypairs = [generate_keypair(), generate_keypair()];
+        let mut events = Vec::new();
+        for (i, keypair) in keypairs.iter().cycle().take(6).enumerate() {
+            let kind = if i % 3 == 0 { 7 } else { 1 };
+            let event = EventBuilder::new(kind, format!("event {}", i))
+                .tag(["t".to_string(), format!("topic{}", i % 2)])
+                .created_at(1000 + (i as u64 / 2))
+                .sign(keypair);
+            events.push(event);
+        }
+        events
+    }
+
+    /// Runs the same scenario against every backend so they stay interchangeable.
+    fn check_store(mut store: Box<dyn EventStore>) {
+        let events = events();
+        for event in &events {
+            assert!(store.insert(event.clone()).unwrap());
+        }
+        assert!(!store.insert(events[0].clone()).unwrap());
+
+        let all = store.query(&[Filter::default()]).unwrap();
+        assert_eq!(all.len(), events.len());
+        assert!(all
+            .windows(2)
+            .all(|w| (w[1].created_at, &w[0].id) <= (w[0].created_at, &w[1].id)));
+
+        let filters = [
+            Filter {
+                kinds: Some(vec![7]),
+                ..Default::default()
+            },
+            Filter {
+                authors: Some(vec![events[1].pubkey]),
+                limit: Some(1),
+                ..Default::default()
+            },
+        ];
+        let found = store.query(&filters).unwrap();
+        let mut ids: Vec<EventId> = found.iter().map(|e| e.id).collect();
+        ids.sort();
+        let mut expected = vec![events[0].id, events[3].id, events[5].id];
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert_eq!(store.count(&filters).unwrap(), 4);
+
+        // Excluded kinds are left out before the limit applies
+        let excluded = [Filter {
+            excluded_kinds: vec![7],
+            limit: Some(2),
+            ..Default::default()
+        }];
+        let mut ids: Vec<EventId> = store
+            .query(&excluded)
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        ids.sort();
+        let mut expected = vec![events[4].id, events[5].id];
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert_eq!(store.count(&excluded).unwrap(), 4);
+
+        let tagged: [Filter; 1] =
+            [serde_json::from_str(r##"{"#t":["topic1"],"since":1001}"##).unwrap()];
+        assert_eq!(
+            store.query(&tagged).unwrap(),
+            vec![events[5].clone(), events[3].clone()]
+        );
+
+        assert!(store.delete(&events[5].id).unwrap());
+        assert!(!store.delete(&events[5].id).unwrap());
+        assert_eq!(store.count(&tagged).unwrap(), 1);
+        assert_eq!(store.count(&[Filter::default()]).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_query_orders_ties_by_id() {
+        let event = events().remove(0);
+        let mut store = MemoryStore::new();
+        for id in [0xbb, 0xaa, 0xcc] {
+            store
+                .insert(Event {
+                    id: EventId([id; 32]),
+                    ..event.clone()
+                })
+                .unwrap();
+        }
+        store
+            .insert(Event {
+                created_at: event.created_at - 1,
+                ..event.clone()
+            })
+            .unwrap();
+
+        let filters = [
+            Filter {
+                limit: Some(2),
+                ..Default::default()
+            },
+            Filter {
+                ids: Some(vec![EventId([0xcc; 32]), event.id]),
+                ..Default::default()
+            },
+        ];
+        let ids: Vec<EventId> = store
+            .query(&filters)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                EventId([0xaa; 32]),
+                EventId([0xbb; 32]),
+                EventId([0xcc; 32]),
+                event.id
+            ]
+        );
+    }
+
+    /// Saves replaceable, addressable and ephemeral events and checks only the latest versions are kept.
+    fn check_save(mut store: Box<dyn EventStore>) {
+        let keypair = generate_keypair();
+        let event = |kind: u32, created_at: u64, d: Option<&str>| {
+            let tags = d.map(|d| vec!["d".to_string(), d.to_string()]);
+            EventBuilder::new(kind, "")
+                .tags(tags)
+                .created_at(created_at)
+                .sign(&keypair)
+        };
+        let stored = |store: &dyn EventStore, kind: u32| {
+            store
+                .query(&[Filter {
+                    kinds: Some(vec![kind]),
+                    ..Default::default()
+                }])
+                .unwrap()
+        };
+
+        let metadata = event(0, 100, None);
+        let newer_metadata = event(0, 200, None);
+        assert_eq!(store.save(metadata.clone()).unwrap(), Saved::Stored);
+        assert_eq!(store.save(metadata.clone()).unwrap(), Saved::Duplicate);
+        assert_eq!(store.save(newer_metadata.clone()).unwrap(), Saved::Stored);
+        assert_eq!(store.save(metadata).unwrap(), Saved::Outdated);
+        assert_eq!(stored(&*store, 0), vec![newer_metadata]);
+
+        let first = event(30023, 100, Some("first"));
+        let second = event(30023, 100, Some("second"));
+        let newer_first = event(30023, 200, Some("first"));
+        for event in [&first, &second, &newer_first] {
+            assert_eq!(store.save(event.clone()).unwrap(), Saved::Stored);
+        }
+        assert_eq!(stored(&*store, 30023), vec![newer_first, second]);
+
+        let notes = [event(1, 100, None), event(1, 200, None)];
+        for note in &notes {
+            assert_eq!(store.save(note.clone()).unwrap(), Saved::Stored);
+        }
+        assert_eq!(stored(&*store, 1).len(), 2);
+
+        assert_eq!(
+            store.save(event(20001, 100, None)).unwrap(),
+            Saved::Ephemeral
+        );
+        assert!(stored(&*store, 20001).is_empty());
+    }
+
+    #[test]
+    fn test_save() {
+        check_save(Box::new(MemoryStore::new()));
+        check_save(Box::new(IndexedStore::new()));
+        check_save(Box::new(SqliteStore::open_in_memory().unwrap()));
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(Box::new(MemoryStore::new()));
+    }
+
+    #[test]
+    fn test_indexed_store() {
+        check_store(Box::new(IndexedStore::new()));
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        check_store(Box::new(SqliteStore::open_in_memory().unwrap()));
+    }
+
+    #[test]
+    fn test_sqlite_store_timestamps_beyond_i64() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let keypair = generate_keypair();
+        let latest = EventBuilder::text_note("latest")
+            .created_at(i64::MAX as u64)
+            .sign(&keypair);
+        store.insert(latest.clone()).unwrap();
+        let too_late = EventBuilder::text_note("too late")
+            .created_at(i64::MAX as u64 + 1)
+            .sign(&keypair);
+        assert!(store.insert(too_late).is_err());
+
+        let bounds = |since, until| Filter {
+            since,
+            until,
+            ..Default::default()
+        };
+        assert_eq!(store.count(&[bounds(None, Some(u64::MAX))]).unwrap(), 1);
+        assert_eq!(store.count(&[bounds(Some(u64::MAX), None)]).unwrap(), 0);
+        assert_eq!(
+            store
+                .query(&[bounds(Some(i64::MAX as u64), Some(u64::MAX))])
+                .unwrap(),
+            vec![latest]
+        );
+    }
+
+    #[test]
+    fn test_sqlite_store_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.db");
+        let events = events();
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        for event in &events {
+            store.insert(event.clone()).unwrap();
+        }
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.count(&[Filter::default()]).unwrap(), events.len());
+        assert_eq!(
+            store
+                .query(&[Filter {
+                    ids: Some(vec![events[2].id]),
+                    ..Default::default()
+                }])
+                .unwrap(),
+            vec![events[2].clone()]
+        );
+    }
+}
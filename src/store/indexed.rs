@@ -0,0 +1,310 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+
+use super::{merge_results, EventStore, StoreError};
+use crate::event::{Event, EventId, Filter, PublicKey};
+
+/// Position of an event in the indexes: newest first, ties broken by lowest id.
+type Key = (Reverse<u64>, EventId);
+
+/// Keeps events in memory with indexes by id, author, kind, single-letter tag value and `created_at`.
+///
+/// Every query is answered from the most selective index for the filter, so only a small set of candidate events
+/// has to be checked against it. Nothing is persisted.
+#[derive(Debug, Default)]
+pub struct IndexedStore {
+    events: HashMap<EventId, Event>,
+    by_created_at: BTreeSet<Key>,
+    by_author: HashMap<PublicKey, BTreeSet<Key>>,
+    by_kind: HashMap<u32, BTreeSet<Key>>,
+    by_tag: HashMap<(char, String), BTreeSet<Key>>,
+}
+
+/// The index a filter is answered from.
+#[derive(Debug, PartialEq, Eq)]
+enum Plan<'a> {
+    Ids(&'a [EventId]),
+    Authors(&'a [PublicKey]),
+    Kinds(&'a [u32]),
+    Tag(char, &'a [String]),
+    CreatedAt,
+}
+
+impl IndexedStore {
+    pub fn new() -> Self {
+        IndexedStore::default()
+    }
+
+    /// Picks the index yielding the fewest candidate events for the filter.
+    ///
+    /// `ids` always wins since each id matches at most one event; falls back to scanning the `created_at` index,
+    /// bounded by `since` and `until`, when the filter has no indexed condition.
+    fn plan<'a>(&self, filter: &'a Filter) -> Plan<'a> {
+        if let Some(ids) = &filter.ids {
+            return Plan::Ids(ids);
+        }
+
+        let mut best = (Plan::CreatedAt, self.events.len());
+        if let Some(authors) = &filter.authors {
+            let size = authors.iter().map(|a| set_len(self.by_author.get(a))).sum();
+            if size <= best.1 {
+                best = (Plan::Authors(authors), size);
+            }
+        }
+        if let Some(kinds) = &filter.kinds {
+            let size = kinds.iter().map(|k| set_len(self.by_kind.get(k))).sum();
+            if size <= best.1 {
+                best = (Plan::Kinds(kinds), size);
+            }
+        }
+        for (&name, values) in &filter.tags {
+            let size = values
+                .iter()
+                .map(|v| set_len(self.by_tag.get(&(name, v.clone()))))
+                .sum();
+            if size <= best.1 {
+                best = (Plan::Tag(name, values), size);
+            }
+        }
+        best.0
+    }
+
+    /// Returns the keys of the candidate events for the filter, newest first, bounded by `since` and `until`.
+    ///
+    /// Candidates from a single index are streamed, so a `limit` stops the scan early.
+    fn candidates<'a>(&'a self, filter: &Filter) -> Box<dyn Iterator<Item = &'a Key> + 'a> {
+        let sets: Vec<&BTreeSet<Key>> = match self.plan(filter) {
+            Plan::Ids(ids) => {
+                let mut keys: Vec<&Key> = ids
+                    .iter()
+                    .filter_map(|id| self.events.get(id))
+                    .filter_map(|e| self.by_created_at.get(&key(e)))
+                    .collect();
+                keys.sort();
+                keys.dedup();
+                return Box::new(keys.into_iter());
+            }
+            Plan::Authors(authors) => authors
+                .iter()
+                .filter_map(|a| self.by_author.get(a))
+                .collect(),
+            Plan::Kinds(kinds) => kinds.iter().filter_map(|k| self.by_kind.get(k)).collect(),
+            Plan::Tag(name, values) => values
+                .iter()
+                .filter_map(|v| self.by_tag.get(&(name, v.clone())))
+                .collect(),
+            Plan::CreatedAt => vec![&self.by_created_at],
+        };
+
+        let since = filter.since.unwrap_or(0);
+        let until = filter.until.unwrap_or(u64::MAX);
+        if let [set] = sets[..] {
+            return Box::new(in_range(set, since, until));
+        }
+        let mut keys: Vec<&Key> = sets
+            .into_iter()
+            .flat_map(|set| in_range(set, since, until))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Box::new(keys.into_iter())
+    }
+
+    /// Returns the events matching the filter, newest first, honouring `limit`.
+    fn query_filter(&self, filter: &Filter) -> Vec<&Event> {
+        self.candidates(filter)
+            .map(|(_, id)| &self.events[id])
+            .filter(|e| filter.matches(e))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    fn index(&mut self, event: &Event) {
+        let key = key(event);
+        self.by_created_at.insert(key);
+        self.by_author.entry(event.pubkey).or_default().insert(key);
+        self.by_kind.entry(event.kind).or_default().insert(key);
+        for (name, value) in indexed_tags(event) {
+            self.by_tag.entry((name, value)).or_default().insert(key);
+        }
+    }
+
+    fn unindex(&mut self, event: &Event) {
+        let key = key(event);
+        self.by_created_at.remove(&key);
+        remove_key(&mut self.by_author, event.pubkey, &key);
+        remove_key(&mut self.by_kind, event.kind, &key);
+        for (name, value) in indexed_tags(event) {
+            remove_key(&mut self.by_tag, (name, value), &key);
+        }
+    }
+}
+
+impl EventStore for IndexedStore {
+    fn insert(&mut self, event: Event) -> Result<bool, StoreError> {
+        if self.events.contains_key(&event.id) {
+            return Ok(false);
+        }
+        self.index(&event);
+        self.events.insert(event.id, event);
+        Ok(true)
+    }
+
+    fn query(&self, filters: &[Filter]) -> Result<Vec<Event>, StoreError> {
+        let results = filters.iter().map(|f| self.query_filter(f)).collect();
+        Ok(merge_results(results).into_iter().cloned().collect())
+    }
+
+    fn delete(&mut self, id: &EventId) -> Result<bool, StoreError> {
+        match self.events.remove(id) {
+            Some(event) => {
+                self.unindex(&event);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn count(&self, filters: &[Filter]) -> Result<usize, StoreError> {
+        let results = filters
+            .iter()
+            .map(|filter| {
+                let unlimited = Filter {
+                    limit: None,
+                    ..filter.clone()
+                };
+                self.query_filter(&unlimited)
+            })
+            .collect();
+        Ok(merge_results(results).len())
+    }
+}
+
+fn key(event: &Event) -> Key {
+    (Reverse(event.created_at), event.id)
+}
+
+/// Keys of a set with `since <= created_at <= until`, newest first.
+fn in_range(set: &BTreeSet<Key>, since: u64, until: u64) -> impl Iterator<Item = &Key> {
+    set.range((Reverse(until), EventId([0; 32]))..)
+        .take_while(move |(Reverse(created_at), _)| *created_at >= since)
+}
+
+fn set_len(set: Option<&BTreeSet<Key>>) -> usize {
+    set.map_or(0, BTreeSet::len)
+}
+
+/// Single-letter tags with a value, the ones `#<letter>` filters can query.
+fn indexed_tags(event: &Event) -> impl Iterator<Item = (char, String)> + '_ {
+    event
+        .tags
+        .iter()
+        .filter(|tag| tag.len() >= 2 && tag[0].len() == 1)
+        .filter_map(|tag| Some((tag[0].chars().next()?, tag[1].clone())))
+}
+
+/// Removes a key from an index, dropping the entry once its set is empty.
+fn remove_key<K: std::hash::Hash + Eq>(index: &mut HashMap<K, BTreeSet<Key>>, entry: K, key: &Key) {
+    if let Some(set) = index.get_mut(&entry) {
+        set.remove(key);
+        if set.is_empty() {
+            index.remove(&entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Signature;
+    use secp256k1::{schnorr, Keypair, SECP256K1};
+
+    /// A valid public key derived from a fixed secret key.
+    fn pubkey(seed: u8) -> PublicKey {
+        PublicKey::from_keypair(&Keypair::from_seckey_slice(SECP256K1, &[seed; 32]).unwrap())
+    }
+
+    fn event(
+        id: u8,
+        pubkey: PublicKey,
+        kind: u32,
+        created_at: u64,
+        tags: &[(&str, &str)],
+    ) -> Event {
+        Event {
+            id: EventId([id; 32]),
+            pubkey,
+            created_at,
+            kind,
+            tags: tags
+                .iter()
+                .map(|(name, value)| vec![name.to_string(), value.to_string()])
+                .collect(),
+            content: String::new(),
+            sig: Signature(schnorr::Signature::from_slice(&[0; 64]).unwrap()),
+        }
+    }
+
+    fn store() -> IndexedStore {
+        let mut store = IndexedStore::new();
+        for id in 0..10 {
+            let pubkey = pubkey(if id == 0 { 1 } else { 2 });
+            let kind = if id < 3 { 7 } else { 1 };
+            let tags = [("t", if id == 9 { "rare" } else { "common" })];
+            store
+                .insert(event(id, pubkey, kind, 100 + id as u64, &tags))
+                .unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn test_plan_picks_most_selective_index() {
+        let store = store();
+        let filter = |json: &str| serde_json::from_str::<Filter>(json).unwrap();
+        let a = pubkey(1);
+        let b = pubkey(2);
+
+        let ids = filter(&format!(
+            r#"{{"ids":["{}"],"authors":["{}"]}}"#,
+            EventId([0; 32]),
+            a
+        ));
+        assert!(matches!(store.plan(&ids), Plan::Ids(_)));
+
+        let authors = filter(&format!(r#"{{"authors":["{}"],"kinds":[1]}}"#, a));
+        assert_eq!(store.plan(&authors), Plan::Authors(&[a]));
+
+        let kinds = filter(&format!(r#"{{"authors":["{}"],"kinds":[7]}}"#, b));
+        assert_eq!(store.plan(&kinds), Plan::Kinds(&[7]));
+
+        let tag = filter(r##"{"kinds":[1],"#t":["rare"]}"##);
+        assert_eq!(store.plan(&tag), Plan::Tag('t', &["rare".to_string()]));
+
+        assert_eq!(store.plan(&filter(r#"{"since":105}"#)), Plan::CreatedAt);
+    }
+
+    #[test]
+    fn test_candidates_respect_time_bounds() {
+        let store = store();
+        let filter: Filter =
+            serde_json::from_str(r#"{"kinds":[1,7],"since":102,"until":104}"#).unwrap();
+
+        let ids: Vec<u8> = store
+            .query_filter(&filter)
+            .into_iter()
+            .map(|e| e.id.0[0])
+            .collect();
+        assert_eq!(ids, vec![4, 3, 2]);
+    }
+
+    #[test]
+    fn test_delete_drops_empty_index_entries() {
+        let mut store = store();
+        assert!(store.delete(&EventId([9; 32])).unwrap());
+
+        assert!(!store.by_tag.contains_key(&('t', "rare".to_string())));
+        assert_eq!(store.by_created_at.len(), 9);
+        assert_eq!(store.by_kind[&1].len(), 6);
+    }
+}
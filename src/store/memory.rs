@@ -0,0 +1,56 @@
+use super::{merge_results, sort_newest_first, EventStore, StoreError};
+use crate::event::{Event, EventId, Filter};
+
+/// Keeps events in a plain vector and answers queries with a linear scan.
+///
+/// Nothing is persisted, so all events are lost when the relay stops.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    events: Vec<Event>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl EventStore for MemoryStore {
+    fn insert(&mut self, event: Event) -> Result<bool, StoreError> {
+        if self.events.iter().any(|e| e.id == event.id) {
+            return Ok(false);
+        }
+        self.events.push(event);
+        Ok(true)
+    }
+
+    fn query(&self, filters: &[Filter]) -> Result<Vec<Event>, StoreError> {
+        let results = filters
+            .iter()
+            .map(|filter| {
+                let mut matches: Vec<&Event> =
+                    self.events.iter().filter(|e| filter.matches(e)).collect();
+                sort_newest_first(&mut matches);
+                if let Some(limit) = filter.limit {
+                    matches.truncate(limit);
+                }
+                matches
+            })
+            .collect();
+        Ok(merge_results(results).into_iter().cloned().collect())
+    }
+
+    fn delete(&mut self, id: &EventId) -> Result<bool, StoreError> {
+        let len = self.events.len();
+        self.events.retain(|e| e.id != *id);
+        Ok(self.events.len() != len)
+    }
+
+    fn count(&self, filters: &[Filter]) -> Result<usize, StoreError> {
+        Ok(self
+            .events
+            .iter()
+            .filter(|e| filters.iter().any(|filter| filter.matches(e)))
+            .count())
+    }
+}
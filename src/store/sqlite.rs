@@ -0,0 +1,201 @@
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+use std::path::Path;
+
+use super::{merge_results, EventStore, StoreError};
+use crate::event::{Event, EventId, Filter};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id TEXT PRIMARY KEY,
+    pubkey TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    kind INTEGER NOT NULL,
+    raw TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_pubkey ON events (pubkey);
+CREATE INDEX IF NOT EXISTS events_kind ON events (kind);
+CREATE INDEX IF NOT EXISTS events_created_at ON events (created_at);
+
+CREATE TABLE IF NOT EXISTS tags (
+    event_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tags_name_value ON tags (name, value);
+CREATE INDEX IF NOT EXISTS tags_event_id ON tags (event_id);
+";
+
+/// Persists events in an embedded SQLite database, so they survive relay restarts.
+///
+/// Each event is stored as its original JSON alongside indexed columns for the filterable fields. Single-letter
+/// tags are copied into a separate table so `#<letter>` queries can use an index.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Opens the database at the given path, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Opens a database that only lives as long as the store, mostly useful for tests.
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, StoreError> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStore { conn })
+    }
+}
+
+impl EventStore for SqliteStore {
+    fn insert(&mut self, event: Event) -> Result<bool, StoreError> {
+        // SQLite integers are signed, later timestamps would wrap around to negative ones
+        let created_at =
+            i64::try_from(event.created_at).map_err(|_| "created_at is too large to store")?;
+        let tx = self.conn.transaction()?;
+        let inserted = tx.execute(
+            "INSERT OR IGNORE INTO events (id, pubkey, created_at, kind, raw) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                event.id.to_string(),
+                event.pubkey.to_string(),
+                created_at,
+                event.kind,
+                serde_json::to_string(&event)?
+            ],
+        )?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        for tag in &event.tags {
+            if tag.len() >= 2 && tag[0].len() == 1 {
+                tx.execute(
+                    "INSERT INTO tags (event_id, name, value) VALUES (?1, ?2, ?3)",
+                    params![event.id.to_string(), tag[0], tag[1]],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn query(&self, filters: &[Filter]) -> Result<Vec<Event>, StoreError> {
+        let mut results = Vec::with_capacity(filters.len());
+        for filter in filters {
+            let (condition, mut values) = filter_condition(filter);
+            let mut sql = format!(
+                "SELECT raw FROM events WHERE {} ORDER BY created_at DESC, id ASC",
+                condition
+            );
+            if let Some(limit) = filter.limit {
+                sql.push_str(" LIMIT ?");
+                values.push(Value::Integer(limit.try_into().unwrap_or(i64::MAX)));
+            }
+
+            let mut stmt = self.conn.prepare(&sql)?;
+            let rows = stmt.query_map(params_from_iter(values), |row| row.get::<_, String>(0))?;
+            let mut events = Vec::new();
+            for raw in rows {
+                events.push(serde_json::from_str::<Event>(&raw?)?);
+            }
+            results.push(events);
+        }
+        Ok(merge_results(results))
+    }
+
+    fn delete(&mut self, id: &EventId) -> Result<bool, StoreError> {
+        let id = id.to_string();
+        let tx = self.conn.transaction()?;
+        let deleted = tx.execute("DELETE FROM events WHERE id = ?1", params![id])?;
+        tx.execute("DELETE FROM tags WHERE event_id = ?1", params![id])?;
+        tx.commit()?;
+        Ok(deleted > 0)
+    }
+
+    fn count(&self, filters: &[Filter]) -> Result<usize, StoreError> {
+        if filters.is_empty() {
+            return Ok(0);
+        }
+        let mut conditions = Vec::with_capacity(filters.len());
+        let mut values = Vec::new();
+        for filter in filters {
+            let (condition, filter_values) = filter_condition(filter);
+            conditions.push(format!("({})", condition));
+            values.extend(filter_values);
+        }
+        let sql = format!(
+            "SELECT COUNT(*) FROM events WHERE {}",
+            conditions.join(" OR ")
+        );
+        let count = self
+            .conn
+            .query_row(&sql, params_from_iter(values), |row| row.get::<_, i64>(0))?;
+        Ok(count as usize)
+    }
+}
+
+/// Translates the conditions of a filter into an SQL `WHERE` expression and its bound values.
+fn filter_condition(filter: &Filter) -> (String, Vec<Value>) {
+    let mut conditions = vec!["1".to_string()];
+    let mut values = Vec::new();
+
+    if let Some(ids) = &filter.ids {
+        conditions.push(in_list("id", ids.len()));
+        values.extend(ids.iter().map(|id| Value::Text(id.to_string())));
+    }
+    if let Some(authors) = &filter.authors {
+        conditions.push(in_list("pubkey", authors.len()));
+        values.extend(authors.iter().map(|a| Value::Text(a.to_string())));
+    }
+    if let Some(kinds) = &filter.kinds {
+        conditions.push(in_list("kind", kinds.len()));
+        values.extend(kinds.iter().map(|&kind| Value::Integer(kind.into())));
+    }
+    if !filter.excluded_kinds.is_empty() {
+        conditions.push(format!(
+            "NOT {}",
+            in_list("kind", filter.excluded_kinds.len())
+        ));
+        values.extend(
+            filter
+                .excluded_kinds
+                .iter()
+                .map(|&kind| Value::Integer(kind.into())),
+        );
+    }
+    // Stored timestamps all fit in an i64, so a later `since` matches nothing and a later `until` everything
+    if let Some(since) = filter.since {
+        match i64::try_from(since) {
+            Ok(since) => {
+                conditions.push("created_at >= ?".to_string());
+                values.push(Value::Integer(since));
+            }
+            Err(_) => conditions.push("0".to_string()),
+        }
+    }
+    if let Some(until) = filter.until {
+        conditions.push("created_at <= ?".to_string());
+        values.push(Value::Integer(i64::try_from(until).unwrap_or(i64::MAX)));
+    }
+    for (name, tag_values) in &filter.tags {
+        conditions.push(format!(
+            "id IN (SELECT event_id FROM tags WHERE name = ? AND {})",
+            in_list("value", tag_values.len())
+        ));
+        values.push(Value::Text(name.to_string()));
+        values.extend(tag_values.iter().cloned().map(Value::Text));
+    }
+
+    (conditions.join(" AND "), values)
+}
+
+/// `column IN (?, ?, ...)`, or an always false condition for an empty list.
+fn in_list(column: &str, len: usize) -> String {
+    if len == 0 {
+        return "0".to_string();
+    }
+    format!("{} IN ({})", column, vec!["?"; len].join(", "))
+}
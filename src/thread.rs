@@ -0,0 +1,252 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::event::{Event, EventId};
+
+/// Maximum depth of a reply tree. Deeper replies start new trees among the orphans, so that building, walking or
+/// dropping a tree never recurses deep enough to overflow the stack.
+pub const MAX_DEPTH: usize = 256;
+
+/// A set of events arranged into reply trees, following their NIP-10 `e` tags.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Thread {
+    /// Events that don't reply to anything, with their replies.
+    pub roots: Vec<Node>,
+    /// Replies whose parent is not in the set, with their own replies.
+    pub orphans: Vec<Node>,
+}
+
+/// An event and its direct replies, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub event: Event,
+    pub replies: Vec<Node>,
+}
+
+impl Thread {
+    /// Arranges events into reply trees.
+    ///
+    /// Each event is placed under the event it replies to. Replies whose parent is missing, for instance because no
+    /// relay returned it, become orphans instead of being dropped, as do replies deeper than [`MAX_DEPTH`]. Duplicate
+    /// events are only placed once, and roots, orphans and replies are ordered oldest first.
+    pub fn build(events: impl IntoIterator<Item = Event>) -> Thread {
+        let events: HashMap<EventId, Event> = events.into_iter().map(|e| (e.id, e)).collect();
+
+        let mut roots = Vec::new();
+        let mut orphans = Vec::new();
+        let mut children: HashMap<EventId, Vec<EventId>> = HashMap::new();
+        for event in events.values() {
+            match event.reply_to() {
+                None => roots.push(event.id),
+                Some(parent) if events.contains_key(&parent) => {
+                    children.entry(parent).or_default().push(event.id)
+                }
+                Some(_) => orphans.push(event.id),
+            }
+        }
+
+        let mut builder = TreeBuilder {
+            events: &events,
+            children: &children,
+            visited: HashSet::new(),
+            too_deep: Vec::new(),
+        };
+        let mut thread = Thread {
+            roots: builder.nodes(roots, 0),
+            orphans: builder.nodes(orphans, 0),
+        };
+        thread.orphans.extend(builder.too_deep_nodes());
+
+        // Events with forged ids can reply to each other in a cycle that no root or orphan reaches
+        let unreached = events
+            .keys()
+            .filter(|id| !builder.visited.contains(id))
+            .copied()
+            .collect::<Vec<_>>();
+        thread.orphans.extend(builder.nodes(unreached, 0));
+        thread.orphans.extend(builder.too_deep_nodes());
+        thread.orphans.sort_by_key(|node| order(&node.event));
+        thread
+    }
+
+    /// Returns the number of events in the thread.
+    pub fn len(&self) -> usize {
+        self.roots
+            .iter()
+            .chain(&self.orphans)
+            .map(Node::count)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty() && self.orphans.is_empty()
+    }
+}
+
+impl Node {
+    /// Returns the number of events in this subtree, including this one.
+    pub fn count(&self) -> usize {
+        1 + self.replies.iter().map(Node::count).sum::<usize>()
+    }
+}
+
+/// Builds nodes from the parent to children map, placing every event at most once.
+struct TreeBuilder<'a> {
+    events: &'a HashMap<EventId, Event>,
+    children: &'a HashMap<EventId, Vec<EventId>>,
+    visited: HashSet<EventId>,
+    /// Replies left out of their tree for being deeper than [`MAX_DEPTH`].
+    too_deep: Vec<EventId>,
+}
+
+impl TreeBuilder<'_> {
+    /// Returns the nodes of the events not placed yet, oldest first, `depth` levels below the top of their tree.
+    fn nodes(&mut self, ids: Vec<EventId>, depth: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = ids
+            .into_iter()
+            .filter_map(|id| self.node(id, depth))
+            .collect();
+        nodes.sort_by_key(|node| order(&node.event));
+        nodes
+    }
+
+    fn node(&mut self, id: EventId, depth: usize) -> Option<Node> {
+        if !self.visited.insert(id) {
+            return None;
+        }
+        let replies = self.children.get(&id).cloned().unwrap_or_default();
+        let replies = if depth + 1 < MAX_DEPTH {
+            self.nodes(replies, depth + 1)
+        } else {
+            self.too_deep.extend(replies);
+            Vec::new()
+        };
+        Some(Node {
+            event: self.events[&id].clone(),
+            replies,
+        })
+    }
+
+    /// Returns the trees of the replies that were too deep, each starting a new tree, until none is left.
+    fn too_deep_nodes(&mut self) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        while !self.too_deep.is_empty() {
+            let ids = std::mem::take(&mut self.too_deep);
+            nodes.extend(self.nodes(ids, 0));
+        }
+        nodes
+    }
+}
+
+/// Oldest first, ties broken by lowest id.
+fn order(event: &Event) -> (u64, EventId) {
+    (event.created_at, event.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_keypair;
+    use crate::event::EventBuilder;
+    use crate::post::{create_reply, create_thread_root};
+
+    fn ids(nodes: &[Node]) -> Vec<EventId> {
+        nodes.iter().map(|node| node.event.id).collect()
+    }
+
+    #[test]
+    fn test_build_reply_tree() {
+        let keypair = generate_keypair();
+        let root = create_thread_root(&keypair, "root");
+        let first = create_reply(&keypair, &root, "first");
+        let nested = create_reply(&keypair, &first, "nested");
+        let second = EventBuilder::text_note("second")
+            .tag(["e", &root.id.to_string(), "", "root"])
+            .created_at(first.created_at + 1)
+            .sign(&keypair);
+
+        let thread = Thread::build([
+            nested.clone(),
+            second.clone(),
+            root.clone(),
+            first.clone(),
+            first.clone(),
+        ]);
+
+        assert_eq!(thread.len(), 4);
+        assert!(thread.orphans.is_empty());
+        assert_eq!(ids(&thread.roots), vec![root.id]);
+        let replies = &thread.roots[0].replies;
+        assert_eq!(ids(replies), vec![first.id, second.id]);
+        assert_eq!(ids(&replies[0].replies), vec![nested.id]);
+        assert!(replies[1].replies.is_empty());
+    }
+
+    #[test]
+    fn test_build_positional_reply_tree() {
+        let keypair = generate_keypair();
+        let reply_to = |parents: &[&Event], content| {
+            EventBuilder::text_note(content)
+                .tags(
+                    parents
+                        .iter()
+                        .map(|parent| vec!["e".to_string(), parent.id.to_string(), String::new()]),
+                )
+                .sign(&keypair)
+        };
+        let root = create_thread_root(&keypair, "root");
+        let reply = reply_to(&[&root], "reply");
+        let nested = reply_to(&[&root, &reply], "nested");
+
+        let thread = Thread::build([nested.clone(), reply.clone(), root.clone()]);
+
+        assert!(thread.orphans.is_empty());
+        assert_eq!(ids(&thread.roots), vec![root.id]);
+        assert_eq!(ids(&thread.roots[0].replies), vec![reply.id]);
+        assert_eq!(ids(&thread.roots[0].replies[0].replies), vec![nested.id]);
+    }
+
+    #[test]
+    fn test_deep_replies_become_orphans() {
+        let keypair = generate_keypair();
+        let mut chain = vec![create_thread_root(&keypair, "root")];
+        for i in 1..MAX_DEPTH * 2 + 1 {
+            let parent = chain.last().unwrap().id.to_string();
+            let reply = EventBuilder::text_note(format!("reply {}", i))
+                .tag(["e", parent.as_str()])
+                .sign(&keypair);
+            chain.push(reply);
+        }
+
+        let thread = Thread::build(chain.clone());
+
+        assert_eq!(thread.len(), chain.len());
+        assert_eq!(ids(&thread.roots), vec![chain[0].id]);
+        let mut tops = ids(&thread.orphans);
+        tops.sort();
+        let mut expected = vec![chain[MAX_DEPTH].id, chain[MAX_DEPTH * 2].id];
+        expected.sort();
+        assert_eq!(tops, expected);
+        let mut node = &thread.roots[0];
+        for _ in 1..MAX_DEPTH {
+            node = &node.replies[0];
+        }
+        assert_eq!(node.event.id, chain[MAX_DEPTH - 1].id);
+        assert!(node.replies.is_empty());
+    }
+
+    #[test]
+    fn test_missing_parents_become_orphans() {
+        let keypair = generate_keypair();
+        let root = create_thread_root(&keypair, "root");
+        let reply = create_reply(&keypair, &root, "reply");
+        let nested = create_reply(&keypair, &reply, "nested");
+        let note = EventBuilder::text_note("unrelated").sign(&keypair);
+
+        let thread = Thread::build([nested.clone(), reply.clone(), note.clone()]);
+
+        assert_eq!(ids(&thread.roots), vec![note.id]);
+        assert_eq!(ids(&thread.orphans), vec![reply.id]);
+        assert_eq!(ids(&thread.orphans[0].replies), vec![nested.id]);
+        assert_eq!(thread.len(), 3);
+    }
+}